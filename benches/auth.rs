@@ -0,0 +1,56 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::{hint::black_box, time::Duration};
+
+use chat_server::{
+    config::Config,
+    password::{Algorithm, Hasher},
+    pool::Pool,
+    server::Users,
+    storage::{self, StorageConfig},
+};
+
+const PASSWORD: &str = "correct horse battery staple";
+
+// Hashed the way the server is configured to out of the box
+fn users(algorithm: Algorithm) -> Users {
+    let config = Config::default();
+    let storage = storage::from_config(&StorageConfig::Memory).unwrap();
+    let users = Users::new(
+        Hasher::new(algorithm, config.pbkdf2_iterations),
+        config.password_policy,
+        storage,
+        Pool::new(1),
+        Duration::from_secs(config.location_ttl_secs),
+    );
+    users.add("user", PASSWORD);
+
+    users
+}
+
+fn login(c: &mut Criterion) {
+    let mut group = c.benchmark_group("login");
+    group.sample_size(10);
+
+    for &(name, algorithm) in [
+        ("pbkdf2", Algorithm::Pbkdf2),
+        ("argon2id", Algorithm::Argon2id),
+    ]
+    .iter()
+    {
+        let users = users(algorithm);
+
+        group.bench_function(name, |b| {
+            b.iter(|| black_box(users.authenticate("user", black_box(PASSWORD))))
+        });
+        // Should cost the same as a wrong password, checked against the dummy
+        // hash
+        group.bench_function(format!("{}/unknown", name), |b| {
+            b.iter(|| black_box(users.authenticate("nobody", black_box(PASSWORD))))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, login);
+criterion_main!(benches);
@@ -0,0 +1,199 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::HashMap,
+    hint::black_box,
+    sync::{atomic::AtomicUsize, Arc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use chat_server::{
+    addrban::{AddrBans, TrustedProxies},
+    origin::OriginPolicy,
+    password::{Algorithm, Hasher, Policy},
+    pool::Pool,
+    queue::Queue,
+    ratelimit::{LocationLimit, TokenBucket},
+    server::{area, Encoder, JsonMessage, Server, Servers, Users},
+    socket::{Payload, Socket, Unsent},
+    storage::{self, StorageConfig},
+};
+
+const CONNECTIONS: [usize; 4] = [100, 1_000, 10_000, 50_000];
+
+// Everyone is placed around here, a few kilometres apart at most, so each
+// message reaches every connection
+const LAT: f32 = 59.33;
+const LON: f32 = 18.07;
+const SPREAD: f32 = 0.0008;
+
+struct Crowd {
+    users: Users,
+    servers: Servers,
+    unsent: Vec<Unsent>,
+}
+
+impl Crowd {
+    fn new(size: usize) -> Crowd {
+        let storage = storage::from_config(&StorageConfig::Memory).unwrap();
+        let users = Users::new(
+            Hasher::new(Algorithm::Pbkdf2, 1),
+            Policy::default(),
+            storage,
+            Pool::new(1),
+            Duration::from_secs(3600),
+        );
+        let (servers, writer) = Servers::new();
+        thread::spawn(move || writer.run());
+        let (queue, _rx) = Queue::bounded(1, &Arc::new(AtomicUsize::new(0)));
+
+        let mut unsent = Vec::with_capacity(size);
+        for i in 0..size {
+            let lat = LAT + ((i % 100) as f32 - 50.0) * SPREAD;
+            let lon = LON + ((i / 100 % 100) as f32 - 50.0) * SPREAD;
+
+            let user_id = users.add(&format!("user{}", i), "");
+            if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                user.relocate(lat, lon, None, None);
+            }
+
+            let (socket, pending) = Socket::unconnected();
+            let id = servers.get_next_id();
+            servers.update(
+                id,
+                Server {
+                    id,
+                    user_id: Arc::new(RwLock::new(None)),
+                    socket,
+                    channel: queue.clone(),
+                    started: Instant::now(),
+                    addr: None,
+                    connected_at: 0,
+                    user_agent: None,
+                    origin_policy: Arc::new(OriginPolicy::default()),
+                    addr_bans: AddrBans::new(),
+                    trusted_proxies: TrustedProxies::default(),
+                    location_rate: Arc::new(Mutex::new(TokenBucket::new(LocationLimit::default()))),
+                },
+            );
+            servers.set_user(id, Some(user_id));
+            servers.subscribe(user_id, area(lat, lon), false);
+            unsent.push(pending);
+        }
+
+        Crowd {
+            users,
+            servers,
+            unsent,
+        }
+    }
+
+    // The same work as a worker handling a message: a payload per distance,
+    // shared by everyone it rounds the same for
+    fn broadcast(&self, encoder: &mut Encoder, user_id: usize) {
+        let mut payloads = HashMap::new();
+
+        self.servers
+            .for_each_in_range(&self.users, user_id, None, |socket, user_id_other| {
+                let distance = if user_id_other == user_id {
+                    None
+                } else {
+                    self.users.distance(user_id_other, user_id)
+                };
+                let key = distance
+                    .as_ref()
+                    .map(|distance| (distance.value.to_bits(), distance.unit));
+
+                let payload = match payloads.get(&key) {
+                    Some(payload) => Payload::clone(payload),
+                    None => {
+                        let payload = match encoder.encode(&JsonMessage::Message {
+                            id: 0,
+                            username: "user0".to_string(),
+                            guest: false,
+                            bot: false,
+                            msg: "Hello there".to_string(),
+                            whisper: false,
+                            distance,
+                            place: None,
+                        }) {
+                            Some(payload) => payload,
+                            None => return,
+                        };
+                        payloads.insert(key, payload.clone());
+                        payload
+                    }
+                };
+
+                let _ = socket.send_shared(&payload);
+            });
+    }
+
+    // Frames pile up on sockets nobody reads, so they're thrown away between
+    // runs without being timed
+    fn clear(&mut self) {
+        for unsent in &mut self.unsent {
+            unsent.clear();
+        }
+    }
+}
+
+fn fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fan_out");
+    group.sample_size(20);
+
+    for &size in CONNECTIONS.iter() {
+        let mut crowd = Crowd::new(size);
+        let mut encoder = Encoder::default();
+        let sender = crowd
+            .users
+            .get_by_name("user0")
+            .map(|user| user.id)
+            .unwrap();
+
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, _| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::from_secs(0);
+                for _ in 0..iters {
+                    let start = Instant::now();
+                    crowd.broadcast(&mut encoder, sender);
+                    elapsed += start.elapsed();
+
+                    crowd.clear();
+                }
+                elapsed
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn in_range(c: &mut Criterion) {
+    let crowd = Crowd::new(2);
+    let first = crowd
+        .users
+        .get_by_name("user0")
+        .map(|user| user.id)
+        .unwrap();
+    let second = crowd
+        .users
+        .get_by_name("user1")
+        .map(|user| user.id)
+        .unwrap();
+
+    c.bench_function("in_range", |b| {
+        b.iter(|| {
+            black_box(
+                crowd
+                    .users
+                    .in_range(black_box(first), black_box(second), None),
+            )
+        })
+    });
+}
+
+criterion_group!(benches, fan_out, in_range);
+criterion_main!(benches);
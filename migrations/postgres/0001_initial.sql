@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS users (
+    id BIGINT PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    role JSONB NOT NULL,
+    status JSONB NOT NULL,
+    email TEXT,
+    verified BOOLEAN NOT NULL,
+    bot BOOLEAN NOT NULL,
+    public_key TEXT,
+    banned BOOLEAN NOT NULL,
+    suspension JSONB NOT NULL,
+    last_seen_visible BOOLEAN NOT NULL
+);
+CREATE SEQUENCE IF NOT EXISTS user_ids;
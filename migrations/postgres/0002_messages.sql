@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS messages (
+    id BIGINT PRIMARY KEY,
+    user_id BIGINT NOT NULL,
+    username TEXT NOT NULL,
+    guest BOOLEAN NOT NULL,
+    bot BOOLEAN NOT NULL,
+    area_lat INTEGER NOT NULL,
+    area_lon INTEGER NOT NULL,
+    time BIGINT NOT NULL,
+    msg TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_area ON messages (area_lat, area_lon, id);
@@ -0,0 +1,8 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS location_history BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE TABLE IF NOT EXISTS locations (
+    user_id BIGINT NOT NULL,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL,
+    time BIGINT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS locations_user ON locations (user_id, time);
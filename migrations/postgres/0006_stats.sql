@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS stats (
+    time BIGINT NOT NULL,
+    period_secs BIGINT NOT NULL,
+    messages BIGINT NOT NULL,
+    active_users BIGINT NOT NULL,
+    areas JSONB NOT NULL
+);
+CREATE INDEX IF NOT EXISTS stats_time ON stats (time);
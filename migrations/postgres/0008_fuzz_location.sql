@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN IF NOT EXISTS fuzz_location BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1 @@
+ALTER TABLE locations ADD COLUMN IF NOT EXISTS sealed TEXT;
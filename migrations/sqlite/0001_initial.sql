@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS users (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    role TEXT NOT NULL,
+    status TEXT NOT NULL,
+    email TEXT,
+    verified INTEGER NOT NULL,
+    bot INTEGER NOT NULL,
+    public_key TEXT,
+    banned INTEGER NOT NULL,
+    suspension TEXT NOT NULL,
+    last_seen_visible INTEGER NOT NULL
+);
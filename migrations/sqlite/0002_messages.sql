@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    username TEXT NOT NULL,
+    guest INTEGER NOT NULL,
+    bot INTEGER NOT NULL,
+    area_lat INTEGER NOT NULL,
+    area_lon INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    msg TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS messages_area ON messages (area_lat, area_lon, id);
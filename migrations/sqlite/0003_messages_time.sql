@@ -0,0 +1,2 @@
+-- Pruning by age
+CREATE INDEX IF NOT EXISTS messages_time ON messages (time);
@@ -0,0 +1,8 @@
+ALTER TABLE users ADD COLUMN location_history INTEGER NOT NULL DEFAULT 0;
+CREATE TABLE IF NOT EXISTS locations (
+    user_id INTEGER NOT NULL,
+    lat REAL NOT NULL,
+    lon REAL NOT NULL,
+    time INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS locations_user ON locations (user_id, time);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS stats (
+    time INTEGER NOT NULL,
+    period_secs INTEGER NOT NULL,
+    messages INTEGER NOT NULL,
+    active_users INTEGER NOT NULL,
+    areas TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS stats_time ON stats (time);
@@ -0,0 +1 @@
+ALTER TABLE users ADD COLUMN fuzz_location INTEGER NOT NULL DEFAULT 0;
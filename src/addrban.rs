@@ -0,0 +1,214 @@
+use parking_lot::RwLock;
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+// An address range such as 10.0.0.0/8 or 2001:db8::/32, or a single address
+#[derive(Clone, Copy, PartialEq)]
+struct Cidr {
+    addr: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().ok_or(())?.trim().parse().map_err(|_| ())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.trim().parse().map_err(|_| ())?,
+            None => bits,
+        };
+
+        if prefix > bits {
+            return Err(());
+        }
+
+        // Host bits are cleared so equal ranges compare equal
+        Ok(Cidr {
+            addr: network(addr, prefix),
+            prefix,
+        })
+    }
+}
+
+impl Cidr {
+    fn contains(&self, addr: &IpAddr) -> bool {
+        self.addr.is_ipv4() == addr.is_ipv4() && network(*addr, self.prefix) == self.addr
+    }
+}
+
+fn network(addr: IpAddr, prefix: u32) -> IpAddr {
+    match addr {
+        IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            IpAddr::V4((u32::from(addr) & mask).into())
+        }
+        IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            IpAddr::V6((u128::from(addr) & mask).into())
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct AddrBans {
+    ranges: Arc<RwLock<Vec<Cidr>>>,
+}
+
+impl AddrBans {
+    pub fn new() -> Self {
+        AddrBans {
+            ranges: Arc::new(RwLock::new(Vec::new())),
+        }
+    }
+
+    // Returns false if `range` can't be parsed
+    pub fn ban(&self, range: &str) -> bool {
+        match range.parse() {
+            Ok(cidr) => {
+                let mut ranges = self.ranges.write();
+                if !ranges.contains(&cidr) {
+                    ranges.push(cidr);
+                }
+                true
+            }
+            Err(()) => false,
+        }
+    }
+
+    pub fn unban(&self, range: &str) -> bool {
+        match range.parse::<Cidr>() {
+            Ok(cidr) => {
+                let mut ranges = self.ranges.write();
+                let before = ranges.len();
+                ranges.retain(|banned| *banned != cidr);
+                ranges.len() != before
+            }
+            Err(()) => false,
+        }
+    }
+
+    // Addresses that don't parse are never banned
+    pub fn is_banned(&self, addr: &str) -> bool {
+        match addr.parse::<IpAddr>() {
+            Ok(addr) => self.bans(&addr),
+            Err(_) => false,
+        }
+    }
+
+    pub fn bans(&self, addr: &IpAddr) -> bool {
+        self.ranges.read().iter().any(|range| range.contains(addr))
+    }
+}
+
+// The proxies in front of the server. Only their forwarding headers are
+// believed, anyone else could put any address in them.
+#[derive(Clone, Default)]
+pub struct TrustedProxies {
+    ranges: Arc<Vec<Cidr>>,
+}
+
+impl TrustedProxies {
+    // Returns the first range that can't be parsed
+    pub fn new(ranges: &[String]) -> Result<Self, String> {
+        let ranges = ranges
+            .iter()
+            .map(|range| range.parse().map_err(|()| range.clone()))
+            .collect::<Result<Vec<Cidr>, String>>()?;
+
+        Ok(TrustedProxies {
+            ranges: Arc::new(ranges),
+        })
+    }
+
+    pub fn trusts(&self, addr: &IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn bans_addresses_in_a_range() {
+        let bans = AddrBans::new();
+        assert!(bans.ban("10.0.0.0/8"));
+
+        assert!(bans.bans(&ip("10.0.0.1")));
+        assert!(bans.bans(&ip("10.255.255.255")));
+        assert!(!bans.bans(&ip("11.0.0.0")));
+        assert!(!bans.bans(&ip("9.255.255.255")));
+    }
+
+    #[test]
+    fn bans_single_addresses() {
+        let bans = AddrBans::new();
+        assert!(bans.ban("192.0.2.7"));
+
+        assert!(bans.is_banned("192.0.2.7"));
+        assert!(!bans.is_banned("192.0.2.8"));
+        assert!(!bans.is_banned("not an address"));
+    }
+
+    #[test]
+    fn bans_ipv6_ranges() {
+        let bans = AddrBans::new();
+        assert!(bans.ban("2001:db8::/32"));
+
+        assert!(bans.bans(&ip("2001:db8:ffff::1")));
+        assert!(!bans.bans(&ip("2001:db9::1")));
+        // A range of one family never covers the other
+        assert!(!bans.bans(&ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn zero_prefix_covers_the_family() {
+        let bans = AddrBans::new();
+        assert!(bans.ban("0.0.0.0/0"));
+
+        assert!(bans.bans(&ip("203.0.113.1")));
+        assert!(!bans.bans(&ip("::1")));
+    }
+
+    #[test]
+    fn unbans_the_same_range_with_host_bits_set() {
+        let bans = AddrBans::new();
+        assert!(bans.ban("192.168.1.77/24"));
+        assert!(bans.bans(&ip("192.168.1.1")));
+
+        assert!(bans.unban("192.168.1.0/24"));
+        assert!(!bans.bans(&ip("192.168.1.1")));
+        assert!(!bans.unban("192.168.1.0/24"));
+    }
+
+    #[test]
+    fn rejects_bad_ranges() {
+        let bans = AddrBans::new();
+
+        assert!(!bans.ban("10.0.0.0/33"));
+        assert!(!bans.ban("2001:db8::/129"));
+        assert!(!bans.ban("10.0.0.0/x"));
+        assert!(!bans.ban("example.com"));
+    }
+
+    #[test]
+    fn trusts_only_the_configured_proxies() {
+        let proxies = TrustedProxies::new(&["10.0.0.0/8".to_string(), "::1".to_string()]).unwrap();
+
+        assert!(proxies.trusts(&ip("10.1.2.3")));
+        assert!(proxies.trusts(&ip("::1")));
+        assert!(!proxies.trusts(&ip("192.0.2.1")));
+        assert!(!TrustedProxies::default().trusts(&ip("10.1.2.3")));
+
+        assert_eq!(
+            TrustedProxies::new(&["10.0.0.0/8".to_string(), "bad".to_string()]).err(),
+            Some("bad".to_string())
+        );
+    }
+}
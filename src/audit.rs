@@ -0,0 +1,110 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const AUDIT_BACKLOG: usize = 10_000;
+pub const MAX_QUERY_LIMIT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Login,
+    Register,
+    PasswordChange,
+    PasswordReset,
+    Logout,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Entry {
+    // Seconds since the unix epoch
+    pub time: u64,
+    pub event: Event,
+    // How the user authenticated, e.g. "password" or "oauth"
+    pub method: Option<String>,
+    pub success: bool,
+    pub username: Option<String>,
+    pub connection: usize,
+    pub addr: Option<String>,
+}
+
+impl Entry {
+    pub fn new(
+        event: Event,
+        method: Option<&str>,
+        username: Option<String>,
+        success: bool,
+    ) -> Entry {
+        Entry {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            event,
+            method: method.map(str::to_string),
+            success,
+            username,
+            connection: 0,
+            addr: None,
+        }
+    }
+}
+
+// Recent entries are kept in memory for admins, every entry is appended to the
+// log file as a line of JSON
+#[derive(Clone)]
+pub struct Audit {
+    entries: Arc<Mutex<VecDeque<Entry>>>,
+    file: Option<Arc<Mutex<File>>>,
+}
+
+impl Audit {
+    pub fn new(path: Option<&str>) -> io::Result<Audit> {
+        let file = match path {
+            Some(path) => Some(Arc::new(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            ))),
+            None => None,
+        };
+
+        Ok(Audit {
+            entries: Arc::new(Mutex::new(VecDeque::new())),
+            file,
+        })
+    }
+
+    pub fn record(&self, entry: Entry) {
+        if let Some(ref file) = self.file {
+            if let Ok(line) = serde_json::to_string(&entry) {
+                let mut file = file.lock();
+                if let Err(e) = writeln!(file, "{}", line) {
+                    println!("Failed to write audit log: {}", e);
+                }
+            }
+        }
+
+        let mut entries = self.entries.lock();
+        if entries.len() >= AUDIT_BACKLOG {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    // Newest first
+    pub fn query(&self, username: Option<&str>, limit: usize) -> Vec<Entry> {
+        self.entries
+            .lock()
+            .iter()
+            .rev()
+            .filter(|entry| username.is_none() || entry.username.as_deref() == username)
+            .take(limit.min(MAX_QUERY_LIMIT))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+use crate::crypt::{self, Key};
+use crate::history::History;
+use crate::server::{Messages, Users};
+use crate::snapshot::write_atomic;
+use crate::storage::{MessageRecord, UserRecord};
+
+// Users and message history, taken while the workers are paused
+#[derive(Serialize, Deserialize)]
+pub struct Backup {
+    users: Vec<UserRecord>,
+    messages: Vec<MessageRecord>,
+}
+
+impl Backup {
+    pub fn take(users: &Users, history: &History) -> Result<Backup, String> {
+        Ok(Backup {
+            users: users.records(),
+            messages: history.export()?,
+        })
+    }
+
+    pub fn load(path: &str, key: Option<&Key>) -> Result<Backup, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&crypt::open_file(contents, key)?).map_err(|e| e.to_string())
+    }
+
+    pub fn save(&self, path: &str, key: Option<&Key>) -> Result<(), String> {
+        let contents = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        write_atomic(path, &crypt::seal_file(contents, key)?)
+    }
+
+    // Users and messages in the backup replace those with the same id, others
+    // are left alone
+    pub fn restore(
+        self,
+        users: &Users,
+        history: &History,
+        messages: &Messages,
+    ) -> Result<(), String> {
+        let ids = self.users.iter().map(|user| user.id).collect::<Vec<_>>();
+        users.restore(self.users);
+        for id in ids {
+            users.persist(id);
+        }
+
+        history.import(&self.messages)?;
+        if let Some(last_id) = self.messages.iter().map(|message| message.id).max() {
+            messages.skip_past(last_id);
+        }
+
+        Ok(())
+    }
+
+    pub fn counts(&self) -> (usize, usize) {
+        (self.users.len(), self.messages.len())
+    }
+}
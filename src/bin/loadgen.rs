@@ -0,0 +1,308 @@
+// Puts load on a running server: opens the given number of connections, each
+// registering a user, sending its location and then a message every few
+// seconds, and reports how long registering and message delivery took.
+//
+//     loadgen [url] [connections] [seconds]
+//
+// Every connection comes from the same address, so the server's
+// max_connections_per_ip has to allow them all. Messages are sent below the
+// default rate limit.
+
+use futures_util::{SinkExt, StreamExt};
+use std::{
+    env, process,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::{runtime, task, time};
+use tokio_tungstenite::tungstenite::Message as Frame;
+
+use chat_server::pow;
+use chat_server::server::{random_token, JsonMessage};
+
+const URL: &str = "ws://127.0.0.1:3012";
+const CONNECTIONS: usize = 1_000;
+const DURATION_SECS: u64 = 60;
+// New connections per second, the server hashes a password for each
+const RAMP_PER_SEC: u64 = 50;
+const LOCATION_INTERVAL: Duration = Duration::from_secs(2);
+// Location updates between messages, a message every 6 seconds
+const LOCATIONS_PER_MESSAGE: u64 = 3;
+// Everyone is placed within a few kilometres of here, so most messages reach
+// most of the other connections
+const LAT: f32 = 59.33;
+const LON: f32 = 18.07;
+const SPREAD: f32 = 0.0005;
+
+#[derive(Default)]
+struct Report {
+    registered: usize,
+    failed: usize,
+    sent: usize,
+    received: usize,
+    rate_limited: usize,
+    // Microseconds
+    register: Vec<u64>,
+    delivery: Vec<u64>,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.registered += other.registered;
+        self.failed += other.failed;
+        self.sent += other.sent;
+        self.received += other.received;
+        self.rate_limited += other.rate_limited;
+        self.register.extend(other.register);
+        self.delivery.extend(other.delivery);
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let url = args.next().unwrap_or_else(|| URL.to_string());
+    let connections = parse(args.next(), CONNECTIONS);
+    let seconds = parse(args.next(), DURATION_SECS);
+
+    let runtime = match runtime::Builder::new_multi_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            println!("Failed to start runtime: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // Messages carry the time they were sent, counted from here
+    let start = Instant::now();
+    let ramp = Duration::from_millis(connections as u64 * 1000 / RAMP_PER_SEC);
+    let end = start + ramp + Duration::from_secs(seconds);
+    // Usernames are kept apart from earlier runs against the same server
+    let run = random_token()[..8].to_string();
+    let password = random_token();
+
+    println!(
+        "Opening {} connections to {} over {} s, then running for {} s",
+        connections,
+        url,
+        ramp.as_secs(),
+        seconds
+    );
+
+    let report = runtime.block_on(async move {
+        let mut clients = Vec::with_capacity(connections);
+        for i in 0..connections {
+            time::sleep_until(time::Instant::from_std(
+                start + Duration::from_millis(i as u64 * 1000 / RAMP_PER_SEC),
+            ))
+            .await;
+
+            clients.push(tokio::spawn(client(
+                url.clone(),
+                format!("lg-{}-{}", run, i),
+                password.clone(),
+                i,
+                start,
+                end,
+            )));
+        }
+
+        let mut report = Report::default();
+        for client in clients {
+            if let Ok(client) = client.await {
+                report.merge(client);
+            }
+        }
+        report
+    });
+
+    let Report {
+        registered,
+        failed,
+        sent,
+        received,
+        rate_limited,
+        mut register,
+        mut delivery,
+    } = report;
+    println!(
+        "{} registered, {} failed, {} messages sent, {} delivered, {} rate limited",
+        registered, failed, sent, received, rate_limited
+    );
+    percentiles("Register", &mut register);
+    percentiles("Delivery", &mut delivery);
+}
+
+fn parse<T: FromStr>(arg: Option<String>, default: T) -> T {
+    match arg {
+        Some(arg) => match arg.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                println!("Usage: loadgen [url] [connections] [seconds]");
+                process::exit(1);
+            }
+        },
+        None => default,
+    }
+}
+
+fn percentiles(name: &str, samples: &mut [u64]) {
+    if samples.is_empty() {
+        println!("{}: no samples", name);
+        return;
+    }
+    samples.sort_unstable();
+
+    let at = |p: f64| samples[((samples.len() - 1) as f64 * p) as usize] as f64 / 1000.0;
+    println!(
+        "{}: p50 {:.1} ms, p90 {:.1} ms, p99 {:.1} ms, p99.9 {:.1} ms, max {:.1} ms",
+        name,
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        at(0.999),
+        at(1.0)
+    );
+}
+
+fn frame(msg: &JsonMessage) -> Frame {
+    Frame::Text(serde_json::to_string(msg).unwrap_or_default())
+}
+
+async fn client(
+    url: String,
+    username: String,
+    password: String,
+    index: usize,
+    start: Instant,
+    end: Instant,
+) -> Report {
+    let mut report = Report::default();
+    let (mut sink, mut stream) = match tokio_tungstenite::connect_async(url.as_str()).await {
+        Ok((ws, _)) => ws.split(),
+        Err(_) => {
+            report.failed += 1;
+            return report;
+        }
+    };
+
+    let register = |proof| JsonMessage::Register {
+        username: username.clone(),
+        password: password.clone(),
+        email: None,
+        proof,
+    };
+
+    let registering = Instant::now();
+    if sink.send(frame(&register(None))).await.is_err() {
+        report.failed += 1;
+        return report;
+    }
+    loop {
+        let msg = match stream.next().await {
+            Some(Ok(Frame::Text(text))) => serde_json::from_str(&text).ok(),
+            Some(Ok(_)) => None,
+            Some(Err(_)) | None => {
+                report.failed += 1;
+                return report;
+            }
+        };
+
+        match msg {
+            Some(JsonMessage::RegisterChallenge {
+                challenge,
+                difficulty,
+            }) => {
+                let proof = task::block_in_place(|| pow::solve(challenge, difficulty));
+                if sink.send(frame(&register(Some(proof)))).await.is_err() {
+                    report.failed += 1;
+                    return report;
+                }
+            }
+            Some(JsonMessage::RegisterResponse { status: true, .. }) => break,
+            Some(JsonMessage::RegisterResponse { .. }) => {
+                report.failed += 1;
+                return report;
+            }
+            _ => (),
+        }
+    }
+    report.registered += 1;
+    report
+        .register
+        .push(registering.elapsed().as_micros() as u64);
+
+    // Locations move a little with each update, so the server has something
+    // to do for them
+    let lat = LAT + ((index % 100) as f32 - 50.0) * SPREAD;
+    let lon = LON + ((index / 100 % 100) as f32 - 50.0) * SPREAD;
+    let sender = tokio::spawn(async move {
+        let mut sent = 0;
+        let mut interval = time::interval(LOCATION_INTERVAL);
+
+        for tick in 0u64.. {
+            interval.tick().await;
+            if Instant::now() >= end {
+                break;
+            }
+
+            let step = (tick % 10) as f32 * 0.0001;
+            let location = JsonMessage::Location {
+                lat: Some(lat + step),
+                lon: Some(lon + step),
+                alt: None,
+                geometry: None,
+                accuracy_m: None,
+            };
+            if sink.send(frame(&location)).await.is_err() {
+                break;
+            }
+
+            if tick % LOCATIONS_PER_MESSAGE == LOCATIONS_PER_MESSAGE - 1 {
+                let message = JsonMessage::SendMessage {
+                    msg: start.elapsed().as_micros().to_string(),
+                    client_id: None,
+                    whisper: false,
+                };
+                if sink.send(frame(&message)).await.is_err() {
+                    break;
+                }
+                sent += 1;
+            }
+        }
+
+        let _ = sink.close().await;
+        sent
+    });
+
+    let deadline = time::Instant::from_std(end);
+    while let Ok(Some(Ok(frame))) = time::timeout_at(deadline, stream.next()).await {
+        let text = match frame {
+            Frame::Text(text) => text,
+            Frame::Close(_) => break,
+            _ => continue,
+        };
+
+        // A burst of messages from one sender comes as an array
+        let msgs: Vec<JsonMessage> = if text.starts_with('[') {
+            serde_json::from_str(&text).unwrap_or_default()
+        } else {
+            serde_json::from_str(&text).ok().into_iter().collect()
+        };
+        for msg in msgs {
+            match msg {
+                JsonMessage::Message { msg, .. } => {
+                    if let Ok(sent_at) = msg.parse::<u64>() {
+                        let now = start.elapsed().as_micros() as u64;
+                        report.received += 1;
+                        report.delivery.push(now.saturating_sub(sent_at));
+                    }
+                }
+                JsonMessage::RateLimited { .. } => report.rate_limited += 1,
+                _ => (),
+            }
+        }
+    }
+
+    report.sent += sender.await.unwrap_or(0);
+    report
+}
@@ -0,0 +1,51 @@
+use chashmap::CHashMap;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::server::random_token;
+
+// Keys are only kept hashed, so a dump of the map can't be used to log in
+#[derive(Clone)]
+pub struct ApiKeys {
+    keys: Arc<CHashMap<String, usize>>,
+}
+
+impl ApiKeys {
+    pub fn new() -> Self {
+        ApiKeys {
+            keys: Arc::new(CHashMap::new()),
+        }
+    }
+
+    // Replaces any key the bot already had
+    pub fn create(&self, user_id: usize) -> String {
+        self.keys.retain(|_, &id| id != user_id);
+
+        let key = format!("{}{}", random_token(), random_token());
+        self.keys.insert(hash(&key), user_id);
+
+        key
+    }
+
+    // Hashed keys and the bots they belong to
+    pub fn export(&self) -> Vec<(String, usize)> {
+        (*self.keys).clone().into_iter().collect()
+    }
+
+    pub fn restore(&self, keys: Vec<(String, usize)>) {
+        for (hash, user_id) in keys {
+            self.keys.insert(hash, user_id);
+        }
+    }
+
+    pub fn verify(&self, key: &str) -> Option<usize> {
+        self.keys.get(&hash(key)).map(|user_id| *user_id)
+    }
+}
+
+fn hash(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
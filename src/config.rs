@@ -0,0 +1,178 @@
+use crate::geo::HotspotConfig;
+use crate::geocode::GeocoderConfig;
+use crate::history::Retention;
+use crate::mail::MailerConfig;
+use crate::oauth::ProviderConfig;
+use crate::origin::OriginPolicy;
+use crate::password::{Algorithm, Policy};
+use crate::ratelimit::{LocationLimit, LoginLimit, MessageLimit};
+use crate::server::{RadiusLimits, Reach, SessionLimits};
+use crate::snapshot::SnapshotConfig;
+use crate::storage::StorageConfig;
+use serde::Deserialize;
+use std::{collections::HashMap, env, fs, num::NonZeroUsize, process, thread};
+
+const CONFIG_PATH: &str = "config.json";
+const ENDPOINT: &str = "127.0.0.1:3012";
+// Used when the number of cores can't be told
+const WORKERS: usize = 4;
+const MAX_CONNECTIONS: usize = 100_000;
+const PBKDF2_ITERATIONS: u32 = 100_000;
+const MAX_CONNECTIONS_PER_IP: usize = 20;
+const DB_THREADS: usize = 4;
+const AUTH_THREADS: usize = 2;
+const STATS_PERIOD_SECS: u64 = 60;
+const LOCATION_GRID_M: f32 = 500.0;
+const LOCATION_TTL_SECS: u64 = 60 * 60;
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Address the server listens on
+    pub endpoint: String,
+    // Threads handling messages, one per core unless set
+    pub workers: usize,
+    // Connections past this are dropped as they are accepted
+    pub max_connections: usize,
+    // Pins the listener and then each worker to a core of its own, for hosts
+    // that run little else
+    pub pin_threads: bool,
+    pub password_hash: Algorithm,
+    pub pbkdf2_iterations: u32,
+    pub password_policy: Policy,
+    pub login_limit: LoginLimit,
+    pub session: SessionLimits,
+    pub radius: RadiusLimits,
+    // How far messages, whispers, announcements and presence events go
+    pub reach: Reach,
+    pub hotspots: HotspotConfig,
+    // Size of the grid that locations of users who asked for it are snapped to
+    pub location_grid_m: f32,
+    // Users who haven't sent their location for this long are out of range of everyone
+    pub location_ttl_secs: u64,
+    pub bot_limit: MessageLimit,
+    // Location updates per connection
+    pub location_limit: LocationLimit,
+    pub max_connections_per_ip: usize,
+    // Addresses or CIDR ranges refused at connect, more can be added at runtime
+    pub banned_addrs: Vec<String>,
+    // Addresses or CIDR ranges of the proxies in front of the server, the
+    // only ones whose X-Forwarded-For and Forwarded headers are believed
+    pub trusted_proxies: Vec<String>,
+    // File that authentication events are appended to
+    pub audit_log: Option<String>,
+    // Where users are kept
+    pub storage: StorageConfig,
+    // Threads for storage access, kept off the workers
+    pub db_threads: usize,
+    // Threads for checking and hashing passwords, kept off the workers and
+    // storage access
+    pub auth_threads: usize,
+    pub retention: Retention,
+    // Activity is aggregated and stored once per period
+    pub stats_period_secs: u64,
+    // Messages are logged here until stored, so a crash can't lose them
+    pub message_log: Option<String>,
+    // Shares sessions and presence with other instances, e.g. redis://127.0.0.1/
+    pub redis: Option<String>,
+    // File the named areas defined by admins are kept in
+    pub fences: Option<String>,
+    // Periodically writes users, OAuth links and bot keys to a file that is loaded on startup
+    pub snapshot: Option<SnapshotConfig>,
+    // 32 random bytes, base64 encoded. Password hashes, emails and location
+    // history are sealed with it in storage, and snapshots and backups whole.
+    pub encryption_key: Option<String>,
+    // Accounts registered with an email can't send messages until it is verified
+    pub require_email: bool,
+    // Leading zero bits of the proof of work asked for on registration, 0 to disable
+    pub registration_difficulty: u32,
+    // Sensitive requests must carry a nonce from GetNonce, so captured frames can't be replayed
+    pub require_nonce: bool,
+    pub mailer: MailerConfig,
+    // Turns locations into place names for messages and nearby lists
+    pub geocoder: GeocoderConfig,
+    pub origin_policy: OriginPolicy,
+    pub tls: Option<TlsConfig>,
+    pub jwt: Option<JwtConfig>,
+    pub oauth: HashMap<String, ProviderConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            endpoint: ENDPOINT.to_string(),
+            workers: thread::available_parallelism().map_or(WORKERS, NonZeroUsize::get),
+            max_connections: MAX_CONNECTIONS,
+            pin_threads: false,
+            password_hash: Algorithm::Pbkdf2,
+            pbkdf2_iterations: PBKDF2_ITERATIONS,
+            password_policy: Policy::default(),
+            login_limit: LoginLimit::default(),
+            session: SessionLimits::default(),
+            radius: RadiusLimits::default(),
+            reach: Reach::default(),
+            hotspots: HotspotConfig::default(),
+            location_grid_m: LOCATION_GRID_M,
+            location_ttl_secs: LOCATION_TTL_SECS,
+            bot_limit: MessageLimit::default(),
+            location_limit: LocationLimit::default(),
+            max_connections_per_ip: MAX_CONNECTIONS_PER_IP,
+            banned_addrs: Vec::new(),
+            trusted_proxies: Vec::new(),
+            audit_log: None,
+            storage: StorageConfig::default(),
+            db_threads: DB_THREADS,
+            auth_threads: AUTH_THREADS,
+            retention: Retention::default(),
+            stats_period_secs: STATS_PERIOD_SECS,
+            message_log: None,
+            redis: None,
+            fences: None,
+            snapshot: None,
+            encryption_key: None,
+            require_email: false,
+            registration_difficulty: 0,
+            require_nonce: false,
+            mailer: MailerConfig::default(),
+            geocoder: GeocoderConfig::default(),
+            origin_policy: OriginPolicy::default(),
+            tls: None,
+            jwt: None,
+            oauth: HashMap::new(),
+        }
+    }
+}
+
+// PEM files; requires building with the "tls" feature
+#[derive(Deserialize)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Deserialize)]
+pub struct JwtConfig {
+    // "HS256" with a shared secret, or "RS256" with the path to a PEM public key
+    pub algorithm: String,
+    pub key: String,
+}
+
+impl Config {
+    pub fn load() -> Config {
+        let path = env::args()
+            .nth(1)
+            .unwrap_or_else(|| CONFIG_PATH.to_string());
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => match serde_json::from_str(&contents) {
+                Ok(config) => config,
+                Err(e) => {
+                    println!("Invalid config {}: {}", path, e);
+                    process::exit(1);
+                }
+            },
+            Err(_) => Config::default(),
+        }
+    }
+}
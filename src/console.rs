@@ -0,0 +1,76 @@
+use crate::bots::ApiKeys;
+use crate::import;
+use crate::server::{PasswordResets, Role, Users};
+use std::io::{self, BufRead};
+
+// Operator commands read from stdin
+pub fn run(users: Users, resets: PasswordResets, api_keys: ApiKeys) {
+    let stdin = io::stdin();
+
+    for line in stdin.lock().lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break,
+        };
+        let args: Vec<&str> = line.split_whitespace().collect();
+
+        match args.as_slice() {
+            ["role", username, role] => match role.parse::<Role>() {
+                Ok(role) => match users.get_mut_by_name(username).map(|mut user| {
+                    user.role = role;
+                    user.id
+                }) {
+                    Some(user_id) => {
+                        users.persist(user_id);
+                        println!("{} is now {}", username, args[2]);
+                    }
+                    None => println!("No such user: {}", username),
+                },
+                Err(_) => println!("Unknown role: {}", role),
+            },
+            ["reset", username] => match users.get_by_name(username) {
+                Some(user) => println!(
+                    "Password reset token for {}: {}",
+                    username,
+                    resets.create(user.id)
+                ),
+                None => println!("No such user: {}", username),
+            },
+            // Creates a bot account, or issues a new key for an existing one
+            ["bot", username] => {
+                let user_id = match users.get_by_name(username) {
+                    Some(ref user) if user.bot => Some(user.id),
+                    Some(_) => None,
+                    None => {
+                        let user_id = users.add_with_hash(username, String::new());
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.bot = true;
+                        }
+                        users.persist(user_id);
+                        Some(user_id)
+                    }
+                };
+
+                match user_id {
+                    Some(user_id) => {
+                        println!("API key for {}: {}", username, api_keys.create(user_id))
+                    }
+                    None => println!("{} is not a bot", username),
+                }
+            }
+            // Users from a CSV or JSON file, see import::ImportedUser
+            ["import", path] => match import::load(path) {
+                Ok(imported) => {
+                    let (added, skipped) = import::import(&users, imported);
+                    for reason in &skipped {
+                        println!("Skipped {}", reason);
+                    }
+                    println!("Imported {} users, skipped {}", added, skipped.len());
+                }
+                Err(e) => println!("Failed to read {}: {}", path, e),
+            },
+            [] => (),
+            _ => println!("Unknown command: {}", line),
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use rand::{thread_rng, Rng};
+use std::sync::Arc;
+
+use crate::storage::{
+    HistoryQuery, LocationRecord, MessageRecord, StatsRecord, Storage, UserRecord,
+};
+
+// Sealed values are marked, so what was stored before a key was configured
+// still loads, and is sealed the next time it's written
+const PREFIX: &str = "sealed:";
+const NONCE_LEN: usize = 12;
+
+// Seals what is kept at rest, so a copy of the database, a snapshot or a
+// backup doesn't give away password hashes, emails or where users have been
+#[derive(Clone)]
+pub struct Key {
+    cipher: Arc<ChaCha20Poly1305>,
+}
+
+impl Key {
+    // 32 bytes, base64 encoded
+    pub fn from_base64(key: &str) -> Result<Key, String> {
+        let bytes = base64::decode(key.trim()).map_err(|e| e.to_string())?;
+        let cipher = ChaCha20Poly1305::new_from_slice(&bytes)
+            .map_err(|_| "The key has to be 32 bytes".to_string())?;
+
+        Ok(Key {
+            cipher: Arc::new(cipher),
+        })
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<String, String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        thread_rng().fill(&mut nonce);
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(Nonce::from_slice(&nonce), plaintext)
+                .map_err(|_| "Failed to seal".to_string())?,
+        );
+
+        Ok(format!("{}{}", PREFIX, base64::encode(&sealed)))
+    }
+
+    // Values that aren't sealed are returned as they are
+    pub fn open(&self, value: &str) -> Result<Vec<u8>, String> {
+        let sealed = match value.strip_prefix(PREFIX) {
+            Some(sealed) => base64::decode(sealed).map_err(|e| e.to_string())?,
+            None => return Ok(value.as_bytes().to_vec()),
+        };
+
+        if sealed.len() < NONCE_LEN {
+            return Err("Sealed value is truncated".to_string());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Failed to open a sealed value, the key may be wrong".to_string())
+    }
+
+    fn seal_str(&self, value: &str) -> Result<String, String> {
+        self.seal(value.as_bytes())
+    }
+
+    fn open_str(&self, value: &str) -> Result<String, String> {
+        String::from_utf8(self.open(value)?).map_err(|e| e.to_string())
+    }
+}
+
+// Files are sealed whole when there is a key
+pub fn seal_file(contents: Vec<u8>, key: Option<&Key>) -> Result<Vec<u8>, String> {
+    match key {
+        Some(key) => key.seal(&contents).map(String::into_bytes),
+        None => Ok(contents),
+    }
+}
+
+pub fn open_file(contents: String, key: Option<&Key>) -> Result<String, String> {
+    match key {
+        Some(key) => key.open_str(&contents),
+        None if contents.starts_with(PREFIX) => {
+            Err("The file is sealed but no encryption_key is configured".to_string())
+        }
+        None => Ok(contents),
+    }
+}
+
+// Seals password hashes, emails and locations on their way into the backend
+// and opens them on the way out, so the rest of the server never sees them
+// sealed. Messages and stats go through as they are, the backends search and
+// prune them.
+pub struct Sealed {
+    storage: Arc<dyn Storage>,
+    key: Key,
+}
+
+impl Sealed {
+    pub fn new(storage: Arc<dyn Storage>, key: Key) -> Sealed {
+        Sealed { storage, key }
+    }
+
+    fn open_user(&self, mut user: UserRecord) -> Result<UserRecord, String> {
+        user.password = self.key.open_str(&user.password)?;
+        user.email = match user.email {
+            Some(ref email) => Some(self.key.open_str(email)?),
+            None => None,
+        };
+
+        Ok(user)
+    }
+
+    fn open_location(&self, mut location: LocationRecord) -> Result<LocationRecord, String> {
+        if let Some(sealed) = location.sealed.take() {
+            let (lat, lon) =
+                serde_json::from_slice(&self.key.open(&sealed)?).map_err(|e| e.to_string())?;
+            location.lat = lat;
+            location.lon = lon;
+        }
+
+        Ok(location)
+    }
+}
+
+impl Storage for Sealed {
+    fn load_users(&self) -> Result<Vec<UserRecord>, String> {
+        self.storage
+            .load_users()?
+            .into_iter()
+            .map(|user| self.open_user(user))
+            .collect()
+    }
+
+    fn find_user(&self, name: &str) -> Result<Option<UserRecord>, String> {
+        match self.storage.find_user(name)? {
+            Some(user) => self.open_user(user).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn save_user(&self, user: &UserRecord) -> Result<(), String> {
+        let mut sealed = user.clone();
+        sealed.password = self.key.seal_str(&user.password)?;
+        sealed.email = match user.email {
+            Some(ref email) => Some(self.key.seal_str(email)?),
+            None => None,
+        };
+
+        self.storage.save_user(&sealed)
+    }
+
+    fn next_id(&self) -> Result<Option<usize>, String> {
+        self.storage.next_id()
+    }
+
+    fn save_messages(&self, messages: &[MessageRecord]) -> Result<(), String> {
+        self.storage.save_messages(messages)
+    }
+
+    fn messages(&self, query: &HistoryQuery) -> Result<Vec<MessageRecord>, String> {
+        self.storage.messages(query)
+    }
+
+    fn last_message_id(&self) -> Result<Option<usize>, String> {
+        self.storage.last_message_id()
+    }
+
+    fn export_messages(&self) -> Result<Vec<MessageRecord>, String> {
+        self.storage.export_messages()
+    }
+
+    fn prune_messages(
+        &self,
+        sent_before: Option<u64>,
+        keep_per_area: Option<usize>,
+    ) -> Result<usize, String> {
+        self.storage.prune_messages(sent_before, keep_per_area)
+    }
+
+    // The coordinates stored in the clear are zeroed
+    fn save_location(&self, location: &LocationRecord) -> Result<(), String> {
+        let coordinates =
+            serde_json::to_vec(&(location.lat, location.lon)).map_err(|e| e.to_string())?;
+
+        self.storage.save_location(&LocationRecord {
+            user_id: location.user_id,
+            lat: 0.0,
+            lon: 0.0,
+            time: location.time,
+            sealed: Some(self.key.seal(&coordinates)?),
+        })
+    }
+
+    fn locations(
+        &self,
+        user_id: usize,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<LocationRecord>, String> {
+        self.storage
+            .locations(user_id, since, limit)?
+            .into_iter()
+            .map(|location| self.open_location(location))
+            .collect()
+    }
+
+    fn delete_locations(&self, user_id: usize) -> Result<(), String> {
+        self.storage.delete_locations(user_id)
+    }
+
+    fn save_stats(&self, stats: &StatsRecord) -> Result<(), String> {
+        self.storage.save_stats(stats)
+    }
+
+    fn stats(&self, since: u64, limit: usize) -> Result<Vec<StatsRecord>, String> {
+        self.storage.stats(since, limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> Key {
+        Key::from_base64(&base64::encode([byte; 32])).unwrap()
+    }
+
+    #[test]
+    fn opens_what_it_sealed() {
+        let key = key(1);
+        let sealed = key.seal(b"alice@example.com").unwrap();
+
+        assert!(sealed.starts_with(PREFIX));
+        assert!(!sealed.contains("alice"));
+        assert_eq!(key.open(&sealed).unwrap(), b"alice@example.com");
+        // A fresh nonce every time
+        assert_ne!(key.seal(b"alice@example.com").unwrap(), sealed);
+    }
+
+    #[test]
+    fn passes_unsealed_values_through() {
+        assert_eq!(
+            key(1).open("stored before the key").unwrap(),
+            b"stored before the key"
+        );
+    }
+
+    #[test]
+    fn rejects_the_wrong_key_and_tampering() {
+        let sealed = key(1).seal(b"secret").unwrap();
+        assert!(key(2).open(&sealed).is_err());
+
+        let mut bytes = base64::decode(&sealed[PREFIX.len()..]).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        let tampered = format!("{}{}", PREFIX, base64::encode(&bytes));
+        assert!(key(1).open(&tampered).is_err());
+
+        assert!(key(1).open(&format!("{}AAAA", PREFIX)).is_err());
+    }
+
+    #[test]
+    fn rejects_keys_of_the_wrong_length() {
+        assert!(Key::from_base64(&base64::encode([1u8; 16])).is_err());
+        assert!(Key::from_base64("not base64!").is_err());
+    }
+
+    #[test]
+    fn sealed_files_need_the_key() {
+        let key = key(1);
+        let sealed = seal_file(b"{}".to_vec(), Some(&key)).unwrap();
+        let sealed = String::from_utf8(sealed).unwrap();
+
+        assert_eq!(open_file(sealed.clone(), Some(&key)).unwrap(), "{}");
+        assert!(open_file(sealed, None).is_err());
+        assert_eq!(open_file("{}".to_string(), None).unwrap(), "{}");
+    }
+}
@@ -0,0 +1,257 @@
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    sync::Arc,
+};
+
+use crate::server::distance;
+use crate::snapshot::write_atomic;
+
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_POINTS: usize = 100;
+const MAX_RADIUS_KM: f32 = 100.0;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Shape {
+    Circle { lat: f32, lon: f32, radius_km: f32 },
+    // Corners in order, as (lat, lon)
+    Polygon { points: Vec<(f32, f32)> },
+}
+
+impl Shape {
+    fn valid(&self) -> bool {
+        match self {
+            Shape::Circle { radius_km, .. } => *radius_km > 0.0 && *radius_km <= MAX_RADIUS_KM,
+            Shape::Polygon { points } => points.len() >= 3 && points.len() <= MAX_POINTS,
+        }
+    }
+
+    // Polygons are taken as flat, which is close enough at the size of a
+    // campus or a festival
+    fn contains(&self, lat: f32, lon: f32) -> bool {
+        match self {
+            Shape::Circle {
+                lat: c_lat,
+                lon: c_lon,
+                radius_km,
+            } => distance(*c_lat, *c_lon, lat, lon) <= *radius_km,
+            Shape::Polygon { points } => {
+                let mut inside = false;
+                let mut j = points.len() - 1;
+                for i in 0..points.len() {
+                    let (lat_i, lon_i) = points[i];
+                    let (lat_j, lon_j) = points[j];
+
+                    if (lat_i > lat) != (lat_j > lat)
+                        && lon < (lon_j - lon_i) * (lat - lat_i) / (lat_j - lat_i) + lon_i
+                    {
+                        inside = !inside;
+                    }
+                    j = i;
+                }
+
+                inside
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Fence {
+    pub id: usize,
+    pub name: String,
+    pub shape: Shape,
+    // Points of interest are channels of their own that the users inside
+    // talk in, instead of taking them into the local conversation
+    #[serde(default)]
+    pub poi: bool,
+}
+
+#[derive(Default)]
+struct State {
+    fences: HashMap<usize, Fence>,
+    // Users inside each fence
+    members: HashMap<usize, HashSet<usize>>,
+    next_id: usize,
+}
+
+// Named areas that act as rooms for everyone inside them. Fences are kept in
+// a file when one is configured, where they are inside isn't.
+#[derive(Clone)]
+pub struct Fences {
+    state: Arc<RwLock<State>>,
+    path: Option<String>,
+}
+
+impl Fences {
+    pub fn load(path: Option<String>) -> Result<Fences, String> {
+        let mut state = State::default();
+
+        if let Some(ref path) = path {
+            let fences: Vec<Fence> = match fs::read_to_string(path) {
+                Ok(contents) => serde_json::from_str(&contents).map_err(|e| e.to_string())?,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e.to_string()),
+            };
+
+            for fence in fences {
+                state.next_id = state.next_id.max(fence.id + 1);
+                state.members.insert(fence.id, HashSet::new());
+                state.fences.insert(fence.id, fence);
+            }
+        }
+
+        Ok(Fences {
+            state: Arc::new(RwLock::new(state)),
+            path,
+        })
+    }
+
+    pub fn add(&self, name: String, shape: Shape, poi: bool) -> Result<(), &'static str> {
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            return Err("Invalid fence name");
+        }
+        if !shape.valid() {
+            return Err("Invalid fence shape");
+        }
+
+        let mut state = self.state.write();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.members.insert(id, HashSet::new());
+        state.fences.insert(
+            id,
+            Fence {
+                id,
+                name,
+                shape,
+                poi,
+            },
+        );
+
+        self.save(&state);
+        Ok(())
+    }
+
+    // Users inside a removed fence are just no longer in it, without a notice
+    pub fn remove(&self, id: usize) -> bool {
+        let mut state = self.state.write();
+        state.members.remove(&id);
+        let removed = state.fences.remove(&id).is_some();
+
+        if removed {
+            self.save(&state);
+        }
+        removed
+    }
+
+    pub fn list(&self) -> Vec<Fence> {
+        let mut fences = self
+            .state
+            .read()
+            .fences
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        fences.sort_by_key(|fence| fence.id);
+
+        fences
+    }
+
+    // Moves the user into the fences around the location and out of the rest,
+    // returns the fences entered and left
+    pub fn locate(&self, user_id: usize, lat: f32, lon: f32) -> (Vec<Fence>, Vec<Fence>) {
+        let mut state = self.state.write();
+        let State {
+            ref fences,
+            ref mut members,
+            ..
+        } = *state;
+
+        let mut entered = Vec::new();
+        let mut left = Vec::new();
+        for fence in fences.values() {
+            let inside = fence.shape.contains(lat, lon);
+            let members = members.entry(fence.id).or_default();
+
+            if inside && members.insert(user_id) {
+                entered.push(fence.clone());
+            } else if !inside && members.remove(&user_id) {
+                left.push(fence.clone());
+            }
+        }
+
+        (entered, left)
+    }
+
+    pub fn forget(&self, user_id: usize) {
+        for members in self.state.write().members.values_mut() {
+            members.remove(&user_id);
+        }
+    }
+
+    pub fn is_member(&self, id: usize, user_id: usize) -> bool {
+        self.state
+            .read()
+            .members
+            .get(&id)
+            .is_some_and(|members| members.contains(&user_id))
+    }
+
+    // The points of interest the user is in
+    pub fn channels(&self, user_id: usize) -> Vec<Fence> {
+        let state = self.state.read();
+        let mut channels = state
+            .fences
+            .values()
+            .filter(|fence| {
+                fence.poi
+                    && state
+                        .members
+                        .get(&fence.id)
+                        .is_some_and(|members| members.contains(&user_id))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        channels.sort_by_key(|fence| fence.id);
+
+        channels
+    }
+
+    pub fn members(&self, id: usize) -> Vec<usize> {
+        match self.state.read().members.get(&id) {
+            Some(members) => members.iter().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // Everyone in a fence with the user, the user included. Points of
+    // interest don't count.
+    pub fn neighbors(&self, user_id: usize) -> HashSet<usize> {
+        let state = self.state.read();
+        state
+            .members
+            .iter()
+            .filter(|&(id, members)| {
+                members.contains(&user_id) && state.fences.get(id).is_some_and(|fence| !fence.poi)
+            })
+            .flat_map(|(_, members)| members.iter().cloned())
+            .collect()
+    }
+
+    fn save(&self, state: &State) {
+        if let Some(ref path) = self.path {
+            let fences = state.fences.values().collect::<Vec<_>>();
+            let result = serde_json::to_vec(&fences)
+                .map_err(|e| e.to_string())
+                .and_then(|json| write_atomic(path, &json));
+
+            if let Err(e) = result {
+                println!("Failed to save fences to {}: {}", path, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use chashmap::CHashMap;
+use parking_lot::{Mutex, RwLock};
+use rstar::{primitives::GeomWithData, PointDistance, RTree, AABB};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+const EARTH_RADIUS_KM: f32 = 6371.0;
+const KM_PER_DEGREE_LAT: f32 = 111.2;
+pub const MAX_NEARBY_LIMIT: usize = 50;
+// How far back the message rate of an area is measured
+const RATE_WINDOW: Duration = Duration::from_secs(600);
+// Past this the oldest messages are forgotten early
+const MAX_RECENT_MESSAGES: usize = 100_000;
+// Users are counted in cells of this precision for finding crowds, about
+// 1.2 by 0.6 km
+const CROWD_PRECISION: usize = 6;
+
+// A GeoJSON geometry, for clients built on mapping tools. Only points are
+// used.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum Geometry {
+    // Longitude first, then latitude and optionally altitude
+    Point { coordinates: Vec<f32> },
+}
+
+impl Geometry {
+    pub fn point(lat: f32, lon: f32) -> Geometry {
+        Geometry::Point {
+            coordinates: vec![lon, lat],
+        }
+    }
+
+    pub fn lat_lon_alt(&self) -> Option<(f32, f32, Option<f32>)> {
+        match self {
+            Geometry::Point { coordinates } => match coordinates[..] {
+                [lon, lat] => Some((lat, lon, None)),
+                [lon, lat, alt] => Some((lat, lon, Some(alt))),
+                _ => None,
+            },
+        }
+    }
+}
+
+// Points on the unit sphere, where straight line distance grows with great
+// circle distance everywhere, poles and the antimeridian included
+pub type Point = GeomWithData<[f32; 3], usize>;
+
+// On the map at all. NaN is in no range, so it is caught as well.
+pub fn valid(lat: f32, lon: f32) -> bool {
+    (-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)
+}
+
+// A location carrying the id of whatever is there
+pub fn point(id: usize, lat: f32, lon: f32) -> Point {
+    let (lat, lon) = (lat.to_radians(), lon.to_radians());
+    GeomWithData::new(
+        [lat.cos() * lon.cos(), lat.cos() * lon.sin(), lat.sin()],
+        id,
+    )
+}
+
+// Squared length of the chord under an arc of the distance
+pub fn chord_squared(km: f32) -> f32 {
+    let chord = 2.0 * (km / EARTH_RADIUS_KM / 2.0).min(1.0).sin();
+    chord * chord
+}
+
+fn arc_km(chord_squared: f32) -> f32 {
+    2.0 * EARTH_RADIUS_KM * (chord_squared.sqrt() / 2.0).min(1.0).asin()
+}
+
+pub fn geohash(lat: f32, lon: f32, precision: usize) -> String {
+    let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let mut even = true;
+    let mut bits = 0;
+    let mut index = 0;
+
+    while hash.len() < precision {
+        let (range, value) = if even {
+            (&mut lon_range, lon)
+        } else {
+            (&mut lat_range, lat)
+        };
+        let middle = (range.0 + range.1) / 2.0;
+
+        index <<= 1;
+        if value >= middle {
+            index |= 1;
+            range.0 = middle;
+        } else {
+            range.1 = middle;
+        }
+        even = !even;
+
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[index] as char);
+            bits = 0;
+            index = 0;
+        }
+    }
+
+    hash
+}
+
+// The middle of a geohash cell
+pub fn geohash_center(hash: &str) -> Option<(f32, f32)> {
+    let (mut lat_range, mut lon_range) = ((-90.0f32, 90.0f32), (-180.0f32, 180.0f32));
+    let mut even = true;
+
+    for c in hash.bytes() {
+        let index = BASE32.iter().position(|&b| b == c)?;
+        for bit in (0..5).rev() {
+            let range = if even { &mut lon_range } else { &mut lat_range };
+            let middle = (range.0 + range.1) / 2.0;
+
+            if index & (1 << bit) != 0 {
+                range.0 = middle;
+            } else {
+                range.1 = middle;
+            }
+            even = !even;
+        }
+    }
+
+    Some((
+        (lat_range.0 + lat_range.1) / 2.0,
+        (lon_range.0 + lon_range.1) / 2.0,
+    ))
+}
+
+// The center of the grid cell a location is in. Cells are about grid_m on a
+// side, so they get more degrees of longitude wide towards the poles.
+pub fn snap(lat: f32, lon: f32, grid_m: f32) -> (f32, f32) {
+    if grid_m <= 0.0 {
+        return (lat, lon);
+    }
+
+    let lat_step = grid_m / 1000.0 / KM_PER_DEGREE_LAT;
+    let lat = (((lat / lat_step).floor() + 0.5) * lat_step).clamp(-90.0, 90.0);
+
+    let lon_step = (lat_step / lat.to_radians().cos().max(0.01)).min(360.0);
+    let lon = (((lon + 180.0) / lon_step).floor() + 0.5) * lon_step - 180.0;
+
+    (lat, lon.clamp(-180.0, 180.0))
+}
+
+#[derive(Default)]
+struct Index {
+    tree: RTree<Point>,
+    // Where each user is and the crowd cell it's in
+    users: HashMap<usize, (Point, String)>,
+    // Users per crowd cell
+    cells: HashMap<String, usize>,
+}
+
+impl Index {
+    fn unlink(&mut self, user_id: usize) {
+        if let Some((previous, cell)) = self.users.remove(&user_id) {
+            self.tree.remove(&previous);
+
+            let empty = match self.cells.get_mut(&cell) {
+                Some(count) => {
+                    *count -= 1;
+                    *count == 0
+                }
+                None => false,
+            };
+            if empty {
+                self.cells.remove(&cell);
+            }
+        }
+    }
+}
+
+// Where every located user is, for finding the users around someone without
+// looking at everyone
+#[derive(Clone)]
+pub struct Positions {
+    index: Arc<RwLock<Index>>,
+    // Who each user was last known to be in range of, kept the same from
+    // both sides
+    neighbors: Arc<CHashMap<usize, HashSet<usize>>>,
+}
+
+impl Positions {
+    pub fn new() -> Positions {
+        Positions {
+            index: Arc::new(RwLock::new(Index::default())),
+            neighbors: Arc::new(CHashMap::new()),
+        }
+    }
+
+    // Returns the crowd cell the user is now in and how many are in it
+    pub fn update(&self, user_id: usize, lat: f32, lon: f32) -> (String, usize) {
+        let point = point(user_id, lat, lon);
+        let cell = geohash(lat, lon, CROWD_PRECISION);
+        let mut index = self.index.write();
+
+        index.unlink(user_id);
+        index.tree.insert(point);
+        index.users.insert(user_id, (point, cell.clone()));
+
+        let count = index.cells.entry(cell.clone()).or_default();
+        *count += 1;
+        let count = *count;
+
+        (cell, count)
+    }
+
+    pub fn remove(&self, user_id: usize) {
+        self.index.write().unlink(user_id);
+
+        if let Some(neighbors) = self.neighbors.remove(&user_id) {
+            for other in neighbors {
+                if let Some(mut set) = self.neighbors.get_mut(&other) {
+                    set.remove(&user_id);
+                }
+            }
+        }
+    }
+
+    // Replaces who the user is in range of, returns who came into range and
+    // who went out of it
+    pub fn set_neighbors(&self, user_id: usize, now: HashSet<usize>) -> (Vec<usize>, Vec<usize>) {
+        let before = self
+            .neighbors
+            .insert(user_id, now.clone())
+            .unwrap_or_default();
+
+        let entered = now.difference(&before).cloned().collect::<Vec<_>>();
+        let left = before.difference(&now).cloned().collect::<Vec<_>>();
+
+        for &other in &entered {
+            self.neighbors.upsert(
+                other,
+                || [user_id].iter().cloned().collect(),
+                |set| {
+                    set.insert(user_id);
+                },
+            );
+        }
+        for &other in &left {
+            if let Some(mut set) = self.neighbors.get_mut(&other) {
+                set.remove(&user_id);
+            }
+        }
+
+        (entered, left)
+    }
+
+    // Users within the radius, in no particular order
+    pub fn within(&self, lat: f32, lon: f32, radius_km: f32) -> Vec<usize> {
+        let center = point(0, lat, lon);
+
+        self.index
+            .read()
+            .tree
+            .locate_within_distance(*center.geom(), chord_squared(radius_km))
+            .map(|point| point.data)
+            .collect()
+    }
+
+    // Users inside the box, which crosses the antimeridian when min_lon is
+    // east of max_lon
+    pub fn within_box(&self, min_lat: f32, min_lon: f32, max_lat: f32, max_lon: f32) -> Vec<usize> {
+        if min_lon > max_lon {
+            let mut users = self.within_box(min_lat, min_lon, max_lat, 180.0);
+            users.extend(self.within_box(min_lat, -180.0, max_lat, max_lon));
+            return users;
+        }
+
+        // The box is curved on the sphere. Its extremes along each axis are
+        // at its corners, the equator or a quarter turn of longitude.
+        let mut lats = vec![min_lat, max_lat];
+        if min_lat < 0.0 && max_lat > 0.0 {
+            lats.push(0.0);
+        }
+        let mut lons = vec![min_lon, max_lon];
+        lons.extend(
+            [-180.0, -90.0, 0.0, 90.0, 180.0]
+                .iter()
+                .filter(|&&lon| lon > min_lon && lon < max_lon),
+        );
+
+        let (mut lower, mut upper) = ([f32::MAX; 3], [f32::MIN; 3]);
+        for &lat in &lats {
+            for &lon in &lons {
+                let corner = point(0, lat, lon);
+                for axis in 0..3 {
+                    lower[axis] = lower[axis].min(corner.geom()[axis]);
+                    upper[axis] = upper[axis].max(corner.geom()[axis]);
+                }
+            }
+        }
+
+        self.index
+            .read()
+            .tree
+            .locate_in_envelope_intersecting(&AABB::from_corners(lower, upper))
+            .filter(|point| {
+                let [x, y, z] = *point.geom();
+                let (lat, lon) = (z.asin().to_degrees(), y.atan2(x).to_degrees());
+                lat >= min_lat && lat <= max_lat && lon >= min_lon && lon <= max_lon
+            })
+            .map(|point| point.data)
+            .collect()
+    }
+
+    // Users within the radius with their distance in km, closest first
+    pub fn nearest(&self, lat: f32, lon: f32, radius_km: f32) -> Vec<(usize, f32)> {
+        let center = point(0, lat, lon);
+        let max = chord_squared(radius_km);
+
+        self.index
+            .read()
+            .tree
+            .nearest_neighbor_iter_with_distance_2(center.geom())
+            .take_while(|&(_, distance)| distance <= max)
+            .map(|(point, distance)| (point.data, arc_km(distance)))
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct HotspotConfig {
+    // Users in one crowd cell that make it a hotspot
+    pub min_users: usize,
+    // A hotspot isn't reported again for this long
+    pub cooldown_secs: u64,
+    pub notify_admins: bool,
+    // Users this close to a hotspot are told about it too, when set
+    pub notify_km: Option<f32>,
+}
+
+impl Default for HotspotConfig {
+    fn default() -> HotspotConfig {
+        HotspotConfig {
+            min_users: 50,
+            cooldown_secs: 60 * 60,
+            notify_admins: true,
+            notify_km: None,
+        }
+    }
+}
+
+// Crowd cells that have been reported lately
+#[derive(Clone)]
+pub struct Hotspots {
+    pub config: HotspotConfig,
+    reported: Arc<Mutex<HashMap<String, Instant>>>,
+}
+
+impl Hotspots {
+    pub fn new(config: HotspotConfig) -> Hotspots {
+        Hotspots {
+            config,
+            reported: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Whether a cell with this many users is a hotspot that hasn't been
+    // reported yet, which counts it as reported
+    pub fn check(&self, cell: &str, users: usize) -> bool {
+        if users < self.config.min_users {
+            return false;
+        }
+
+        let cooldown = Duration::from_secs(self.config.cooldown_secs);
+        let mut reported = self.reported.lock();
+        reported.retain(|_, at| at.elapsed() < cooldown);
+
+        if reported.contains_key(cell) {
+            return false;
+        }
+        reported.insert(cell.to_string(), Instant::now());
+        true
+    }
+}
+
+// When and where from a message was sent
+type Sent = (Instant, [f32; 3]);
+
+// Where the messages of the last while were sent from
+#[derive(Clone)]
+pub struct RecentMessages {
+    messages: Arc<Mutex<VecDeque<Sent>>>,
+}
+
+impl RecentMessages {
+    pub fn new() -> RecentMessages {
+        RecentMessages {
+            messages: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn record(&self, lat: f32, lon: f32) {
+        let mut messages = self.messages.lock();
+        if messages.len() >= MAX_RECENT_MESSAGES {
+            messages.pop_front();
+        }
+        messages.push_back((Instant::now(), *point(0, lat, lon).geom()));
+    }
+
+    // Messages per minute sent within the radius
+    pub fn rate(&self, lat: f32, lon: f32, radius_km: f32) -> f32 {
+        let center = *point(0, lat, lon).geom();
+        let max = chord_squared(radius_km);
+
+        let mut messages = self.messages.lock();
+        while let Some(&(sent, _)) = messages.front() {
+            if sent.elapsed() < RATE_WINDOW {
+                break;
+            }
+            messages.pop_front();
+        }
+
+        let count = messages
+            .iter()
+            .filter(|(_, point)| point.distance_2(&center) <= max)
+            .count();
+
+        count as f32 / (RATE_WINDOW.as_secs() as f32 / 60.0)
+    }
+}
@@ -0,0 +1,173 @@
+use chashmap::CHashMap;
+use rstar::RTree;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{fs, sync::Arc, time::Duration};
+
+use crate::geo::{chord_squared, geohash, point, Point};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_PLACE_KM: f32 = 20.0;
+// Lookups are cached per cell of this precision, about a km across
+const CACHE_PRECISION: usize = 6;
+const MAX_CACHED: usize = 100_000;
+
+pub trait Geocoder: Send + Sync {
+    // Blocks on the lookup, so only call this from the pool
+    fn place(&self, lat: f32, lon: f32) -> Option<String>;
+}
+
+#[derive(Deserialize, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum GeocoderConfig {
+    #[default]
+    None,
+    // The closest of a list of places, a CSV file of name, lat and lon such
+    // as one made from GeoNames
+    Offline {
+        path: String,
+        #[serde(default = "default_max_km")]
+        max_km: f32,
+    },
+    // A Nominatim compatible reverse geocoding service
+    Http {
+        url: String,
+        user_agent: String,
+    },
+}
+
+fn default_max_km() -> f32 {
+    MAX_PLACE_KM
+}
+
+pub fn from_config(config: &GeocoderConfig) -> Result<Option<Arc<dyn Geocoder>>, String> {
+    let geocoder: Arc<dyn Geocoder> = match config {
+        GeocoderConfig::None => return Ok(None),
+        GeocoderConfig::Offline { path, max_km } => {
+            Arc::new(Cached::new(Offline::load(path, *max_km)?))
+        }
+        GeocoderConfig::Http { url, user_agent } => Arc::new(Cached::new(Http {
+            url: url.trim_end_matches('/').to_string(),
+            user_agent: user_agent.clone(),
+        })),
+    };
+
+    Ok(Some(geocoder))
+}
+
+struct Offline {
+    names: Vec<String>,
+    tree: RTree<Point>,
+    max_chord_squared: f32,
+}
+
+impl Offline {
+    fn load(path: &str, max_km: f32) -> Result<Offline, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+        let mut names = Vec::new();
+        let mut points = Vec::new();
+        // Names may contain commas, the coordinates are the last two fields
+        for line in contents.lines() {
+            let mut fields = line.rsplitn(3, ',');
+            let lon = fields.next().and_then(|lon| lon.trim().parse::<f32>().ok());
+            let lat = fields.next().and_then(|lat| lat.trim().parse::<f32>().ok());
+            let name = fields.next().map(|name| name.trim().trim_matches('"'));
+
+            // Skips the header and anything else that isn't a place
+            if let (Some(name), Some(lat), Some(lon)) = (name, lat, lon) {
+                points.push(point(names.len(), lat, lon));
+                names.push(name.to_string());
+            }
+        }
+
+        Ok(Offline {
+            names,
+            tree: RTree::bulk_load(points),
+            max_chord_squared: chord_squared(max_km),
+        })
+    }
+}
+
+impl Geocoder for Offline {
+    fn place(&self, lat: f32, lon: f32) -> Option<String> {
+        let center = point(0, lat, lon);
+
+        self.tree
+            .nearest_neighbor_iter_with_distance_2(center.geom())
+            .next()
+            .filter(|&(_, distance)| distance <= self.max_chord_squared)
+            .map(|(place, _)| self.names[place.data].clone())
+    }
+}
+
+struct Http {
+    url: String,
+    user_agent: String,
+}
+
+impl Geocoder for Http {
+    // The district followed by the city, e.g. "Södermalm, Stockholm"
+    fn place(&self, lat: f32, lon: f32) -> Option<String> {
+        let response: Value = ureq::get(&format!("{}/reverse", self.url))
+            .query("format", "jsonv2")
+            .query("lat", &lat.to_string())
+            .query("lon", &lon.to_string())
+            .query("zoom", "14")
+            .set("User-Agent", &self.user_agent)
+            .timeout(TIMEOUT)
+            .call()
+            .ok()?
+            .into_json()
+            .ok()?;
+
+        let address = response.get("address")?;
+        let first = |keys: &[&str]| {
+            keys.iter()
+                .filter_map(|key| address.get(*key).and_then(Value::as_str))
+                .next()
+        };
+
+        let district = first(&["suburb", "neighbourhood", "quarter", "village"]);
+        let city = first(&["city", "town", "municipality"]);
+        match (district, city) {
+            (Some(district), Some(city)) if district != city => {
+                Some(format!("{}, {}", district, city))
+            }
+            (Some(place), _) | (None, Some(place)) => Some(place.to_string()),
+            (None, None) => None,
+        }
+    }
+}
+
+// Nearby locations share a lookup, failed ones included
+struct Cached<G: Geocoder> {
+    geocoder: G,
+    places: CHashMap<String, Option<String>>,
+}
+
+impl<G: Geocoder> Cached<G> {
+    fn new(geocoder: G) -> Cached<G> {
+        Cached {
+            geocoder,
+            places: CHashMap::new(),
+        }
+    }
+}
+
+impl<G: Geocoder> Geocoder for Cached<G> {
+    fn place(&self, lat: f32, lon: f32) -> Option<String> {
+        let cell = geohash(lat, lon, CACHE_PRECISION);
+        if let Some(place) = self.places.get(&cell) {
+            return place.clone();
+        }
+
+        let place = self.geocoder.place(lat, lon);
+        if self.places.len() >= MAX_CACHED {
+            self.places.clear();
+        }
+        self.places.insert(cell, place.clone());
+
+        place
+    }
+}
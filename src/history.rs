@@ -0,0 +1,164 @@
+use crossbeam::channel::{bounded, Receiver, Sender};
+use serde::Deserialize;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::server::unix_time;
+
+use crate::storage::{HistoryQuery, MessageRecord, Storage};
+use crate::wal::Wal;
+
+const BATCH_SIZE: usize = 100;
+// Messages waiting for the writer, past which recording one waits for room
+const MAX_QUEUED: usize = 10_000;
+pub const MAX_HISTORY_LIMIT: usize = 100;
+
+// Messages past either limit are deleted by a background sweep
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Retention {
+    pub max_age_secs: Option<u64>,
+    pub max_per_area: Option<usize>,
+    pub interval_secs: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Retention {
+        Retention {
+            max_age_secs: None,
+            max_per_area: None,
+            interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl Retention {
+    fn enabled(&self) -> bool {
+        self.max_age_secs.is_some() || self.max_per_area.is_some()
+    }
+}
+
+// Queues broadcast messages for storage, so sending only waits on the backend
+// when it falls far behind
+#[derive(Clone)]
+pub struct History {
+    storage: Arc<dyn Storage>,
+    tx: Sender<MessageRecord>,
+    wal: Option<Wal>,
+    // Recorded but not yet through the writer
+    unsaved: Arc<AtomicUsize>,
+}
+
+// Drains the queue in batches on its own thread
+pub struct HistoryWriter {
+    storage: Arc<dyn Storage>,
+    rx: Receiver<MessageRecord>,
+    wal: Option<Wal>,
+    unsaved: Arc<AtomicUsize>,
+}
+
+impl History {
+    pub fn new(storage: Arc<dyn Storage>, wal: Option<Wal>) -> (History, HistoryWriter) {
+        let (tx, rx) = bounded(MAX_QUEUED);
+        let unsaved = Arc::new(AtomicUsize::new(0));
+
+        (
+            History {
+                storage: storage.clone(),
+                tx,
+                wal: wal.clone(),
+                unsaved: unsaved.clone(),
+            },
+            HistoryWriter {
+                storage,
+                rx,
+                wal,
+                unsaved,
+            },
+        )
+    }
+
+    // Fails only when the message couldn't be made durable, it shouldn't be
+    // delivered then
+    pub fn record(&self, message: MessageRecord) -> Result<(), String> {
+        if let Some(ref wal) = self.wal {
+            wal.append(&message)?;
+        }
+
+        self.unsaved.fetch_add(1, Ordering::SeqCst);
+        let _ = self.tx.send(message);
+        Ok(())
+    }
+
+    // Waits until everything recorded so far has been through the writer
+    pub fn flush(&self) {
+        while self.unsaved.load(Ordering::SeqCst) > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    // Oldest first
+    pub fn export(&self) -> Result<Vec<MessageRecord>, String> {
+        self.flush();
+        self.storage.export_messages()
+    }
+
+    pub fn import(&self, messages: &[MessageRecord]) -> Result<(), String> {
+        self.storage.save_messages(messages)
+    }
+
+    pub fn query(&self, query: &HistoryQuery) -> Vec<MessageRecord> {
+        match self.storage.messages(query) {
+            Ok(messages) => messages,
+            Err(e) => {
+                println!("Failed to read message history: {}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+impl HistoryWriter {
+    pub fn run(self) {
+        while let Ok(message) = self.rx.recv() {
+            let mut batch = vec![message];
+            batch.extend(self.rx.try_iter().take(BATCH_SIZE - 1));
+
+            match self.storage.save_messages(&batch) {
+                Ok(()) => {
+                    if let Some(ref wal) = self.wal {
+                        wal.stored(batch.len());
+                    }
+                }
+                Err(e) => println!("Failed to save {} messages: {}", batch.len(), e),
+            }
+            self.unsaved.fetch_sub(batch.len(), Ordering::SeqCst);
+        }
+    }
+}
+
+pub fn prune(storage: Arc<dyn Storage>, retention: Retention) {
+    if !retention.enabled() {
+        return;
+    }
+
+    loop {
+        let sent_before = retention
+            .max_age_secs
+            .map(|max_age| unix_time().saturating_sub(max_age));
+
+        match storage.prune_messages(sent_before, retention.max_per_area) {
+            Ok(0) => (),
+            Ok(deleted) => println!("Pruned {} messages from history", deleted),
+            Err(e) => println!("Failed to prune message history: {}", e),
+        }
+
+        thread::sleep(Duration::from_secs(retention.interval_secs.max(1)));
+    }
+}
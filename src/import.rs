@@ -0,0 +1,131 @@
+use serde::Deserialize;
+use std::fs;
+
+use crate::mail;
+use crate::password;
+use crate::server::{valid_username, Users};
+
+// A user brought over from another community. Either the plaintext password,
+// which is hashed on import, or a hash this server can verify is required.
+#[derive(Deserialize, Default)]
+pub struct ImportedUser {
+    pub username: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_hash: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+// A JSON array of users, or CSV with a header naming the columns
+pub fn load(path: &str) -> Result<Vec<ImportedUser>, String> {
+    let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    if path.ends_with(".json") {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        parse_csv(&contents)
+    }
+}
+
+// Adds the users that don't clash with existing ones, returns how many were
+// added and why the others weren't
+pub fn import(users: &Users, imported: Vec<ImportedUser>) -> (usize, Vec<String>) {
+    let mut added = 0;
+    let mut skipped = Vec::new();
+
+    for user in imported {
+        let username = user.username.trim();
+        let hash = if !valid_username(username) {
+            Err("invalid username")
+        } else if users.contains_username(username) {
+            Err("username taken")
+        } else if !user.email.iter().all(|email| mail::valid_address(email)) {
+            Err("invalid email")
+        } else {
+            match (user.password_hash, user.password) {
+                (Some(hash), _) if password::is_hash(&hash) => Ok(hash),
+                (None, Some(ref password)) if !password.is_empty() => {
+                    Ok(users.hash_password(password))
+                }
+                _ => Err("no usable password"),
+            }
+        };
+
+        match hash {
+            Ok(hash) => {
+                let user_id = users.add_with_hash(username, hash);
+
+                // Addresses are taken as verified by the community they come from
+                if let Some(email) = user.email {
+                    if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                        user.email = Some(email);
+                        user.verified = true;
+                    }
+                    users.persist(user_id);
+                }
+
+                added += 1;
+            }
+            Err(reason) => skipped.push(format!("{}: {}", username, reason)),
+        }
+    }
+
+    (added, skipped)
+}
+
+fn parse_csv(contents: &str) -> Result<Vec<ImportedUser>, String> {
+    let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+    let header = match lines.next() {
+        Some(header) => split_csv(header),
+        None => return Ok(Vec::new()),
+    };
+    if !header.iter().any(|column| column == "username") {
+        return Err("CSV header has no username column".to_string());
+    }
+
+    Ok(lines
+        .map(|line| {
+            let mut user = ImportedUser::default();
+            for (column, value) in header.iter().zip(split_csv(line)) {
+                let value = if value.is_empty() { None } else { Some(value) };
+                match column.as_str() {
+                    "username" => user.username = value.unwrap_or_default(),
+                    "password" => user.password = value,
+                    "password_hash" => user.password_hash = value,
+                    "email" => user.email = value,
+                    _ => (),
+                }
+            }
+            user
+        })
+        .collect())
+}
+
+// Fields may be quoted, with "" for a quote inside them. Argon2 hashes
+// contain commas.
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(field.split_off(0)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+
+    fields
+        .iter()
+        .map(|field| field.trim().to_string())
+        .collect()
+}
@@ -22,8 +22,12 @@
 
         ws.onmessage = (e) => {
             console.log(e);
-            let obj = JSON.parse(e.data);
+            let data = JSON.parse(e.data);
+            // A burst of messages from one sender comes as an array
+            (Array.isArray(data) ? data : [data]).forEach(handleMessage);
+        }
 
+        function handleMessage(obj) {
             if ((obj.hasOwnProperty("LoginResponse") && obj.LoginResponse.status) || (obj.hasOwnProperty("RegisterResponse") && obj.RegisterResponse.status)) {
                 sendLocation();
             } else if (obj.hasOwnProperty("Message")) {
@@ -0,0 +1,45 @@
+use crate::config::JwtConfig;
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use std::fs;
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+}
+
+pub struct Jwt {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl Jwt {
+    pub fn new(config: &JwtConfig) -> Result<Jwt, String> {
+        let (algorithm, key) = match config.algorithm.as_str() {
+            "HS256" => (
+                Algorithm::HS256,
+                DecodingKey::from_secret(config.key.as_bytes()),
+            ),
+            "RS256" => {
+                let pem = fs::read(&config.key).map_err(|e| e.to_string())?;
+                let key = DecodingKey::from_rsa_pem(&pem).map_err(|e| e.to_string())?;
+
+                (Algorithm::RS256, key)
+            }
+            other => return Err(format!("unsupported algorithm {}", other)),
+        };
+
+        Ok(Jwt {
+            key,
+            validation: Validation::new(algorithm),
+        })
+    }
+
+    // Returns the token's subject claim, which identifies the user to the
+    // issuer rather than naming one here
+    pub fn verify(&self, token: &str) -> Option<String> {
+        decode::<Claims>(token, &self.key, &self.validation)
+            .ok()
+            .map(|data| data.claims.sub)
+    }
+}
@@ -0,0 +1,303 @@
+use serde::{de::DeserializeOwned, Serialize};
+use sled::{Batch, Db, Tree};
+use std::collections::HashSet;
+
+use crate::server::Area;
+use crate::storage::{
+    HistoryQuery, LocationRecord, MessageRecord, StatsRecord, Storage, UserRecord,
+};
+
+// Embedded key-value storage in a single directory. Records are JSON, keyed
+// by big-endian ids so that iteration follows id order.
+pub struct Sled {
+    db: Db,
+    users: Tree,
+    // Name to id
+    user_names: Tree,
+    messages: Tree,
+    // Area followed by message id, for reading an area's history in order
+    messages_by_area: Tree,
+    // User id, time and a unique id
+    locations: Tree,
+    // Time and a unique id
+    stats: Tree,
+}
+
+impl Sled {
+    pub fn open(path: &str) -> sled::Result<Sled> {
+        let db = sled::open(path)?;
+
+        Ok(Sled {
+            users: db.open_tree("users")?,
+            user_names: db.open_tree("user_names")?,
+            messages: db.open_tree("messages")?,
+            messages_by_area: db.open_tree("messages_by_area")?,
+            locations: db.open_tree("locations")?,
+            stats: db.open_tree("stats")?,
+            db,
+        })
+    }
+
+    fn message(&self, id: &[u8]) -> Result<Option<MessageRecord>, String> {
+        match self.messages.get(id).map_err(|e| e.to_string())? {
+            Some(value) => decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_message(&self, message: &MessageRecord) -> Result<(), String> {
+        self.messages
+            .remove(id_key(message.id))
+            .and_then(|_| {
+                self.messages_by_area
+                    .remove(area_key(message.area, message.id))
+            })
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl Storage for Sled {
+    fn load_users(&self) -> Result<Vec<UserRecord>, String> {
+        self.users
+            .iter()
+            .map(|entry| {
+                let (_, value) = entry.map_err(|e| e.to_string())?;
+                decode(&value)
+            })
+            .collect()
+    }
+
+    fn find_user(&self, name: &str) -> Result<Option<UserRecord>, String> {
+        let id = match self.user_names.get(name).map_err(|e| e.to_string())? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+
+        match self.users.get(&id).map_err(|e| e.to_string())? {
+            Some(value) => decode(&value).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn save_user(&self, user: &UserRecord) -> Result<(), String> {
+        let id = id_key(user.id);
+
+        self.users
+            .insert(id, encode(user)?)
+            .and_then(|_| self.user_names.insert(user.name.as_str(), &id[..]))
+            .and_then(|_| self.db.flush())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_messages(&self, messages: &[MessageRecord]) -> Result<(), String> {
+        let mut records = Batch::default();
+        let mut index = Batch::default();
+        for message in messages {
+            records.insert(&id_key(message.id)[..], encode(message)?);
+            index.insert(area_key(message.area, message.id), &[][..]);
+        }
+
+        self.messages
+            .apply_batch(records)
+            .and_then(|_| self.messages_by_area.apply_batch(index))
+            .and_then(|_| self.db.flush())
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn messages(&self, query: &HistoryQuery) -> Result<Vec<MessageRecord>, String> {
+        let start = area_key(query.area, 0);
+        let end = area_key(query.area, query.before.unwrap_or(usize::MAX));
+
+        let mut messages = Vec::new();
+        for entry in self.messages_by_area.range(start..end).rev() {
+            if messages.len() >= query.limit {
+                break;
+            }
+
+            let (key, _) = entry.map_err(|e| e.to_string())?;
+            if let Some(message) = self.message(&key[8..])? {
+                if query
+                    .search
+                    .iter()
+                    .all(|search| message.msg.contains(search.as_str()))
+                {
+                    messages.push(message);
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+
+    fn last_message_id(&self) -> Result<Option<usize>, String> {
+        self.messages
+            .last()
+            .map(|entry| entry.map(|(key, _)| decode_id(&key)))
+            .map_err(|e| e.to_string())
+    }
+
+    fn export_messages(&self) -> Result<Vec<MessageRecord>, String> {
+        self.messages
+            .iter()
+            .map(|entry| {
+                let (_, value) = entry.map_err(|e| e.to_string())?;
+                decode(&value)
+            })
+            .collect()
+    }
+
+    fn prune_messages(
+        &self,
+        sent_before: Option<u64>,
+        keep_per_area: Option<usize>,
+    ) -> Result<usize, String> {
+        let mut deleted = 0;
+
+        // Ids follow send order, so the old messages are all at the start
+        if let Some(sent_before) = sent_before {
+            for entry in self.messages.iter() {
+                let (_, value) = entry.map_err(|e| e.to_string())?;
+                let message: MessageRecord = decode(&value)?;
+                if message.time >= sent_before {
+                    break;
+                }
+
+                self.remove_message(&message)?;
+                deleted += 1;
+            }
+        }
+
+        // Newest first within each area
+        if let Some(keep) = keep_per_area {
+            let mut area = None;
+            let mut count = 0;
+            let mut excess = HashSet::new();
+            for entry in self.messages_by_area.iter().rev() {
+                let (key, _) = entry.map_err(|e| e.to_string())?;
+                if area != Some(key[..8].to_vec()) {
+                    area = Some(key[..8].to_vec());
+                    count = 0;
+                }
+
+                count += 1;
+                if count > keep {
+                    excess.insert(decode_id(&key[8..]));
+                }
+            }
+
+            for id in excess {
+                if let Some(message) = self.message(&id_key(id))? {
+                    self.remove_message(&message)?;
+                    deleted += 1;
+                }
+            }
+        }
+
+        self.db.flush().map_err(|e| e.to_string())?;
+        Ok(deleted)
+    }
+
+    fn save_location(&self, location: &LocationRecord) -> Result<(), String> {
+        let mut key = location_key(location.user_id, location.time);
+        key.extend_from_slice(
+            &self
+                .db
+                .generate_id()
+                .map_err(|e| e.to_string())?
+                .to_be_bytes(),
+        );
+
+        self.locations
+            .insert(key, encode(location)?)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn locations(
+        &self,
+        user_id: usize,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<LocationRecord>, String> {
+        self.locations
+            .range(location_key(user_id, since)..location_key(user_id, u64::MAX))
+            .take(limit)
+            .map(|entry| {
+                let (_, value) = entry.map_err(|e| e.to_string())?;
+                decode(&value)
+            })
+            .collect()
+    }
+
+    fn delete_locations(&self, user_id: usize) -> Result<(), String> {
+        for entry in self.locations.scan_prefix(id_key(user_id)) {
+            let (key, _) = entry.map_err(|e| e.to_string())?;
+            self.locations.remove(key).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn save_stats(&self, stats: &StatsRecord) -> Result<(), String> {
+        let mut key = stats.time.to_be_bytes().to_vec();
+        key.extend_from_slice(
+            &self
+                .db
+                .generate_id()
+                .map_err(|e| e.to_string())?
+                .to_be_bytes(),
+        );
+
+        self.stats
+            .insert(key, encode(stats)?)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn stats(&self, since: u64, limit: usize) -> Result<Vec<StatsRecord>, String> {
+        self.stats
+            .range(since.to_be_bytes()..)
+            .take(limit)
+            .map(|entry| {
+                let (_, value) = entry.map_err(|e| e.to_string())?;
+                decode(&value)
+            })
+            .collect()
+    }
+}
+
+fn id_key(id: usize) -> [u8; 8] {
+    (id as u64).to_be_bytes()
+}
+
+fn decode_id(key: &[u8]) -> usize {
+    let mut id = [0; 8];
+    id.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(id) as usize
+}
+
+// Sign bits are flipped so negative coordinates sort before positive ones
+fn area_key(area: Area, id: usize) -> Vec<u8> {
+    let mut key = Vec::with_capacity(16);
+    key.extend_from_slice(&(area.0 as u32 ^ 0x8000_0000).to_be_bytes());
+    key.extend_from_slice(&(area.1 as u32 ^ 0x8000_0000).to_be_bytes());
+    key.extend_from_slice(&id_key(id));
+    key
+}
+
+fn location_key(user_id: usize, time: u64) -> Vec<u8> {
+    let mut key = id_key(user_id).to_vec();
+    key.extend_from_slice(&time.to_be_bytes());
+    key
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    serde_json::to_vec(value).map_err(|e| e.to_string())
+}
+
+fn decode<T: DeserializeOwned>(value: &[u8]) -> Result<T, String> {
+    serde_json::from_slice(value).map_err(|e| e.to_string())
+}
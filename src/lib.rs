@@ -0,0 +1,41 @@
+// The server's parts, built as a library so the benchmarks can reach them.
+// Nothing else uses it, so it isn't held to the lints for public APIs.
+#![allow(clippy::new_without_default, clippy::len_without_is_empty)]
+
+pub mod addrban;
+pub mod audit;
+pub mod backup;
+pub mod bots;
+pub mod config;
+pub mod console;
+pub mod crypt;
+pub mod fences;
+pub mod geo;
+pub mod geocode;
+pub mod history;
+pub mod import;
+pub mod jwt;
+pub mod kv;
+pub mod mail;
+pub mod migrations;
+pub mod oauth;
+pub mod origin;
+pub mod password;
+pub mod pg;
+pub mod polls;
+pub mod pool;
+pub mod pow;
+pub mod queue;
+pub mod ratelimit;
+pub mod reports;
+pub mod server;
+pub mod shared;
+pub mod snapshot;
+pub mod socket;
+pub mod sqlite;
+pub mod stats;
+pub mod storage;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trail;
+pub mod wal;
@@ -0,0 +1,83 @@
+use serde::Deserialize;
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+    sync::Arc,
+};
+
+const MAX_EMAIL_LENGTH: usize = 254;
+
+pub trait Mailer: Send + Sync {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+#[derive(Deserialize, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum MailerConfig {
+    // Prints outgoing mail instead of sending it
+    #[default]
+    Log,
+    // Pipes the message to a sendmail compatible command
+    Sendmail {
+        command: String,
+        from: String,
+    },
+}
+
+pub fn from_config(config: &MailerConfig) -> Arc<dyn Mailer> {
+    match config {
+        MailerConfig::Log => Arc::new(LogMailer),
+        MailerConfig::Sendmail { command, from } => Arc::new(SendmailMailer {
+            command: command.clone(),
+            from: from.clone(),
+        }),
+    }
+}
+
+// Also keeps addresses from smuggling extra headers into the message
+pub fn valid_address(email: &str) -> bool {
+    email.len() <= MAX_EMAIL_LENGTH
+        && email.contains('@')
+        && !email.starts_with('@')
+        && !email.ends_with('@')
+        && !email.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        println!("Mail to {}: {}\n{}", to, subject, body);
+        Ok(())
+    }
+}
+
+struct SendmailMailer {
+    command: String,
+    from: String,
+}
+
+impl Mailer for SendmailMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let mut child = Command::new(&self.command)
+            .args(["-t", "-i"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| e.to_string())?;
+
+        if let Some(ref mut stdin) = child.stdin {
+            write!(
+                stdin,
+                "From: {}\nTo: {}\nSubject: {}\n\n{}\n",
+                self.from, to, subject, body
+            )
+            .map_err(|e| e.to_string())?;
+        }
+
+        match child.wait() {
+            Ok(status) if status.success() => Ok(()),
+            Ok(status) => Err(format!("{} exited with {}", self.command, status)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+}
@@ -1,162 +1,2708 @@
-#![warn(unused_extern_crates)]
+use core_affinity::CoreId;
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    iter,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-extern crate chashmap;
-extern crate crossbeam;
-extern crate parking_lot;
-extern crate pbkdf2;
-extern crate serde;
-extern crate serde_json;
-extern crate ws;
+use chat_server::addrban::{AddrBans, TrustedProxies};
+use chat_server::audit::{Audit, Entry, Event, MAX_QUERY_LIMIT};
+use chat_server::backup::Backup;
+use chat_server::bots::ApiKeys;
+use chat_server::config::Config;
+use chat_server::crypt::{Key, Sealed};
+use chat_server::fences::Fences;
+use chat_server::geo::{Geometry, HotspotConfig, Hotspots, Positions, RecentMessages};
+use chat_server::history::{History, MAX_HISTORY_LIMIT};
+use chat_server::jwt::Jwt;
+use chat_server::oauth::OAuth;
+use chat_server::password::Hasher;
+use chat_server::polls::Polls;
+use chat_server::pool::{user_key, Pool};
+use chat_server::pow::Challenges;
+use chat_server::queue::{Delayed, Queue};
+use chat_server::ratelimit::{ConnectionLimit, LoginAttempts, MessageRate, TokenBucket};
+use chat_server::reports::Reports;
+use chat_server::server::{
+    valid_username, ChatMessage, DataExport, Distance, EmailVerifications, Encoder, Expiry,
+    JsonMessage, LocationPoint, Message, Messages, NearbyUser, Nonces, PasswordResets,
+    RegisterError, Role, Server, Servers, SessionInfo, Sessions, Users,
+};
+use chat_server::shared::Shared;
+use chat_server::snapshot::Snapshot;
+use chat_server::socket::{CloseCode, Payload};
+use chat_server::stats::{Activity, Counters, Heatmap, MAX_STATS_LIMIT};
+use chat_server::storage::{HistoryQuery, MessageRecord};
+#[cfg(feature = "tls")]
+use chat_server::tls;
+use chat_server::trail::Trail;
+use chat_server::wal::Wal;
+use chat_server::{
+    console, geo, geocode, history, mail, password, server, snapshot, socket, stats, storage,
+};
 
-use crossbeam::channel::unbounded;
-use parking_lot::RwLock;
-use std::{sync::Arc, thread};
+// Messages waiting on the router and on each worker
+const QUEUE_CAPACITY: usize = 10_000;
+// Messages from one sender sent out in a single frame
+const MAX_COALESCED: usize = 20;
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+const LOGIN_FAILURE_DELAY: Duration = Duration::from_millis(250);
+// Subjects of login tokens are linked to users like those of a provider
+const JWT_PROVIDER: &str = "jwt";
+// How long a backup waits for the other workers to pause
+const QUIESCE_TIMEOUT: Duration = Duration::from_secs(1);
 
-mod server;
-use server::{JsonMessage, Message, Server, Servers, Users};
-
-const ENDPOINT: &str = "127.0.0.1:3012";
-const WORKERS: usize = 4;
 fn main() {
-    let (tx, rx) = unbounded();
+    let config = Config::load();
+    let jwt = match config.jwt {
+        Some(ref jwt) => match Jwt::new(jwt) {
+            Ok(jwt) => Some(Arc::new(jwt)),
+            Err(e) => {
+                println!("Invalid JWT configuration: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    #[cfg(feature = "tls")]
+    let tls = match config.tls {
+        Some(ref tls) => match tls::acceptor(tls) {
+            Ok(acceptor) => Some(Arc::new(acceptor)),
+            Err(e) => {
+                println!("Invalid TLS configuration: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+    #[cfg(not(feature = "tls"))]
+    {
+        if config.tls.is_some() {
+            println!("TLS is configured but the server was built without the tls feature");
+            return;
+        }
+    }
+    let origin_policy = Arc::new(config.origin_policy.clone());
+
+    let addr_bans = AddrBans::new();
+    for range in &config.banned_addrs {
+        if !addr_bans.ban(range) {
+            println!("Invalid address range: {}", range);
+            return;
+        }
+    }
+
+    let trusted_proxies = match TrustedProxies::new(&config.trusted_proxies) {
+        Ok(trusted_proxies) => trusted_proxies,
+        Err(range) => {
+            println!("Invalid address range: {}", range);
+            return;
+        }
+    };
+
+    let audit = match Audit::new(config.audit_log.as_deref()) {
+        Ok(audit) => audit,
+        Err(e) => {
+            println!("Failed to open audit log: {}", e);
+            return;
+        }
+    };
+
+    let key = match config.encryption_key {
+        Some(ref key) => match Key::from_base64(key) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                println!("Invalid encryption key: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let storage = match storage::from_config(&config.storage) {
+        Ok(storage) => match key {
+            Some(ref key) => Arc::new(Sealed::new(storage, key.clone())),
+            None => storage,
+        },
+        Err(e) => {
+            println!("Failed to open storage: {}", e);
+            return;
+        }
+    };
+    let records = match storage.load_users() {
+        Ok(records) => records,
+        Err(e) => {
+            println!("Failed to load users: {}", e);
+            return;
+        }
+    };
+    let (wal, unflushed) = match config.message_log {
+        Some(ref path) => match Wal::open(path) {
+            Ok((wal, unflushed)) => (Some(wal), unflushed),
+            Err(e) => {
+                println!("Failed to open message log {}: {}", path, e);
+                return;
+            }
+        },
+        None => (None, Vec::new()),
+    };
+    if let Some(ref wal) = wal {
+        if !unflushed.is_empty() {
+            if let Err(e) = storage.save_messages(&unflushed).and_then(|_| wal.clear()) {
+                println!("Failed to replay message log: {}", e);
+                return;
+            }
+            println!("Replayed {} logged messages", unflushed.len());
+        }
+    }
+
+    // Replayed messages count too, the memory backend doesn't report its last id
+    let last_logged_id = unflushed.iter().map(|message| message.id).max();
+    let first_message_id = match storage.last_message_id() {
+        Ok(last_id) => last_id.max(last_logged_id).map_or(0, |id| id + 1),
+        Err(e) => {
+            println!("Failed to load message history: {}", e);
+            return;
+        }
+    };
+
+    let snapshot = match config.snapshot {
+        Some(ref snapshot) => match Snapshot::load(&snapshot.path, key.as_ref()) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                println!("Failed to load snapshot {}: {}", snapshot.path, e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let fences = match Fences::load(config.fences.clone()) {
+        Ok(fences) => fences,
+        Err(e) => {
+            println!("Failed to load fences: {}", e);
+            return;
+        }
+    };
+
+    let shared = match config.redis {
+        Some(ref url) => match Shared::connect(url) {
+            Ok(shared) => Some(shared),
+            Err(e) => {
+                println!("Failed to connect to Redis: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    // Location updates dropped when the workers fell behind
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = Queue::bounded(QUEUE_CAPACITY, &dropped);
+
+    let pool = Pool::new(config.db_threads);
+    // Password checks and hashing take long enough to hold up storage access
+    let auth = Pool::new(config.auth_threads);
+    let users = Users::new(
+        Hasher::new(config.password_hash, config.pbkdf2_iterations),
+        config.password_policy.clone(),
+        storage.clone(),
+        pool.clone(),
+        Duration::from_secs(config.location_ttl_secs),
+    );
+    let (servers, servers_writer) = Servers::new();
+    let positions = Positions::new();
+    let recent_messages = RecentMessages::new();
+    let messages = Messages::new(first_message_id);
+    let (history, history_writer) = History::new(storage.clone(), wal);
+    let trail = Trail::new(storage.clone(), pool.clone());
+    let activity = Activity::new();
+    let counters = Counters::new();
+    let heatmap = Heatmap::new();
+    let sessions = Sessions::new(config.session.clone(), shared.clone());
+    let resets = PasswordResets::new();
+    let verifications = EmailVerifications::new();
+    let mailer = mail::from_config(&config.mailer);
+    let geocoder = match geocode::from_config(&config.geocoder) {
+        Ok(geocoder) => geocoder,
+        Err(e) => {
+            println!("Failed to load geocoder: {}", e);
+            return;
+        }
+    };
+    let require_email = config.require_email;
+    let radius_limits = config.radius.clone();
+    let reach = config.reach;
+    let hotspots = Hotspots::new(config.hotspots.clone());
+    let location_grid_m = config.location_grid_m;
+    let challenges = Challenges::new(config.registration_difficulty);
+    let nonces = Nonces::new();
+    let require_nonce = config.require_nonce;
+    let login_attempts = LoginAttempts::new(config.login_limit.clone());
+    let api_keys = ApiKeys::new();
+    let bot_rate = MessageRate::new(config.bot_limit.clone());
+    let connection_limit = ConnectionLimit::new(config.max_connections_per_ip);
+    let oauth = OAuth::new(config.oauth);
+    let polls = Polls::new();
+    let reports = Reports::new();
 
-    let users = Users::new();
-    let servers = Servers::new();
+    // Storage is written through on every change, so it wins over an older snapshot
+    if let Some(snapshot) = snapshot {
+        snapshot.restore(&users, &oauth, &api_keys);
+    }
+    users.restore(records);
 
-    let (t_tx, t_rx) = unbounded();
+    // Everything from a connection is handled by the worker its id picks, so
+    // it's handled in order and no two workers race over the same connection.
+    //
+    // Chat messages and moves used to go to the worker of the grid cell they
+    // were in instead. That can't be had alongside this: a message routed by
+    // cell could overtake the login or the move the same connection sent
+    // before it, and a worker could no longer find a sender's burst on its
+    // own queue. What the cells were for still holds. A move and what is said
+    // after it come from the same connection, so they are handled in order,
+    // and what a region shares (positions, users, history) is behind locks
+    // either way. Connection ids also spread a busy city over every worker,
+    // where its cell put it all on one.
+    let (worker_txs, worker_rxs): (Vec<_>, Vec<_>) = (0..config.workers.max(1))
+        .map(|_| Queue::bounded(QUEUE_CAPACITY, &dropped))
+        .unzip();
+    // Held shared while a worker handles a message or the session sweep runs,
+    // exclusively for backups
+    let quiesce = Arc::new(RwLock::new(()));
 
     let mut threads = Vec::new();
+    let started = Instant::now();
+
+    threads.push(thread::spawn(move || history_writer.run()));
+    threads.push(thread::spawn(move || servers_writer.run()));
+    let stats_activity = activity.clone();
+    let stats_storage = storage.clone();
+    let stats_period_secs = config.stats_period_secs;
+    threads.push(thread::spawn(move || {
+        stats::run(stats_activity, stats_storage, stats_period_secs)
+    }));
 
+    let retention = config.retention;
+    let prune_storage = storage.clone();
     threads.push(thread::spawn(move || {
-        if let Ok(socket) = ws::Builder::new()
-            .with_settings(ws::Settings {
-                max_connections: 100_000,
-                ..ws::Settings::default()
-            })
-            .build(|out| Server {
-                id: 0,
-                user_id: Arc::new(RwLock::new(None)),
-                socket: out,
-                channel: tx.clone(),
-            })
-        {
-            let _ = socket.listen(ENDPOINT);
+        history::prune(prune_storage, retention)
+    }));
+
+    if let Some(config) = config.snapshot {
+        let users = users.clone();
+        let oauth = oauth.clone();
+        let api_keys = api_keys.clone();
+        let key = key.clone();
+        threads.push(thread::spawn(move || {
+            snapshot::run(config, users, oauth, api_keys, key)
+        }));
+    }
+
+    // Work finished off the workers comes back through the router, by way of
+    // `delayed` so a pool never waits for room and a result is never lost.
+    // Failed logins are answered late through it too, rather than by sleeping.
+    let results = tx.clone();
+    let (delayed, delay_timer) = Delayed::new(results.clone());
+    threads.push(thread::spawn(move || delay_timer.run()));
+    let queues = iter::once(results.clone())
+        .chain(worker_txs.iter().cloned())
+        .collect::<Vec<_>>();
+    let listener_bans = addr_bans.clone();
+    let location_limit = config.location_limit.clone();
+    let endpoint = config.endpoint.clone();
+    let max_connections = config.max_connections;
+
+    // The listener gets the first core, the workers the ones after it
+    let cores = if config.pin_threads {
+        core_affinity::get_core_ids().unwrap_or_else(|| {
+            println!("Failed to list the cores, threads are left unpinned");
+            Vec::new()
+        })
+    } else {
+        Vec::new()
+    };
+    let core = |n: usize| {
+        if cores.is_empty() {
+            None
+        } else {
+            Some(cores[n % cores.len()])
+        }
+    };
+
+    threads.push(spawn_on(core(0), move || {
+        let settings = socket::Settings {
+            max_connections,
+            #[cfg(feature = "tls")]
+            tls,
+        };
+
+        let result = socket::listen(&endpoint, settings, move |socket| Server {
+            id: 0,
+            user_id: Arc::new(RwLock::new(None)),
+            socket,
+            channel: tx.clone(),
+            started,
+            addr: None,
+            connected_at: 0,
+            user_agent: None,
+            origin_policy: origin_policy.clone(),
+            addr_bans: listener_bans.clone(),
+            trusted_proxies: trusted_proxies.clone(),
+            location_rate: Arc::new(Mutex::new(TokenBucket::new(location_limit.clone()))),
+        });
+        if let Err(e) = result {
+            println!("Failed to listen on {}: {}", endpoint, e);
         }
     }));
 
-    for i in 0..WORKERS {
-        let t_rx = t_rx.clone();
+    for (i, worker_rx) in worker_rxs.into_iter().enumerate() {
         let users = users.clone();
         let servers = servers.clone();
+        let positions = positions.clone();
+        let fences = fences.clone();
+        let recent_messages = recent_messages.clone();
+        let messages = messages.clone();
+        let history = history.clone();
+        let storage = storage.clone();
+        let sessions = sessions.clone();
+        let shared = shared.clone();
+        let resets = resets.clone();
+        let verifications = verifications.clone();
+        let mailer = mailer.clone();
+        let challenges = challenges.clone();
+        let nonces = nonces.clone();
+        let login_attempts = login_attempts.clone();
+        let connection_limit = connection_limit.clone();
+        let addr_bans = addr_bans.clone();
+        let jwt = jwt.clone();
+        let oauth = oauth.clone();
+        let key = key.clone();
+        let api_keys = api_keys.clone();
+        let audit = audit.clone();
+        let counters = counters.clone();
+        let bot_rate = bot_rate.clone();
+        let polls = polls.clone();
+        let reports = reports.clone();
+        let quiesce = quiesce.clone();
+        let pool = pool.clone();
+        let auth = auth.clone();
+        let delayed = delayed.clone();
+        let radius_limits = radius_limits.clone();
+        let trail = trail.clone();
+        let activity = activity.clone();
+        let heatmap = heatmap.clone();
+        let geocoder = geocoder.clone();
+        let hotspots = hotspots.clone();
+        let dropped = dropped.clone();
+        let queues = queues.clone();
+        let mut encoder = Encoder::default();
+        // Taken off the queue while looking for more of a sender's messages
+        let mut stashed = VecDeque::new();
+
+        threads.push(spawn_on(core(i + 1), move || loop {
+            let msg = match stashed.pop_front() {
+                Some(msg) => Ok(msg),
+                None => worker_rx.recv(),
+            };
+
+            if let Ok(msg) = msg {
+                if let Some((user_id, required, id)) = Message::required_role(&msg) {
+                    if !users.has_role(user_id, required) {
+                        servers.send_to(id, &JsonMessage::PermissionDenied { required });
+                        continue;
+                    }
+                }
+
+                let exclusive = msg.exclusive();
+                let _paused = if exclusive {
+                    // The others may be held up on a full queue, and waiting on
+                    // them for long would hold this worker up too. Jobs of the
+                    // auth pool queue writes on the other, so it goes first.
+                    let paused = quiesce.try_write_for(QUIESCE_TIMEOUT).and_then(|workers| {
+                        let auth = auth.pause(QUIESCE_TIMEOUT)?;
+                        let pool = pool.pause(QUIESCE_TIMEOUT)?;
+                        Some((workers, auth, pool))
+                    });
+                    match paused {
+                        Some(paused) => Some(paused),
+                        None => {
+                            busy(&servers, msg.id());
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
+                let _running = if exclusive {
+                    None
+                } else {
+                    Some(quiesce.read())
+                };
 
-        threads.push(thread::spawn(move || loop {
-            if let Ok(msg) = t_rx.recv() {
                 match msg {
                     Message::Open { server, tx } => {
-                        let c_id = servers.get_next_id();
+                        if let Some(ref addr) = server.addr {
+                            if !connection_limit.open(addr) {
+                                println!("{}: refused connection from {}", i, addr);
+
+                                let _ = tx.send(None);
+                                continue;
+                            }
+                        }
 
+                        let c_id = server.id;
                         servers.update(c_id, server);
-                        println!(
-                            "{}: {} active servers (new with id {})",
-                            i,
-                            servers.len(),
-                            c_id
-                        );
+                        counters.opened();
 
-                        let _ = tx.send(c_id);
+                        let _ = tx.send(Some(c_id));
                     }
-                    Message::Close { id, code } => {
-                        servers.empty(id);
+                    Message::Close { id, .. } => {
+                        if let Some(addr) = servers.get(id).and_then(|server| server.addr) {
+                            connection_limit.close(&addr);
+                        }
 
-                        println!("{}: {} active servers ({:?})", i, servers.len(), code);
+                        release_guest(&users, &servers, &positions, &fences, id);
+                        servers.empty(id);
+                        sessions.disconnect(id);
+                        counters.closed();
                     }
                     Message::Login {
                         id,
                         username,
                         password,
-                        tx,
-                    } => {
-                        let status = {
-                            if let Some(user) = &users.get_by_name(&username) {
-                                match pbkdf2::pbkdf2_check(&password, &user.password) {
-                                    Ok(()) => {
-                                        if let Some(server) = servers.get(id) {
-                                            *server.user_id.write() = Some(user.id);
-                                            servers.update(id, server);
-                                        }
+                    } => {
+                        // No one can have such a name, so there is nothing to check
+                        if !valid_username(&username) {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::LoginResponse {
+                                    status: false,
+                                    token: None,
+                                },
+                            );
+                            continue;
+                        }
 
-                                        true
-                                    }
-                                    _ => false,
+                        let addr = servers.get(id).and_then(|server| server.addr);
+                        if let Some(retry_after) = login_attempts.locked(&username, addr.as_deref())
+                        {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::RateLimited {
+                                    retry_after: retry_after.as_secs() + 1,
+                                },
+                            );
+                            continue;
+                        }
+                        // Counted now rather than when the result is back, so
+                        // attempts made in the meantime see it
+                        login_attempts.attempted(&username, addr.as_deref());
+
+                        let users = users.clone();
+                        let servers = servers.clone();
+                        let delayed = delayed.clone();
+                        let key = user_key(&username);
+
+                        // Only looking the user up and verifying the password happen
+                        // off the worker, the rest is done when the result is back
+                        after_writes(&pool, &auth, servers.clone(), id, key, move || {
+                            let attempted = Instant::now();
+                            let (user_id, rehash) = match users.authenticate(&username, &password) {
+                                Some((user_id, rehash)) => (Some(user_id), rehash),
+                                None => (None, false),
+                            };
+
+                            if let (Some(user_id), true) = (user_id, rehash) {
+                                let hash = users.hash_password(&password);
+                                if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                                    user.password = hash;
                                 }
+                                users.persist(user_id);
+                            }
+
+                            // Every failure is answered at the same time after the attempt,
+                            // however quickly it was decided, without holding up the pool
+                            let result = Message::AuthResult {
+                                id,
+                                username,
+                                user_id,
+                            };
+                            if user_id.is_none() {
+                                delayed.send_at(attempted + LOGIN_FAILURE_DELAY, result);
                             } else {
-                                false
+                                delayed.send(result);
                             }
-                        };
+                        });
+                    }
+                    Message::AuthResult {
+                        id,
+                        username,
+                        user_id,
+                    } => {
+                        if user_id.is_some() {
+                            let addr = servers.get(id).and_then(|server| server.addr);
+                            login_attempts.succeeded(&username, addr.as_deref());
+                        }
+
+                        if let Some(response) =
+                            user_id.and_then(|user_id| users.restriction(user_id))
+                        {
+                            let entry =
+                                Entry::new(Event::Login, Some("password"), Some(username), false);
+                            record(&audit, &counters, &servers, id, entry);
+
+                            servers.send_to(id, &response);
+                            continue;
+                        }
 
-                        let _ = tx.send(JsonMessage::LoginResponse { status });
+                        let token = user_id.map(|user_id| {
+                            release_guest(&users, &servers, &positions, &fences, id);
+                            servers.set_user(id, Some(user_id));
+                            subscribe(&users, &servers, user_id);
+                            sessions.create(id, user_id)
+                        });
+
+                        let entry = Entry::new(
+                            Event::Login,
+                            Some("password"),
+                            Some(username),
+                            token.is_some(),
+                        );
+                        record(&audit, &counters, &servers, id, entry);
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::LoginResponse {
+                                status: token.is_some(),
+                                token,
+                            },
+                        );
                     }
                     Message::Register {
                         id,
                         username,
                         password,
-                        tx,
+                        email,
+                        proof,
                     } => {
-                        let status = {
-                            if users.contains_username(&username) {
-                                false
-                            } else {
-                                let user_id = users.add(&username, &password);
+                        let users = users.clone();
+                        let servers = servers.clone();
+                        let challenges = challenges.clone();
+                        let verifications = verifications.clone();
+                        let mailer = mailer.clone();
+                        let delayed = delayed.clone();
+                        let key = user_key(&username);
 
-                                if let Some(server) = servers.get(id) {
-                                    *server.user_id.write() = Some(user_id);
-                                    servers.update(id, server);
+                        // Also hashes the password and mails the verification code,
+                        // signing in is done when the result is back
+                        after_writes(&pool, &auth, servers.clone(), id, key, move || {
+                            let solved = match proof {
+                                Some(ref proof) => challenges.check(proof),
+                                None => false,
+                            };
+
+                            if challenges.difficulty() > 0 && !solved {
+                                let response = match challenges.create(id) {
+                                    Some(challenge) => JsonMessage::RegisterChallenge {
+                                        challenge,
+                                        difficulty: challenges.difficulty(),
+                                    },
+                                    None => JsonMessage::Error {
+                                        reason: "Too many challenges".to_string(),
+                                    },
+                                };
+                                servers.send_to(id, &response);
+                                return;
+                            }
+
+                            let result = {
+                                if !valid_username(&username) {
+                                    Err(RegisterError::InvalidUsername)
+                                } else if users.contains_username(&username) {
+                                    Err(RegisterError::UsernameTaken)
+                                } else if let Err(e) = users.check_password(&password) {
+                                    Err(RegisterError::from(e))
+                                } else if email.is_none() && require_email {
+                                    Err(RegisterError::EmailRequired)
+                                } else if !email.iter().all(|email| mail::valid_address(email)) {
+                                    Err(RegisterError::InvalidEmail)
+                                } else {
+                                    let user_id = users.add(&username, &password);
+
+                                    if let Some(email) = email {
+                                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                                            user.email = Some(email.clone());
+                                            user.verified = false;
+                                        }
+                                        users.persist(user_id);
+
+                                        let body = format!(
+                                            "Your verification code is {}",
+                                            verifications.create(user_id)
+                                        );
+                                        if let Err(e) =
+                                            mailer.send(&email, "Verify your email", &body)
+                                        {
+                                            println!("{}: failed to mail {}: {}", i, email, e);
+                                        }
+                                    }
+
+                                    Ok(user_id)
                                 }
+                            };
+
+                            delayed.send(Message::RegisterResult {
+                                id,
+                                username,
+                                result,
+                            });
+                        });
+                    }
+                    Message::RegisterResult {
+                        id,
+                        username,
+                        result,
+                    } => {
+                        let result = result.map(|user_id| {
+                            release_guest(&users, &servers, &positions, &fences, id);
+                            servers.set_user(id, Some(user_id));
+                            subscribe(&users, &servers, user_id);
+                            sessions.create(id, user_id)
+                        });
+
+                        let entry = Entry::new(
+                            Event::Register,
+                            Some("password"),
+                            Some(username),
+                            result.is_ok(),
+                        );
+                        record(&audit, &counters, &servers, id, entry);
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::RegisterResponse {
+                                status: result.is_ok(),
+                                reason: result.as_ref().err().cloned(),
+                                token: result.ok(),
+                            },
+                        );
+                    }
+                    Message::LoginToken { id, jwt: token } => {
+                        let subject = jwt.as_ref().and_then(|jwt| jwt.verify(&token));
+
+                        // Audited as the subject, like external identities are
+                        let username = subject
+                            .as_ref()
+                            .map(|subject| format!("{}:{}", JWT_PROVIDER, subject));
+                        let user_id = subject.and_then(|subject| {
+                            let name = Some(subject.clone());
+                            external_user(&users, &oauth, JWT_PROVIDER, subject, name)
+                        });
+
+                        if let Some(response) =
+                            user_id.and_then(|user_id| users.restriction(user_id))
+                        {
+                            record(
+                                &audit,
+                                &counters,
+                                &servers,
+                                id,
+                                Entry::new(Event::Login, Some("jwt"), username, false),
+                            );
+
+                            servers.send_to(id, &response);
+                            continue;
+                        }
+
+                        let token = user_id.map(|user_id| {
+                            release_guest(&users, &servers, &positions, &fences, id);
+                            servers.set_user(id, Some(user_id));
+                            subscribe(&users, &servers, user_id);
+                            sessions.create(id, user_id)
+                        });
+
+                        let entry =
+                            Entry::new(Event::Login, Some("jwt"), username, token.is_some());
+                        record(&audit, &counters, &servers, id, entry);
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::LoginResponse {
+                                status: token.is_some(),
+                                token,
+                            },
+                        );
+                    }
+                    Message::GuestLogin { id, nickname } => {
+                        let response = if !valid_username(&nickname) {
+                            JsonMessage::Error {
+                                reason: "Invalid nickname".to_string(),
+                            }
+                        } else if users.contains_username(&nickname) {
+                            JsonMessage::Error {
+                                reason: "Username taken".to_string(),
+                            }
+                        } else {
+                            // Taken before the guest it replaces is let go, so a
+                            // connection keeps who it was when the nickname is taken
+                            match users.add_guest(&nickname) {
+                                Some(user_id) => {
+                                    release_guest(&users, &servers, &positions, &fences, id);
+                                    servers.set_user(id, Some(user_id));
+                                    subscribe(&users, &servers, user_id);
 
-                                true
+                                    JsonMessage::LoginResponse {
+                                        status: true,
+                                        token: None,
+                                    }
+                                }
+                                None => JsonMessage::Error {
+                                    reason: "Nickname taken".to_string(),
+                                },
                             }
                         };
 
-                        let _ = tx.send(JsonMessage::RegisterResponse { status });
+                        servers.send_to(id, &response);
                     }
-                    Message::Message { user_id, msg } => {
-                        if let Some(user) = &users.get_by_id(user_id) {
-                            if let Ok(message) = serde_json::to_string(&JsonMessage::Message {
-                                username: user.name.clone(),
-                                msg: msg.clone(),
-                            }) {
-                                servers.read().for_each(|_, servers| {
-                                    if let Some(server) = servers.first() {
-                                        if let Some(user_id_other) = *server.user_id.read() {
-                                            if users.in_range(user_id, user_id_other) {
-                                                let _ = server.socket.send(message.clone());
-                                            }
-                                        }
+                    Message::LoginOAuth {
+                        id,
+                        provider,
+                        token,
+                    } => {
+                        let oauth = oauth.clone();
+                        let delayed = delayed.clone();
+
+                        // The provider can take seconds to answer, so it is asked
+                        // off the worker and the rest is done when the answer is back
+                        auth.execute(move || {
+                            let identity = oauth.verify(&provider, &token);
+                            let result = Message::OAuthResult {
+                                id,
+                                provider,
+                                identity,
+                            };
+                            delayed.send(result);
+                        });
+                    }
+                    Message::OAuthResult {
+                        id,
+                        provider,
+                        identity,
+                    } => {
+                        // Audited as the external identity, which is stable across renames
+                        let subject = identity
+                            .as_ref()
+                            .map(|identity| format!("{}:{}", provider, identity.subject));
+
+                        let user_id = identity.and_then(|identity| {
+                            external_user(
+                                &users,
+                                &oauth,
+                                &provider,
+                                identity.subject,
+                                identity.name,
+                            )
+                        });
+
+                        if let Some(response) =
+                            user_id.and_then(|user_id| users.restriction(user_id))
+                        {
+                            record(
+                                &audit,
+                                &counters,
+                                &servers,
+                                id,
+                                Entry::new(Event::Login, Some("oauth"), subject, false),
+                            );
+
+                            servers.send_to(id, &response);
+                            continue;
+                        }
+
+                        let token = user_id.map(|user_id| {
+                            release_guest(&users, &servers, &positions, &fences, id);
+                            servers.set_user(id, Some(user_id));
+                            subscribe(&users, &servers, user_id);
+                            sessions.create(id, user_id)
+                        });
+
+                        let entry =
+                            Entry::new(Event::Login, Some("oauth"), subject, token.is_some());
+                        record(&audit, &counters, &servers, id, entry);
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::LoginResponse {
+                                status: token.is_some(),
+                                token,
+                            },
+                        );
+                    }
+                    Message::BotLogin { id, key } => {
+                        let user_id = api_keys.verify(&key);
+                        let username = user_id
+                            .and_then(|user_id| users.get_by_id(user_id))
+                            .map(|user| user.name.clone());
+
+                        if let Some(response) =
+                            user_id.and_then(|user_id| users.restriction(user_id))
+                        {
+                            record(
+                                &audit,
+                                &counters,
+                                &servers,
+                                id,
+                                Entry::new(Event::Login, Some("api_key"), username, false),
+                            );
+
+                            servers.send_to(id, &response);
+                            continue;
+                        }
+
+                        if let Some(user_id) = user_id {
+                            release_guest(&users, &servers, &positions, &fences, id);
+                            servers.set_user(id, Some(user_id));
+                            subscribe(&users, &servers, user_id);
+                        }
+
+                        let entry =
+                            Entry::new(Event::Login, Some("api_key"), username, user_id.is_some());
+                        record(&audit, &counters, &servers, id, entry);
+
+                        // Bots log in with their key every time, so there is no session
+                        servers.send_to(
+                            id,
+                            &JsonMessage::LoginResponse {
+                                status: user_id.is_some(),
+                                token: None,
+                            },
+                        );
+                    }
+                    Message::Resume { id, token } => {
+                        let user_id = sessions.resume(id, &token);
+                        if let Some(user_id) = user_id {
+                            release_guest(&users, &servers, &positions, &fences, id);
+                            servers.set_user(id, Some(user_id));
+                            subscribe(&users, &servers, user_id);
+                        }
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::ResumeResponse {
+                                status: user_id.is_some(),
+                            },
+                        );
+                    }
+                    Message::Logout { id } => {
+                        let user_id = servers.get(id).and_then(|server| *server.user_id.read());
+                        let status = user_id.is_some();
+
+                        if let Some(user_id) = user_id {
+                            let username = users.get_by_id(user_id).map(|user| user.name.clone());
+                            record(
+                                &audit,
+                                &counters,
+                                &servers,
+                                id,
+                                Entry::new(Event::Logout, None, username, true),
+                            );
+                        }
+
+                        release_guest(&users, &servers, &positions, &fences, id);
+                        servers.set_user(id, None);
+                        sessions.end(id);
+
+                        servers.send_to(id, &JsonMessage::LogoutResponse { status });
+                    }
+                    Message::ListSessions { id, user_id } => {
+                        let sessions = servers
+                            .find_by_user(user_id)
+                            .into_iter()
+                            .map(|server| SessionInfo {
+                                id: server.id,
+                                connected_at: server.connected_at,
+                                user_agent: server.user_agent,
+                                addr: server.addr,
+                                current: server.id == id,
+                            })
+                            .collect();
+
+                        servers.send_to(id, &JsonMessage::SessionList { sessions });
+                    }
+                    Message::RevokeSession {
+                        id,
+                        user_id,
+                        session_id,
+                    } => {
+                        // Only the user's own connections can be revoked
+                        let revoked: Vec<Server> = servers
+                            .find_by_user(user_id)
+                            .into_iter()
+                            .filter(|server| match session_id {
+                                Some(session_id) => server.id == session_id,
+                                None => server.id != id,
+                            })
+                            .collect();
+
+                        if session_id.is_none() {
+                            sessions.end_others(id, user_id);
+                        }
+
+                        for server in &revoked {
+                            sessions.end(server.id);
+                            servers.set_user(server.id, None);
+                            let _ = server.socket.close(CloseCode::Normal);
+                        }
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::RevokeResponse {
+                                status: session_id.is_none() || !revoked.is_empty(),
+                            },
+                        );
+                    }
+                    Message::Nonce { id } => {
+                        servers.send_to(
+                            id,
+                            &JsonMessage::Nonce {
+                                nonce: nonces.create(id),
+                            },
+                        );
+                    }
+                    Message::ChangePassword {
+                        id,
+                        user_id,
+                        old,
+                        new,
+                        nonce,
+                    } => {
+                        // Checked even when not required, so a supplied nonce is always used up
+                        let replayed = match nonce {
+                            Some(ref nonce) => !nonces.consume(id, nonce),
+                            None => require_nonce,
+                        };
+
+                        if replayed {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::Error {
+                                    reason: "Invalid nonce".to_string(),
+                                },
+                            );
+                            continue;
+                        }
+
+                        let users = users.clone();
+                        let servers = servers.clone();
+                        let sessions = sessions.clone();
+                        let audit = audit.clone();
+                        let counters = counters.clone();
+                        let delayed = delayed.clone();
+
+                        auth.execute_for(user_id, move || {
+                            // Not checked under the user's lock, which would stall
+                            // everything else that touches the user
+                            let hash = users.get_by_id(user_id).map(|user| user.password.clone());
+                            let status = match hash {
+                                Some(hash) => {
+                                    users.check_password(&new).is_ok()
+                                        && password::verify(&old, &hash)
+                                }
+                                None => false,
+                            };
+
+                            if status {
+                                let hash = users.hash_password(&new);
+                                if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                                    user.password = hash;
+                                }
+                                users.persist(user_id);
+
+                                sessions.end_others(id, user_id);
+
+                                for server in servers.find_by_user(user_id) {
+                                    if server.id != id {
+                                        delayed.send(Message::SignOut {
+                                            id: server.id,
+                                            user_id,
+                                            expired: false,
+                                        });
                                     }
-                                });
+                                }
+                            }
+
+                            let username = users.get_by_id(user_id).map(|user| user.name.clone());
+                            let entry = Entry::new(
+                                Event::PasswordChange,
+                                Some("password"),
+                                username,
+                                status,
+                            );
+                            record(&audit, &counters, &servers, id, entry);
+
+                            servers.send_to(id, &JsonMessage::ChangePasswordResponse { status });
+                        });
+                    }
+                    Message::VerifyEmail { user_id, code, id } => {
+                        let status = verifications.consume(user_id, &code);
+                        if status {
+                            if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                                user.verified = true;
                             }
+                            users.persist(user_id);
                         }
+
+                        servers.send_to(id, &JsonMessage::VerifyEmailResponse { status });
                     }
-                    Message::Location { user_id, lat, lon } => {
-                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
-                            user.lat = lat;
-                            user.lon = lon;
+                    Message::ResetPassword {
+                        id,
+                        token,
+                        new_password,
+                    } => {
+                        let user_id = match users.check_password(&new_password) {
+                            Ok(()) => resets.consume(&token),
+                            Err(_) => None,
+                        };
+
+                        let users = users.clone();
+                        let servers = servers.clone();
+                        let sessions = sessions.clone();
+                        let audit = audit.clone();
+                        let counters = counters.clone();
+                        let delayed = delayed.clone();
+
+                        auth.execute(move || {
+                            if let Some(user_id) = user_id {
+                                let hash = users.hash_password(&new_password);
+                                if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                                    user.password = hash;
+                                }
+                                users.persist(user_id);
+
+                                sessions.end_others(id, user_id);
+
+                                for server in servers.find_by_user(user_id) {
+                                    delayed.send(Message::SignOut {
+                                        id: server.id,
+                                        user_id,
+                                        expired: false,
+                                    });
+                                }
+                            }
+
+                            let username = user_id
+                                .and_then(|user_id| users.get_by_id(user_id))
+                                .map(|user| user.name.clone());
+                            let entry = Entry::new(
+                                Event::PasswordReset,
+                                Some("reset_token"),
+                                username,
+                                user_id.is_some(),
+                            );
+                            record(&audit, &counters, &servers, id, entry);
+
+                            servers.send_to(
+                                id,
+                                &JsonMessage::ResetPasswordResponse {
+                                    status: user_id.is_some(),
+                                },
+                            );
+                        });
+                    }
+                    Message::SignOut {
+                        id,
+                        user_id,
+                        expired,
+                    } => {
+                        // Left alone if someone else signed in on the connection
+                        // since, or the session was renewed
+                        let current = servers.get(id).and_then(|server| *server.user_id.read());
+                        if current != Some(user_id)
+                            || (expired && !matches!(sessions.expiry(id), Expiry::Expired))
+                        {
+                            continue;
+                        }
+
+                        if expired {
+                            sessions.end(id);
+                        }
+                        servers.set_user(id, None);
+
+                        if expired {
+                            servers.send_to(id, &JsonMessage::SessionExpired);
                         }
                     }
-                }
-            } else {
-                thread::yield_now();
-            }
-        }));
-    }
+                    Message::Message {
+                        id,
+                        user_id,
+                        msg,
+                        client_id,
+                        whisper,
+                    } => {
+                        // Messages the sender has queued right behind this one go
+                        // out with it in one frame per recipient. Nothing is waited
+                        // for. The sender's activity in between is noted once after
+                        // them, anything else taken off the queue is handled next.
+                        let mut burst = vec![(msg, client_id)];
+                        let mut seen = None;
+                        while burst.len() < MAX_COALESCED {
+                            match worker_rx.try_recv() {
+                                Ok(Message::Message {
+                                    id: next_id,
+                                    user_id: next_user_id,
+                                    msg,
+                                    client_id,
+                                    whisper: next_whisper,
+                                }) if next_id == id
+                                    && next_user_id == user_id
+                                    && next_whisper == whisper =>
+                                {
+                                    burst.push((msg, client_id))
+                                }
+                                Ok(next @ Message::Seen { .. }) if next.id() == id => {
+                                    seen = Some(next)
+                                }
+                                Ok(next) => {
+                                    stashed.push_back(next);
+                                    break;
+                                }
+                                Err(_) => break,
+                            }
+                        }
+                        if let Some(seen) = seen {
+                            stashed.push_front(seen);
+                        }
 
-    threads.push(thread::spawn(move || {
-        while let Ok(msg) = rx.recv() {
-            let _ = t_tx.send(msg);
-        }
-    }));
+                        let mut sent = Vec::with_capacity(burst.len());
+                        let mut global = None;
+                        let mut burst = burst.into_iter();
+                        while let Some((msg, client_id)) = burst.next() {
+                            let (username, guest, bot, (lat, lon), (area, in_global), duplicate) =
+                                match users.get_mut_by_id(user_id) {
+                                    Some(ref user) if !user.verified => {
+                                        servers.send_to(
+                                            id,
+                                            &JsonMessage::Error {
+                                                reason: "Email not verified".to_string(),
+                                            },
+                                        );
+                                        continue;
+                                    }
+                                    Some(ref mut user) => (
+                                        user.name.clone(),
+                                        user.guest,
+                                        user.bot,
+                                        (user.lat, user.lon),
+                                        (user.area(), user.global),
+                                        client_id
+                                            .as_ref()
+                                            .and_then(|client_id| user.sent_message(client_id)),
+                                    ),
+                                    None => continue,
+                                };
 
-    for thread in threads {
-        let _ = thread.join();
+                            // What comes after a move in or out of the global room
+                            // goes out to the other room, in a frame of its own
+                            if global.is_some_and(|global| global != in_global) {
+                                let rest = iter::once((msg, client_id)).chain(burst.by_ref());
+                                for (msg, client_id) in rest.collect::<Vec<_>>().into_iter().rev() {
+                                    stashed.push_front(Message::Message {
+                                        id,
+                                        user_id,
+                                        msg,
+                                        client_id,
+                                        whisper,
+                                    });
+                                }
+                                break;
+                            }
+                            global = Some(in_global);
+
+                            if let Some(message_id) = duplicate {
+                                send_ack(&servers, id, message_id, client_id);
+                                continue;
+                            }
+
+                            if bot {
+                                if let Some(retry_after) = bot_rate.check(user_id) {
+                                    servers.send_to(
+                                        id,
+                                        &JsonMessage::RateLimited {
+                                            retry_after: retry_after.as_secs() + 1,
+                                        },
+                                    );
+                                    continue;
+                                }
+                            }
+
+                            let message = messages.add(user_id, area, username, guest, bot, msg);
+                            activity.message(user_id, area);
+                            if !in_global {
+                                recent_messages.record(lat, lon);
+                                heatmap.message(lat, lon);
+                            }
+                            let message_id = message.id;
+
+                            if let Err(e) = history.record(MessageRecord {
+                                id: message_id,
+                                user_id,
+                                username: message.username.clone(),
+                                guest,
+                                bot,
+                                area,
+                                time: server::unix_time(),
+                                msg: message.msg.clone(),
+                            }) {
+                                println!("{}: failed to log message {}: {}", i, message_id, e);
+                                servers.send_to(
+                                    id,
+                                    &JsonMessage::Error {
+                                        reason: "Message could not be stored".to_string(),
+                                    },
+                                );
+                                continue;
+                            }
+
+                            if let Some(client_id) = client_id {
+                                if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                                    user.remember_sent(client_id.clone(), message_id);
+                                }
+
+                                send_ack(&servers, id, message_id, Some(client_id));
+                            }
+
+                            sent.push(message);
+                        }
+
+                        if sent.is_empty() {
+                            continue;
+                        }
+                        let global = global.unwrap_or(false);
+
+                        let place = match users.get_by_id(user_id) {
+                            Some(ref user) if !global => user.place.clone(),
+                            _ => None,
+                        };
+
+                        // Each recipient gets their own distance, serialized once for
+                        // everyone it is rounded the same for
+                        let mut payloads = HashMap::new();
+                        let mut serialize = |user_id_other: usize| {
+                            let distance = if global || user_id_other == user_id {
+                                None
+                            } else {
+                                users.distance(user_id_other, user_id)
+                            };
+                            let key = distance
+                                .as_ref()
+                                .map(|distance| (distance.value.to_bits(), distance.unit));
+
+                            if let Some(payload) = payloads.get(&key) {
+                                return Some(Payload::clone(payload));
+                            }
+
+                            let batch = sent
+                                .iter()
+                                .map(|message| JsonMessage::Message {
+                                    id: message.id,
+                                    username: message.username.clone(),
+                                    guest: message.guest,
+                                    bot: message.bot,
+                                    msg: message.msg.clone(),
+                                    whisper,
+                                    distance: distance.clone(),
+                                    place: place.clone(),
+                                })
+                                .collect::<Vec<_>>();
+                            let payload = encoder.encode_batch(&batch)?;
+                            payloads.insert(key, payload.clone());
+
+                            Some(payload)
+                        };
+                        let add_unread = |user_id_other: usize| {
+                            if let Some(ref mut other) = users.get_mut_by_id(user_id_other) {
+                                for message in &sent {
+                                    other.add_unread(message.id);
+                                }
+                            }
+                        };
+
+                        // Whispers stay with the users around the sender, in the
+                        // global room too
+                        if global && !whisper {
+                            servers.for_each_global(|socket, user_id_other| {
+                                if let Some(payload) = serialize(user_id_other) {
+                                    let _ = socket.send_shared(&payload);
+                                }
+
+                                if user_id_other != user_id {
+                                    add_unread(user_id_other);
+                                }
+                            });
+                            continue;
+                        }
+
+                        let mut reached = HashSet::new();
+                        let radius = if whisper {
+                            reach.whisper_km
+                        } else {
+                            reach.message_km
+                        };
+                        servers.for_each_in_range(
+                            &users,
+                            user_id,
+                            radius,
+                            |socket, user_id_other| {
+                                if let Some(payload) = serialize(user_id_other) {
+                                    let _ = socket.send_shared(&payload);
+                                }
+                                reached.insert(user_id_other);
+
+                                if user_id_other != user_id {
+                                    add_unread(user_id_other);
+                                }
+                            },
+                        );
+
+                        // Everyone along the sender's recent path and in a fence
+                        // with them, however far
+                        let route = if whisper {
+                            HashSet::new()
+                        } else {
+                            along_route(&users, &servers, &positions, user_id, radius)
+                        };
+                        let fenced = if whisper {
+                            HashSet::new()
+                        } else {
+                            fences.neighbors(user_id)
+                        };
+                        for user_id_other in route.into_iter().chain(fenced) {
+                            if !reached.insert(user_id_other) {
+                                continue;
+                            }
+
+                            if let Some(payload) = serialize(user_id_other) {
+                                for server in servers.find_by_user(user_id_other) {
+                                    let _ = server.socket.send_shared(&payload);
+                                }
+                            }
+                            add_unread(user_id_other);
+                        }
+                    }
+                    Message::Location {
+                        id,
+                        user_id,
+                        lat,
+                        lon,
+                        alt,
+                        accuracy_m,
+                    } => {
+                        if !geo::valid(lat, lon) {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::Error {
+                                    reason: "Invalid location".to_string(),
+                                },
+                            );
+                            continue;
+                        }
+
+                        let (plausible, moved, keep, lat, lon) = match users.get_mut_by_id(user_id)
+                        {
+                            Some(ref mut user) => {
+                                let (lat, lon) = if user.fuzz_location {
+                                    geo::snap(lat, lon, location_grid_m)
+                                } else {
+                                    (lat, lon)
+                                };
+                                let previous = user.area();
+                                let plausible = user.relocate(lat, lon, alt, accuracy_m);
+
+                                (
+                                    plausible,
+                                    previous != user.area(),
+                                    user.location_history && !user.guest,
+                                    lat,
+                                    lon,
+                                )
+                            }
+                            None => (true, false, false, lat, lon),
+                        };
+
+                        if plausible {
+                            subscribe(&users, &servers, user_id);
+                            let (cell, count) = positions.update(user_id, lat, lon);
+                            if hotspots.check(&cell, count) {
+                                report_hotspot(
+                                    &users,
+                                    &servers,
+                                    &positions,
+                                    &hotspots.config,
+                                    &cell,
+                                    count,
+                                );
+                            }
+                        }
+                        if plausible && keep {
+                            trail.record(user_id, lat, lon);
+                        }
+                        activity.active(user_id);
+
+                        if !plausible {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::Error {
+                                    reason: "Implausible location".to_string(),
+                                },
+                            );
+                        }
+
+                        if let (true, Some(geocoder)) = (plausible, geocoder.clone()) {
+                            let users = users.clone();
+                            pool.execute_for(user_id, move || {
+                                let place = geocoder.place(lat, lon);
+                                if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                                    user.place = place;
+                                }
+                            });
+                        }
+
+                        if plausible {
+                            let now = online_in_range(
+                                &users,
+                                &servers,
+                                &positions,
+                                user_id,
+                                reach.presence_km,
+                            );
+                            let (entered, left) = positions.set_neighbors(user_id, now);
+
+                            for other in entered {
+                                notify_range(&users, &servers, user_id, other, true);
+                            }
+                            for other in left {
+                                notify_range(&users, &servers, user_id, other, false);
+                            }
+
+                            let (entered, left) = fences.locate(user_id, lat, lon);
+                            let username = users.get_by_id(user_id).map(|user| user.name.clone());
+
+                            if entered.iter().chain(left.iter()).any(|fence| fence.poi) {
+                                let channels = JsonMessage::Channels {
+                                    channels: fences.channels(user_id),
+                                };
+                                if let Ok(json) = serde_json::to_string(&channels) {
+                                    for server in servers.find_by_user(user_id) {
+                                        let _ = server.socket.send(json.clone());
+                                    }
+                                }
+                            }
+
+                            if let Some(username) = username {
+                                for fence in entered {
+                                    let notice = JsonMessage::FenceJoined {
+                                        fence: fence.name,
+                                        username: username.clone(),
+                                    };
+                                    send_to_fence(&servers, &fences, fence.id, &notice);
+                                }
+                                // Sent to the one leaving too, who is no longer a member
+                                for fence in left {
+                                    let notice = JsonMessage::FenceLeft {
+                                        fence: fence.name,
+                                        username: username.clone(),
+                                    };
+                                    send_to_fence(&servers, &fences, fence.id, &notice);
+                                    servers.send_to(id, &notice);
+                                }
+                            }
+                        }
+
+                        if moved {
+                            let pinned = messages.pinned(server::area(lat, lon));
+
+                            if !pinned.is_empty() {
+                                if let Some(server) = servers.get(id) {
+                                    if let Ok(json) = serde_json::to_string(&JsonMessage::Pinned {
+                                        messages: pinned,
+                                    }) {
+                                        let _ = server.socket.send(json);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Message::ShareLocation { user_id, .. } => {
+                        let shared = match users.get_by_id(user_id) {
+                            Some(user) => encoder.encode(&JsonMessage::SharedLocation {
+                                username: user.name.clone(),
+                                guest: user.guest,
+                                bot: user.bot,
+                                lat: user.lat,
+                                lon: user.lon,
+                                geometry: Geometry::point(user.lat, user.lon),
+                            }),
+                            None => continue,
+                        };
+
+                        if let Some(shared) = shared {
+                            servers.for_each_in_range(
+                                &users,
+                                user_id,
+                                reach.message_km,
+                                |socket, _| {
+                                    let _ = socket.send_event(&shared);
+                                },
+                            );
+                        }
+                    }
+                    Message::Status {
+                        user_id, status, ..
+                    } => {
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.status = status;
+                        }
+                        users.persist(user_id);
+                    }
+                    Message::Seen { id, user_id } => {
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.last_seen = Instant::now();
+                        }
+                        sessions.touch(id);
+                    }
+                    Message::LastSeenVisible {
+                        user_id, visible, ..
+                    } => {
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.last_seen_visible = visible;
+                        }
+                        users.persist(user_id);
+                    }
+                    Message::Radius { user_id, km, id } => {
+                        let km = radius_limits.clamp(km);
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.radius_km = Some(km);
+                        }
+                        users.persist(user_id);
+
+                        servers.send_to(id, &JsonMessage::Radius { km });
+                    }
+                    Message::AreaInfo { user_id, id } => {
+                        let (lat, lon, radius) = match users.get_by_id(user_id) {
+                            Some(user) => (user.lat, user.lon, user.radius()),
+                            None => continue,
+                        };
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::AreaInfo {
+                                online_users: online_in_range(
+                                    &users,
+                                    &servers,
+                                    &positions,
+                                    user_id,
+                                    reach.message_km,
+                                )
+                                .len(),
+                                messages_per_minute: recent_messages.rate(lat, lon, radius),
+                            },
+                        );
+                    }
+                    Message::Fences { id } => {
+                        servers.send_to(
+                            id,
+                            &JsonMessage::Fences {
+                                fences: fences.list(),
+                            },
+                        );
+                    }
+                    Message::AddFence {
+                        name,
+                        shape,
+                        poi,
+                        id,
+                        ..
+                    } => {
+                        servers.send_to(
+                            id,
+                            &match fences.add(name, shape, poi) {
+                                Ok(()) => JsonMessage::Fences {
+                                    fences: fences.list(),
+                                },
+                                Err(reason) => JsonMessage::Error {
+                                    reason: reason.to_string(),
+                                },
+                            },
+                        );
+                    }
+                    Message::RemoveFence { id, fence_id, .. } => {
+                        servers.send_to(
+                            id,
+                            &if fences.remove(fence_id) {
+                                JsonMessage::Fences {
+                                    fences: fences.list(),
+                                }
+                            } else {
+                                JsonMessage::Error {
+                                    reason: "No such fence".to_string(),
+                                }
+                            },
+                        );
+                    }
+                    Message::Channels { user_id, id } => {
+                        servers.send_to(
+                            id,
+                            &JsonMessage::Channels {
+                                channels: fences.channels(user_id),
+                            },
+                        );
+                    }
+                    Message::ToChannel {
+                        id,
+                        user_id,
+                        channel,
+                        msg,
+                    } => {
+                        let (username, guest, bot) = match users.get_by_id(user_id) {
+                            Some(ref user) if !user.verified => {
+                                servers.send_to(
+                                    id,
+                                    &JsonMessage::Error {
+                                        reason: "Email not verified".to_string(),
+                                    },
+                                );
+                                continue;
+                            }
+                            Some(user) => (user.name.clone(), user.guest, user.bot),
+                            None => continue,
+                        };
+
+                        if !fences.is_member(channel, user_id) {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::Error {
+                                    reason: "Not in that channel".to_string(),
+                                },
+                            );
+                            continue;
+                        }
+
+                        if bot {
+                            if let Some(retry_after) = bot_rate.check(user_id) {
+                                servers.send_to(
+                                    id,
+                                    &JsonMessage::RateLimited {
+                                        retry_after: retry_after.as_secs() + 1,
+                                    },
+                                );
+                                continue;
+                            }
+                        }
+
+                        let message = JsonMessage::ChannelMessage {
+                            channel,
+                            username,
+                            guest,
+                            bot,
+                            msg,
+                        };
+                        send_to_fence(&servers, &fences, channel, &message);
+                    }
+                    Message::Nearby { user_id, limit, id } => {
+                        let (nearest, unit) = match users.get_by_id(user_id) {
+                            Some(user) => (
+                                positions.nearest(user.lat, user.lon, user.radius()),
+                                user.unit(),
+                            ),
+                            None => continue,
+                        };
+
+                        let nearby = nearest
+                            .into_iter()
+                            .filter(|&(other, _)| {
+                                other != user_id
+                                    && users.in_range(user_id, other, reach.message_km)
+                                    && !servers.find_by_user(other).is_empty()
+                            })
+                            .filter_map(|(other, distance)| {
+                                users.get_by_id(other).map(|other| NearbyUser {
+                                    username: other.name.clone(),
+                                    guest: other.guest,
+                                    bot: other.bot,
+                                    distance: Distance::coarse(distance, unit),
+                                    place: other.place.clone(),
+                                })
+                            })
+                            .take(limit)
+                            .collect();
+
+                        servers.send_to(id, &JsonMessage::NearbyUsers { users: nearby });
+                    }
+                    Message::RouteMode {
+                        user_id, enabled, ..
+                    } => {
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.set_route_mode(enabled);
+                        }
+                    }
+                    Message::Global {
+                        user_id, enabled, ..
+                    } => {
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.global = enabled;
+                        }
+                        subscribe(&users, &servers, user_id);
+                    }
+                    Message::FuzzLocation {
+                        user_id, enabled, ..
+                    } => {
+                        // The exact location known so far is dropped right away
+                        let snapped = match users.get_mut_by_id(user_id) {
+                            Some(ref mut user) => {
+                                user.fuzz_location = enabled;
+                                if enabled && user.located() {
+                                    let (lat, lon) = geo::snap(user.lat, user.lon, location_grid_m);
+                                    user.lat = lat;
+                                    user.lon = lon;
+                                    Some((lat, lon))
+                                } else {
+                                    None
+                                }
+                            }
+                            None => continue,
+                        };
+                        users.persist(user_id);
+
+                        if let Some((lat, lon)) = snapped {
+                            subscribe(&users, &servers, user_id);
+                            positions.update(user_id, lat, lon);
+                        }
+                    }
+                    Message::LocationHistory {
+                        user_id, enabled, ..
+                    } => {
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.location_history = enabled;
+                        }
+                        users.persist(user_id);
+
+                        if !enabled {
+                            trail.forget(user_id);
+                        }
+                    }
+                    Message::MyLocationHistory { user_id, since, id } => {
+                        let trail = trail.clone();
+                        let servers = servers.clone();
+                        pool.execute_for(user_id, move || {
+                            let locations = trail
+                                .query(user_id, since)
+                                .into_iter()
+                                .map(|location| LocationPoint {
+                                    lat: location.lat,
+                                    lon: location.lon,
+                                    geometry: Geometry::point(location.lat, location.lon),
+                                    time: location.time,
+                                })
+                                .collect();
+
+                            servers.send_to(id, &JsonMessage::LocationHistory { locations });
+                        });
+                    }
+                    Message::Profile { username, id } => {
+                        let response = match users.get_by_name(&username) {
+                            Some(user) => JsonMessage::Profile {
+                                username: user.name.clone(),
+                                status: user.status.clone(),
+                                online: !servers.find_by_user(user.id).is_empty()
+                                    || shared.iter().any(|shared| shared.is_online(user.id)),
+                                last_seen_minutes: if user.last_seen_visible {
+                                    Some(user.last_seen.elapsed().as_secs() / 60)
+                                } else {
+                                    None
+                                },
+                            },
+                            None => JsonMessage::Error {
+                                reason: "No such user".to_string(),
+                            },
+                        };
+
+                        servers.send_to(id, &response);
+                    }
+                    Message::Settings {
+                        user_id,
+                        update,
+                        id,
+                    } => {
+                        let updated = update.is_some();
+                        let result = match users.get_mut_by_id(user_id) {
+                            Some(ref mut user) => match update {
+                                Some(update) => user.update_settings(update),
+                                None => Ok(()),
+                            }
+                            .map(|_| user.settings.clone()),
+                            None => continue,
+                        };
+
+                        servers.send_to(
+                            id,
+                            &match result {
+                                Ok(settings) => {
+                                    if updated {
+                                        users.persist(user_id);
+                                    }
+                                    JsonMessage::Settings { settings }
+                                }
+                                Err(reason) => JsonMessage::Error {
+                                    reason: reason.to_string(),
+                                },
+                            },
+                        );
+                    }
+                    Message::PublishKey { user_id, key, .. } => {
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.public_key = Some(key);
+                        }
+                        users.persist(user_id);
+                    }
+                    Message::Key { username, id } => {
+                        let response = match users.get_by_name(&username) {
+                            Some(user) => JsonMessage::PublicKey {
+                                username: user.name.clone(),
+                                key: user.public_key.clone(),
+                            },
+                            None => JsonMessage::Error {
+                                reason: "No such user".to_string(),
+                            },
+                        };
+
+                        servers.send_to(id, &response);
+                    }
+                    Message::Encrypted {
+                        user_id,
+                        to,
+                        ciphertext,
+                        id,
+                    } => {
+                        let from = match users.get_by_id(user_id) {
+                            Some(user) => user.name.clone(),
+                            None => continue,
+                        };
+                        let recipient = users.get_by_name(&to).map(|user| user.id);
+
+                        // Nothing is stored, so the recipient has to be connected
+                        let connections = recipient
+                            .map(|recipient| servers.find_by_user(recipient))
+                            .unwrap_or_default();
+
+                        if connections.is_empty() {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::Error {
+                                    reason: if recipient.is_some() {
+                                        "User not online".to_string()
+                                    } else {
+                                        "No such user".to_string()
+                                    },
+                                },
+                            );
+                            continue;
+                        }
+
+                        if let Ok(json) =
+                            serde_json::to_string(&JsonMessage::Encrypted { from, ciphertext })
+                        {
+                            for server in connections {
+                                let _ = server.socket.send(json.clone());
+                            }
+                        }
+                    }
+                    Message::MarkRead {
+                        user_id,
+                        message_id,
+                        ..
+                    } => {
+                        if let Some(ref mut user) = users.get_mut_by_id(user_id) {
+                            user.mark_read(message_id);
+                        }
+                    }
+                    Message::Pin {
+                        id,
+                        message_id,
+                        pinned,
+                        ..
+                    } => {
+                        let status = if pinned {
+                            messages.pin(message_id)
+                        } else {
+                            messages.unpin(message_id)
+                        };
+
+                        servers.send_to(id, &JsonMessage::PinResponse { status });
+                    }
+                    Message::CreatePoll {
+                        user_id,
+                        question,
+                        options,
+                        ..
+                    } => {
+                        let poll_id = polls.create(user_id, question, options);
+                        broadcast_poll(&users, &servers, &polls, poll_id, reach.message_km);
+                    }
+                    Message::Vote {
+                        user_id,
+                        poll_id,
+                        option,
+                        ..
+                    } => {
+                        if polls.vote(poll_id, user_id, option) {
+                            broadcast_poll(&users, &servers, &polls, poll_id, reach.message_km);
+                        }
+                    }
+                    Message::Report {
+                        user_id,
+                        message_id,
+                        reason,
+                        ..
+                    } => {
+                        let reporter = match users.get_by_id(user_id) {
+                            Some(user) => user.name.clone(),
+                            None => continue,
+                        };
+                        let reported = messages
+                            .get(message_id)
+                            .map(|stored| (stored.user_id, stored.message.clone()));
+
+                        if let Some((target_id, message)) = reported {
+                            if let Some(target) = users.get_by_id(target_id) {
+                                reports.add(reporter, target.name.clone(), message, reason);
+                            }
+                        }
+                    }
+                    Message::Reports { resolve, id, .. } => {
+                        if let Some(id) = resolve {
+                            reports.resolve(id);
+                        }
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::Reports {
+                                reports: reports.list(),
+                            },
+                        );
+                    }
+                    Message::Announce { user_id, text, .. } => {
+                        if let Some(payload) = encoder.encode(&JsonMessage::Announcement { text }) {
+                            match reach.announcement_km {
+                                Some(radius) => servers.for_each_in_range(
+                                    &users,
+                                    user_id,
+                                    Some(radius),
+                                    |socket, _| {
+                                        let _ = socket.send_shared(&payload);
+                                    },
+                                ),
+                                None => servers.for_each(|server| {
+                                    if server.user_id.read().is_some() {
+                                        let _ = server.socket.send_shared(&payload);
+                                    }
+                                }),
+                            }
+                        }
+                    }
+                    Message::Ban { username, id, .. } => {
+                        let target = users.get_mut_by_name(&username).map(|mut user| {
+                            user.banned = true;
+                            user.id
+                        });
+
+                        if let Some(target) = target {
+                            users.persist(target);
+                            force_logout(&users, &servers, &sessions, target);
+                        }
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::BanResponse {
+                                status: target.is_some(),
+                            },
+                        );
+                    }
+                    Message::BanAddress {
+                        range, banned, id, ..
+                    } => {
+                        let status = if banned {
+                            addr_bans.ban(&range)
+                        } else {
+                            addr_bans.unban(&range)
+                        };
+
+                        // Connections from a newly banned range are dropped as well
+                        if status && banned {
+                            servers.for_each(|server| {
+                                if let Some(ref addr) = server.addr {
+                                    if addr_bans.is_banned(addr) {
+                                        let _ = server.socket.close(CloseCode::Policy);
+                                    }
+                                }
+                            });
+                        }
+
+                        servers.send_to(id, &JsonMessage::BanAddressResponse { status });
+                    }
+                    Message::Suspend {
+                        username,
+                        suspension,
+                        id,
+                        ..
+                    } => {
+                        let suspended = suspension.is_some();
+                        let target = users.get_mut_by_name(&username).map(|mut user| {
+                            user.suspension = suspension;
+                            user.id
+                        });
+
+                        if let Some(target) = target {
+                            users.persist(target);
+                        }
+                        if let (Some(target), true) = (target, suspended) {
+                            force_logout(&users, &servers, &sessions, target);
+                        }
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::SuspendResponse {
+                                status: target.is_some(),
+                            },
+                        );
+                    }
+                    Message::AuditLog {
+                        username,
+                        limit,
+                        id,
+                        ..
+                    } => {
+                        servers.send_to(
+                            id,
+                            &JsonMessage::AuditLog {
+                                entries: audit.query(username.as_deref(), limit.unwrap_or(100)),
+                            },
+                        );
+                    }
+                    Message::Backup {
+                        path, restore, id, ..
+                    } => {
+                        let result = if restore {
+                            Backup::load(&path, key.as_ref()).and_then(|backup| {
+                                let counts = backup.counts();
+                                backup.restore(&users, &history, &messages).map(|_| counts)
+                            })
+                        } else {
+                            Backup::take(&users, &history).and_then(|backup| {
+                                backup.save(&path, key.as_ref()).map(|_| backup.counts())
+                            })
+                        };
+
+                        servers.send_to(
+                            id,
+                            &match result {
+                                Ok((users, messages)) => {
+                                    JsonMessage::BackupResponse { users, messages }
+                                }
+                                Err(e) => {
+                                    println!("{}: backup of {} failed: {}", i, path, e);
+                                    JsonMessage::Error { reason: e }
+                                }
+                            },
+                        );
+                    }
+                    Message::Stats { id, .. } => {
+                        servers.send_to(
+                            id,
+                            &JsonMessage::Stats {
+                                users: users.len(),
+                                connections: counters.connections(),
+                                messages: messages.len(),
+                                uptime_secs: started.elapsed().as_secs(),
+                                dropped: dropped.load(Ordering::Relaxed),
+                                routed_per_sec: counters.routed_per_sec(),
+                                queues: queues.iter().map(Queue::len).collect(),
+                                auth_failures: counters.auth_failures(),
+                            },
+                        );
+                    }
+                    Message::StatsHistory {
+                        since, limit, id, ..
+                    } => {
+                        let storage = storage.clone();
+                        let servers = servers.clone();
+                        pool.execute(move || {
+                            let limit = limit.unwrap_or(MAX_STATS_LIMIT).min(MAX_STATS_LIMIT);
+                            servers.send_to(
+                                id,
+                                &match storage.stats(since, limit) {
+                                    Ok(stats) => JsonMessage::StatsHistory { stats },
+                                    Err(e) => JsonMessage::Error { reason: e },
+                                },
+                            );
+                        });
+                    }
+                    Message::Heatmap {
+                        user_id,
+                        window_secs,
+                        precision,
+                        id,
+                    } => {
+                        let allowed = users.has_role(user_id, Role::Admin)
+                            || users.get_by_id(user_id).is_some_and(|user| user.bot);
+
+                        servers.send_to(
+                            id,
+                            &if allowed {
+                                JsonMessage::Heatmap {
+                                    cells: heatmap.query(window_secs, precision),
+                                }
+                            } else {
+                                JsonMessage::PermissionDenied {
+                                    required: Role::Admin,
+                                }
+                            },
+                        );
+                    }
+                    Message::QueryBox {
+                        user_id,
+                        min_lat,
+                        min_lon,
+                        max_lat,
+                        max_lon,
+                        id,
+                    } => {
+                        let moderator = users.has_role(user_id, Role::Moderator);
+                        if !moderator && !users.get_by_id(user_id).is_some_and(|user| user.bot) {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::PermissionDenied {
+                                    required: Role::Moderator,
+                                },
+                            );
+                            continue;
+                        }
+
+                        if !geo::valid(min_lat, min_lon)
+                            || !geo::valid(max_lat, max_lon)
+                            || min_lat > max_lat
+                        {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::Error {
+                                    reason: "Invalid box".to_string(),
+                                },
+                            );
+                            continue;
+                        }
+
+                        let (mut guests, mut bots, mut usernames) = (0, 0, Vec::new());
+                        for other in positions.within_box(min_lat, min_lon, max_lat, max_lon) {
+                            if !users.located_recently(other)
+                                || servers.find_by_user(other).is_empty()
+                            {
+                                continue;
+                            }
+                            if let Some(other) = users.get_by_id(other) {
+                                guests += other.guest as usize;
+                                bots += other.bot as usize;
+                                usernames.push(other.name.clone());
+                            }
+                        }
+
+                        servers.send_to(
+                            id,
+                            &JsonMessage::BoxCounts {
+                                users: usernames.len(),
+                                guests,
+                                bots,
+                                usernames: if moderator { Some(usernames) } else { None },
+                            },
+                        );
+                    }
+                    Message::UnreadCounts { user_id, id } => {
+                        if let Some(user) = &users.get_by_id(user_id) {
+                            servers.send_to(
+                                id,
+                                &JsonMessage::UnreadCounts {
+                                    count: user.unread.len(),
+                                    last_read: user.last_read,
+                                },
+                            );
+                        }
+                    }
+                    Message::History {
+                        user_id,
+                        before,
+                        search,
+                        limit,
+                        id,
+                    } => {
+                        let area = match users.get_by_id(user_id) {
+                            Some(user) => user.area(),
+                            None => continue,
+                        };
+
+                        // The query goes to storage
+                        let history = history.clone();
+                        let servers = servers.clone();
+                        pool.execute(move || {
+                            let messages = history
+                                .query(&HistoryQuery {
+                                    area,
+                                    before,
+                                    search,
+                                    limit: limit
+                                        .unwrap_or(MAX_HISTORY_LIMIT)
+                                        .min(MAX_HISTORY_LIMIT),
+                                })
+                                .into_iter()
+                                .map(|message| ChatMessage {
+                                    id: message.id,
+                                    username: message.username,
+                                    guest: message.guest,
+                                    bot: message.bot,
+                                    msg: message.msg,
+                                })
+                                .collect();
+
+                            servers.send_to(id, &JsonMessage::History { messages });
+                        });
+                    }
+                    Message::DataExport { id, user_id } => {
+                        let data = match users.get_by_id(user_id) {
+                            Some(user) => DataExport {
+                                username: user.name.clone(),
+                                email: user.email.clone(),
+                                verified: user.verified,
+                                role: user.role,
+                                status: user.status.clone(),
+                                guest: user.guest,
+                                bot: user.bot,
+                                lat: user.lat,
+                                lon: user.lon,
+                                last_seen_visible: user.last_seen_visible,
+                                location_history: user.location_history,
+                                fuzz_location: user.fuzz_location,
+                                radius_km: user.radius(),
+                                public_key: user.public_key.clone(),
+                                suspension: user.suspension.clone(),
+                                settings: user.settings.clone(),
+                                messages: messages.by_user(user_id),
+                                sessions: servers
+                                    .find_by_user(user_id)
+                                    .into_iter()
+                                    .map(|server| SessionInfo {
+                                        id: server.id,
+                                        connected_at: server.connected_at,
+                                        user_agent: server.user_agent,
+                                        addr: server.addr,
+                                        current: server.id == id,
+                                    })
+                                    .collect(),
+                                audit: audit.query(Some(&user.name), MAX_QUERY_LIMIT),
+                            },
+                            None => continue,
+                        };
+
+                        servers.send_to(id, &JsonMessage::DataExport { data });
+                    }
+                }
+            } else {
+                thread::yield_now();
+            }
+        }));
+    }
+
+    // Warns connections whose session is about to lapse, then logs them out.
+    // Also tells other instances who is online here, and forgets expired
+    // sessions and old login failures.
+    threads.push(thread::spawn({
+        let servers = servers.clone();
+        let sessions = sessions.clone();
+        let login_attempts = login_attempts.clone();
+        let delayed = delayed.clone();
+        let quiesce = quiesce.clone();
+
+        move || loop {
+            thread::sleep(SESSION_SWEEP_INTERVAL);
+            let _running = quiesce.read();
+            sessions.sweep();
+            login_attempts.sweep();
+
+            let mut lapsing = Vec::new();
+            let mut online = Vec::new();
+            servers.for_each(|server| {
+                if let Some(user_id) = *server.user_id.read() {
+                    online.push(user_id);
+
+                    match sessions.expiry(server.id) {
+                        Expiry::Valid => (),
+                        expiry => lapsing.push((server.id, user_id, expiry)),
+                    }
+                }
+            });
+
+            if let Some(ref shared) = shared {
+                shared.heartbeat(&online);
+            }
+
+            for (id, user_id, expiry) in lapsing {
+                match expiry {
+                    Expiry::Expiring(expires_in) => servers.send_to(
+                        id,
+                        &JsonMessage::SessionExpiring {
+                            expires_in: expires_in.as_secs(),
+                        },
+                    ),
+                    Expiry::Expired => delayed.send(Message::SignOut {
+                        id,
+                        user_id,
+                        expired: true,
+                    }),
+                    Expiry::Valid => (),
+                }
+            }
+        }
+    }));
+
+    let router_servers = servers.clone();
+    threads.push(thread::spawn(move || console::run(users, resets, api_keys)));
+
+    threads.push(thread::spawn(move || {
+        while let Ok(mut msg) = rx.recv() {
+            // New connections get their id here, before the worker is picked
+            if let Message::Open { ref mut server, .. } = msg {
+                server.id = router_servers.get_next_id();
+            }
+
+            if worker_txs[msg.id() % worker_txs.len()].send(msg) {
+                counters.routed();
+            }
+        }
+    }));
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+// Pins the thread to the core when there is one
+fn spawn_on<F>(core: Option<CoreId>, f: F) -> thread::JoinHandle<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::spawn(move || {
+        if let Some(core) = core {
+            if !core_affinity::set_for_current(core) {
+                println!("Failed to pin a thread to core {}", core.id);
+            }
+        }
+
+        f()
+    })
+}
+
+// Online users other than the user who are in range of them
+fn online_in_range(
+    users: &Users,
+    servers: &Servers,
+    positions: &Positions,
+    user_id: usize,
+    radius: Option<f32>,
+) -> HashSet<usize> {
+    let (lat, lon, reach) = match users.get_by_id(user_id) {
+        Some(user) => (user.lat, user.lon, user.reach(radius)),
+        None => return HashSet::new(),
+    };
+
+    positions
+        .within(lat, lon, reach)
+        .into_iter()
+        .filter(|&other| {
+            other != user_id
+                && users.in_range(user_id, other, radius)
+                && !servers.find_by_user(other).is_empty()
+        })
+        .collect()
+}
+
+// Online users in range of any point of the user's path in route mode, whose
+// radii are compared with the distance to the nearest of them
+fn along_route(
+    users: &Users,
+    servers: &Servers,
+    positions: &Positions,
+    user_id: usize,
+    radius: Option<f32>,
+) -> HashSet<usize> {
+    let (route, reach) = match users.get_by_id(user_id) {
+        Some(ref user) if user.route_mode => (user.route(), user.reach(radius)),
+        _ => return HashSet::new(),
+    };
+
+    let mut found = HashSet::new();
+    for (lat, lon) in route {
+        for other in positions.within(lat, lon, reach) {
+            if other == user_id || found.contains(&other) || !users.located_recently(other) {
+                continue;
+            }
+
+            let close = users.get_by_id(other).is_some_and(|other| {
+                server::distance(lat, lon, other.lat, other.lon) < other.radius()
+            });
+            if close && !servers.find_by_user(other).is_empty() {
+                found.insert(other);
+            }
+        }
+    }
+
+    found
+}
+
+// Tells both users that they came into or went out of range of each other
+fn notify_range(users: &Users, servers: &Servers, user_id: usize, other: usize, in_range: bool) {
+    for &(to, about) in &[(user_id, other), (other, user_id)] {
+        let msg = match users.get_by_id(about) {
+            Some(ref user) if in_range => JsonMessage::InRange {
+                username: user.name.clone(),
+                guest: user.guest,
+                bot: user.bot,
+            },
+            Some(ref user) => JsonMessage::OutOfRange {
+                username: user.name.clone(),
+            },
+            None => continue,
+        };
+
+        if let Ok(json) = serde_json::to_string(&msg) {
+            let payload = Payload::from(json);
+            for server in servers.find_by_user(to) {
+                let _ = server.socket.send_event(&payload);
+            }
+        }
+    }
+}
+
+fn send_to_fence(servers: &Servers, fences: &Fences, fence_id: usize, msg: &JsonMessage) {
+    if let Ok(json) = serde_json::to_string(msg) {
+        let payload = Payload::from(json);
+        for user_id in fences.members(fence_id) {
+            for server in servers.find_by_user(user_id) {
+                let _ = server.socket.send_shared(&payload);
+            }
+        }
+    }
+}
+
+fn broadcast_poll(
+    users: &Users,
+    servers: &Servers,
+    polls: &Polls,
+    poll_id: usize,
+    radius: Option<f32>,
+) {
+    let (user_id, json) = match polls.get(poll_id) {
+        Some(poll) => (
+            poll.user_id,
+            serde_json::to_string(&JsonMessage::Poll {
+                id: poll.id,
+                question: poll.question.clone(),
+                options: poll.options.clone(),
+                tally: poll.tally(),
+            }),
+        ),
+        None => return,
+    };
+
+    if let Ok(json) = json {
+        let payload = Payload::from(json);
+        servers.for_each_in_range(users, user_id, radius, |socket, _| {
+            let _ = socket.send_event(&payload);
+        });
+    }
+}
+
+// Tells the admins and, when configured, the users around a crowd about it
+fn report_hotspot(
+    users: &Users,
+    servers: &Servers,
+    positions: &Positions,
+    config: &HotspotConfig,
+    cell: &str,
+    count: usize,
+) {
+    let (lat, lon) = match geo::geohash_center(cell) {
+        Some(center) => center,
+        None => return,
+    };
+    let json = match serde_json::to_string(&JsonMessage::Hotspot {
+        geohash: cell.to_string(),
+        lat,
+        lon,
+        geometry: Geometry::point(lat, lon),
+        users: count,
+    }) {
+        Ok(json) => Payload::from(json),
+        Err(_) => return,
+    };
+
+    let admin = |user_id: usize| config.notify_admins && users.has_role(user_id, Role::Admin);
+    servers.for_each(|server| {
+        if server.user_id.read().is_some_and(admin) {
+            let _ = server.socket.send_event(&json);
+        }
+    });
+
+    if let Some(km) = config.notify_km {
+        for other in positions.within(lat, lon, km) {
+            if admin(other) || !users.located_recently(other) {
+                continue;
+            }
+            for server in servers.find_by_user(other) {
+                let _ = server.socket.send_event(&json);
+            }
+        }
+    }
+}
+
+// Lists the user's connections where messages to the area they are in and to
+// the global room look for them
+fn subscribe(users: &Users, servers: &Servers, user_id: usize) {
+    let (area, global) = match users.get_by_id(user_id) {
+        Some(user) => (server::area(user.lat, user.lon), user.global),
+        None => return,
+    };
+
+    servers.subscribe(user_id, area, global);
+}
+
+// Runs the job on the auth threads once the writes already queued for the
+// user are done, as logins read the user back from storage
+//
+// The pool doesn't wait for room on the auth threads, nor they on the router.
+// The router waits on the workers and the workers on the pool, so waiting at
+// every step could go round in a circle forever. The client is told the
+// server is busy instead.
+fn after_writes<F: FnOnce() + Send + 'static>(
+    pool: &Pool,
+    auth: &Pool,
+    servers: Servers,
+    id: usize,
+    key: usize,
+    job: F,
+) {
+    let auth = auth.clone();
+    pool.execute_for(key, move || {
+        if !auth.try_execute_for(key, job) {
+            busy(&servers, id);
+        }
+    });
+}
+
+fn busy(servers: &Servers, id: usize) {
+    servers.send_to(
+        id,
+        &JsonMessage::Error {
+            reason: "Server busy".to_string(),
+        },
+    );
+}
+
+// The user linked to an external identity, created on its first login. Never
+// an existing account, which would let the identity take it over.
+fn external_user(
+    users: &Users,
+    oauth: &OAuth,
+    provider: &str,
+    subject: String,
+    name: Option<String>,
+) -> Option<usize> {
+    if let Some(user_id) = oauth.linked(provider, &subject) {
+        return Some(user_id);
+    }
+
+    // Fall back to a name that can't clash with a registered user
+    let username = match name {
+        Some(name) if valid_username(&name) && !users.contains_username(&name) => name,
+        _ => format!("{}:{}", provider, subject),
+    };
+
+    // Someone has the name already, e.g. from before such names were reserved
+    if users.contains_username(&username) {
+        return None;
+    }
+
+    let user_id = users.add_with_hash(&username, String::new());
+    oauth.link(provider, subject, user_id);
+    Some(user_id)
+}
+
+// Guests don't outlive the connection they were created on
+fn release_guest(
+    users: &Users,
+    servers: &Servers,
+    positions: &Positions,
+    fences: &Fences,
+    id: usize,
+) {
+    if let Some(user_id) = servers.get(id).and_then(|server| *server.user_id.read()) {
+        if users.get_by_id(user_id).is_some_and(|user| user.guest) {
+            positions.remove(user_id);
+            fences.forget(user_id);
+        }
+        users.remove_guest(user_id);
+    }
+}
+
+// Ends every session of a barred user and closes their connections with the reason
+fn force_logout(users: &Users, servers: &Servers, sessions: &Sessions, user_id: usize) {
+    sessions.end_all(user_id);
+
+    let json = users
+        .restriction(user_id)
+        .and_then(|response| serde_json::to_string(&response).ok());
+
+    for server in servers.find_by_user(user_id) {
+        servers.set_user(server.id, None);
+
+        if let Some(ref json) = json {
+            let _ = server.socket.send(json.clone());
+        }
+        let _ = server.socket.close(CloseCode::Policy);
+    }
+}
+
+fn record(audit: &Audit, counters: &Counters, servers: &Servers, id: usize, mut entry: Entry) {
+    if entry.event == Event::Login && !entry.success {
+        counters.auth_failed();
+    }
+
+    entry.connection = id;
+    entry.addr = servers.get(id).and_then(|server| server.addr);
+    audit.record(entry);
+}
+
+fn send_ack(servers: &Servers, id: usize, message_id: usize, client_id: Option<String>) {
+    if let Some(client_id) = client_id {
+        servers.send_to(
+            id,
+            &JsonMessage::MessageAck {
+                id: message_id,
+                client_id,
+            },
+        );
     }
 }
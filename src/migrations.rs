@@ -0,0 +1,115 @@
+// Schema changes for the SQL backends, applied in order on startup. A
+// migration is never edited once released, changes go into a new one.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+// The first migrations only create what is missing, so databases made before
+// migrations were tracked are picked up as they are
+pub const SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/sqlite/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "messages",
+        sql: include_str!("../migrations/sqlite/0002_messages.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "messages_time",
+        sql: include_str!("../migrations/sqlite/0003_messages_time.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "settings",
+        sql: include_str!("../migrations/sqlite/0004_settings.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "locations",
+        sql: include_str!("../migrations/sqlite/0005_locations.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "stats",
+        sql: include_str!("../migrations/sqlite/0006_stats.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "radius",
+        sql: include_str!("../migrations/sqlite/0007_radius.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "fuzz_location",
+        sql: include_str!("../migrations/sqlite/0008_fuzz_location.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "sealed_locations",
+        sql: include_str!("../migrations/sqlite/0009_sealed_locations.sql"),
+    },
+];
+
+pub const POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial",
+        sql: include_str!("../migrations/postgres/0001_initial.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "messages",
+        sql: include_str!("../migrations/postgres/0002_messages.sql"),
+    },
+    Migration {
+        version: 3,
+        name: "messages_time",
+        sql: include_str!("../migrations/postgres/0003_messages_time.sql"),
+    },
+    Migration {
+        version: 4,
+        name: "settings",
+        sql: include_str!("../migrations/postgres/0004_settings.sql"),
+    },
+    Migration {
+        version: 5,
+        name: "locations",
+        sql: include_str!("../migrations/postgres/0005_locations.sql"),
+    },
+    Migration {
+        version: 6,
+        name: "stats",
+        sql: include_str!("../migrations/postgres/0006_stats.sql"),
+    },
+    Migration {
+        version: 7,
+        name: "radius",
+        sql: include_str!("../migrations/postgres/0007_radius.sql"),
+    },
+    Migration {
+        version: 8,
+        name: "fuzz_location",
+        sql: include_str!("../migrations/postgres/0008_fuzz_location.sql"),
+    },
+    Migration {
+        version: 9,
+        name: "sealed_locations",
+        sql: include_str!("../migrations/postgres/0009_sealed_locations.sql"),
+    },
+];
+
+// Migrations newer than the applied version
+pub fn pending(migrations: &'static [Migration], applied: i64) -> &'static [Migration] {
+    let start = migrations
+        .iter()
+        .position(|migration| migration.version > applied)
+        .unwrap_or(migrations.len());
+
+    &migrations[start..]
+}
@@ -0,0 +1,82 @@
+use chashmap::CHashMap;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize)]
+pub struct ProviderConfig {
+    // Queried with the client's access token, e.g.
+    // https://openidconnect.googleapis.com/v1/userinfo
+    pub userinfo_url: String,
+    // Claim used as the username of newly created accounts
+    #[serde(default = "default_name_claim")]
+    pub name_claim: String,
+}
+
+fn default_name_claim() -> String {
+    "email".to_string()
+}
+
+pub struct Identity {
+    pub subject: String,
+    pub name: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct OAuth {
+    providers: Arc<HashMap<String, ProviderConfig>>,
+    // (provider, subject) -> user id
+    links: Arc<CHashMap<(String, String), usize>>,
+}
+
+impl OAuth {
+    pub fn new(providers: HashMap<String, ProviderConfig>) -> OAuth {
+        OAuth {
+            providers: Arc::new(providers),
+            links: Arc::new(CHashMap::new()),
+        }
+    }
+
+    // Blocks on the provider for up to the timeout, so keep it off the workers
+    pub fn verify(&self, provider: &str, token: &str) -> Option<Identity> {
+        let config = self.providers.get(provider)?;
+
+        let claims: Value = ureq::get(&config.userinfo_url)
+            .set("Authorization", &format!("Bearer {}", token))
+            .timeout(TIMEOUT)
+            .call()
+            .ok()?
+            .into_json()
+            .ok()?;
+
+        let subject = claims.get("sub")?.as_str()?.to_string();
+        let name = claims
+            .get(&config.name_claim)
+            .and_then(Value::as_str)
+            .map(str::to_string);
+
+        Some(Identity { subject, name })
+    }
+
+    pub fn linked(&self, provider: &str, subject: &str) -> Option<usize> {
+        self.links
+            .get(&(provider.to_string(), subject.to_string()))
+            .map(|user_id| *user_id)
+    }
+
+    pub fn link(&self, provider: &str, subject: String, user_id: usize) {
+        self.links.insert((provider.to_string(), subject), user_id);
+    }
+
+    pub fn links(&self) -> Vec<((String, String), usize)> {
+        (*self.links).clone().into_iter().collect()
+    }
+
+    pub fn restore(&self, links: Vec<((String, String), usize)>) {
+        for (key, user_id) in links {
+            self.links.insert(key, user_id);
+        }
+    }
+}
@@ -0,0 +1,39 @@
+use crate::socket::Request;
+use serde::Deserialize;
+
+// Empty lists accept anything. Clients that don't send an Origin header
+// (native apps, scripts) aren't browsers and can't be hijacked cross-site.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct OriginPolicy {
+    pub allowed_origins: Vec<String>,
+    pub allowed_hosts: Vec<String>,
+}
+
+impl OriginPolicy {
+    pub fn allows(&self, request: &Request) -> bool {
+        let headers = request.headers();
+        let origin_ok = match headers
+            .get("Origin")
+            .or_else(|| headers.get("Sec-WebSocket-Origin"))
+        {
+            Some(origin) => origin
+                .to_str()
+                .is_ok_and(|origin| allowed(&self.allowed_origins, origin)),
+            None => true,
+        };
+
+        let host_ok = self.allowed_hosts.is_empty()
+            || headers
+                .get("Host")
+                .and_then(|host| host.to_str().ok())
+                .filter(|host| allowed(&self.allowed_hosts, host))
+                .is_some();
+
+        origin_ok && host_ok
+    }
+}
+
+fn allowed(list: &[String], value: &str) -> bool {
+    list.is_empty() || list.iter().any(|v| v.eq_ignore_ascii_case(value.trim()))
+}
@@ -0,0 +1,172 @@
+use argon2::{self, Variant};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+
+const ARGON2_MEMORY_KB: u32 = 19_456;
+const ARGON2_PASSES: u32 = 2;
+const MIN_LENGTH: usize = 8;
+const MIN_ENTROPY: f64 = 30.0;
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Pbkdf2,
+    Argon2id,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PasswordError {
+    TooShort,
+    TooWeak,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct Policy {
+    pub min_length: usize,
+    // Estimated bits of entropy, see `entropy`
+    pub min_entropy: f64,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            min_length: MIN_LENGTH,
+            min_entropy: MIN_ENTROPY,
+        }
+    }
+}
+
+impl Policy {
+    pub fn check(&self, password: &str) -> Result<(), PasswordError> {
+        if password.chars().count() < self.min_length {
+            Err(PasswordError::TooShort)
+        } else if entropy(password) < self.min_entropy {
+            Err(PasswordError::TooWeak)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Length times the bits needed per character for the character classes used
+fn entropy(password: &str) -> f64 {
+    let mut pool = 0;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password
+        .chars()
+        .any(|c| c.is_ascii_punctuation() || c == ' ')
+    {
+        pool += 33;
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+
+    if pool == 0 {
+        return 0.0;
+    }
+
+    password.chars().count() as f64 * f64::from(pool).log2()
+}
+
+#[derive(Clone)]
+pub struct Hasher {
+    algorithm: Algorithm,
+    pbkdf2_iterations: u32,
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm, pbkdf2_iterations: u32) -> Self {
+        Hasher {
+            algorithm,
+            pbkdf2_iterations,
+        }
+    }
+
+    pub fn hash(&self, password: &str) -> String {
+        match self.algorithm {
+            Algorithm::Pbkdf2 => pbkdf2::pbkdf2_simple(password, self.pbkdf2_iterations).unwrap(),
+            Algorithm::Argon2id => {
+                let salt: [u8; 16] = thread_rng().gen();
+                let config = argon2::Config {
+                    variant: Variant::Argon2id,
+                    mem_cost: ARGON2_MEMORY_KB,
+                    time_cost: ARGON2_PASSES,
+                    ..argon2::Config::default()
+                };
+
+                argon2::hash_encoded(password.as_bytes(), &salt, &config).unwrap()
+            }
+        }
+    }
+
+    // Hashes weaker than what would be produced now. PBKDF2 hashes are upgraded
+    // to Argon2id when that is configured, but Argon2 hashes are never downgraded.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        if hash.starts_with("$argon2") {
+            return match argon2_params(hash) {
+                Some((variant, memory, passes)) => {
+                    variant != "argon2id" || memory < ARGON2_MEMORY_KB || passes < ARGON2_PASSES
+                }
+                None => false,
+            };
+        }
+
+        match pbkdf2_iterations(hash) {
+            Some(_) if self.algorithm == Algorithm::Argon2id => true,
+            Some(iterations) => iterations < self.pbkdf2_iterations,
+            None => false,
+        }
+    }
+}
+
+// Verifies against whichever algorithm produced the hash
+pub fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        argon2::verify_encoded(hash, password.as_bytes()).unwrap_or(false)
+    } else {
+        pbkdf2::pbkdf2_check(password, hash).is_ok()
+    }
+}
+
+// Whether verify understands the hash, for hashes from elsewhere
+pub fn is_hash(hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        argon2_params(hash).is_some()
+    } else {
+        pbkdf2_iterations(hash).is_some()
+    }
+}
+
+// Iteration count stored in a pbkdf2_simple hash: $rpbkdf2$0$<base64(c)>$<salt>$<hash>$
+fn pbkdf2_iterations(hash: &str) -> Option<u32> {
+    let encoded = hash.split('$').nth(3)?;
+    let bytes = base64::decode(encoded).ok()?;
+    if bytes.len() != 4 {
+        return None;
+    }
+
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Variant, memory and passes of an encoded Argon2 hash:
+// $argon2id$v=19$m=19456,t=2,p=1$<salt>$<hash>
+fn argon2_params(hash: &str) -> Option<(&str, u32, u32)> {
+    let mut parts = hash.split('$').skip(1);
+    let variant = parts.next()?;
+    let mut params = parts.find(|part| part.starts_with("m="))?.split(',');
+
+    let memory = params.next()?.trim_start_matches("m=").parse().ok()?;
+    let passes = params.next()?.trim_start_matches("t=").parse().ok()?;
+
+    Some((variant, memory, passes))
+}
@@ -0,0 +1,432 @@
+use parking_lot::{Mutex, MutexGuard};
+use postgres::{types::Json, Client, NoTls, Row, Statement};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::migrations::{self, Migration};
+use crate::server::unix_time;
+use crate::storage::{
+    HistoryQuery, LocationRecord, MessageRecord, StatsRecord, Storage, UserRecord,
+};
+
+const COLUMNS: &str = "id, name, password, role, status, email, verified, bot, \
+                       public_key, banned, suspension, last_seen_visible, settings, \
+                       location_history, radius_km, fuzz_location";
+const MESSAGE_COLUMNS: &str = "id, user_id, username, guest, bot, area_lat, area_lon, time, msg";
+
+struct Connection {
+    client: Client,
+    // Prepared once per connection for the login and lookup paths
+    find_user: Statement,
+    save_user: Statement,
+    save_message: Statement,
+    messages: Statement,
+}
+
+impl Connection {
+    fn open(url: &str) -> Result<Connection, postgres::Error> {
+        let mut client = Client::connect(url, NoTls)?;
+        let find_user =
+            client.prepare(&format!("SELECT {} FROM users WHERE name = $1", COLUMNS))?;
+        let save_user = client.prepare(&format!(
+            "INSERT INTO users ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16)
+            ON CONFLICT (id) DO UPDATE SET
+                password = EXCLUDED.password,
+                role = EXCLUDED.role,
+                status = EXCLUDED.status,
+                email = EXCLUDED.email,
+                verified = EXCLUDED.verified,
+                bot = EXCLUDED.bot,
+                public_key = EXCLUDED.public_key,
+                banned = EXCLUDED.banned,
+                suspension = EXCLUDED.suspension,
+                last_seen_visible = EXCLUDED.last_seen_visible,
+                settings = EXCLUDED.settings,
+                location_history = EXCLUDED.location_history,
+                radius_km = EXCLUDED.radius_km,
+                fuzz_location = EXCLUDED.fuzz_location",
+            COLUMNS
+        ))?;
+        let save_message = client.prepare(&format!(
+            "INSERT INTO messages ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (id) DO NOTHING",
+            MESSAGE_COLUMNS
+        ))?;
+        let messages = client.prepare(&format!(
+            "SELECT {} FROM messages
+            WHERE area_lat = $1 AND area_lon = $2
+                AND ($3::BIGINT IS NULL OR id < $3)
+                AND ($4::TEXT IS NULL OR strpos(msg, $4) > 0)
+            ORDER BY id DESC LIMIT $5",
+            MESSAGE_COLUMNS
+        ))?;
+
+        Ok(Connection {
+            client,
+            find_user,
+            save_user,
+            save_message,
+            messages,
+        })
+    }
+}
+
+// A fixed pool of connections, each used by one thread at a time
+pub struct Postgres {
+    connections: Arc<Vec<Mutex<Connection>>>,
+    next: Arc<AtomicUsize>,
+}
+
+impl Postgres {
+    pub fn connect(url: &str, pool_size: usize) -> Result<Postgres, postgres::Error> {
+        migrate(&mut Client::connect(url, NoTls)?)?;
+
+        let connections = (0..pool_size.max(1))
+            .map(|_| Connection::open(url).map(Mutex::new))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Postgres {
+            connections: Arc::new(connections),
+            next: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    // An idle connection if there is one, otherwise waits on them in turn
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        for connection in self.connections.iter() {
+            if let Some(connection) = connection.try_lock() {
+                return connection;
+            }
+        }
+
+        let next = self.next.fetch_add(1, Ordering::Relaxed) % self.connections.len();
+        self.connections[next].lock()
+    }
+}
+
+impl Storage for Postgres {
+    fn load_users(&self) -> Result<Vec<UserRecord>, String> {
+        let mut connection = self.connection();
+        connection
+            .client
+            .query(format!("SELECT {} FROM users", COLUMNS).as_str(), &[])
+            .and_then(|rows| rows.iter().map(record).collect())
+            .map_err(|e| e.to_string())
+    }
+
+    fn find_user(&self, name: &str) -> Result<Option<UserRecord>, String> {
+        let connection = &mut *self.connection();
+        match connection.client.query_opt(&connection.find_user, &[&name]) {
+            Ok(Some(ref row)) => record(row).map(Some).map_err(|e| e.to_string()),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn save_user(&self, user: &UserRecord) -> Result<(), String> {
+        let connection = &mut *self.connection();
+        connection
+            .client
+            .execute(
+                &connection.save_user,
+                &[
+                    &(user.id as i64),
+                    &user.name,
+                    &user.password,
+                    &Json(&user.role),
+                    &Json(&user.status),
+                    &user.email,
+                    &user.verified,
+                    &user.bot,
+                    &user.public_key,
+                    &user.banned,
+                    &Json(&user.suspension),
+                    &user.last_seen_visible,
+                    &Json(&user.settings),
+                    &user.location_history,
+                    &user.radius_km,
+                    &user.fuzz_location,
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    fn next_id(&self) -> Result<Option<usize>, String> {
+        self.connection()
+            .client
+            .query_one("SELECT nextval('user_ids')", &[])
+            .and_then(|row| row.try_get::<_, i64>(0))
+            .map(|id| Some(id as usize))
+            .map_err(|e| e.to_string())
+    }
+
+    // One transaction per batch
+    fn save_messages(&self, messages: &[MessageRecord]) -> Result<(), String> {
+        let connection = &mut *self.connection();
+        let mut transaction = connection.client.transaction().map_err(|e| e.to_string())?;
+
+        for message in messages {
+            transaction
+                .execute(
+                    &connection.save_message,
+                    &[
+                        &(message.id as i64),
+                        &(message.user_id as i64),
+                        &message.username,
+                        &message.guest,
+                        &message.bot,
+                        &message.area.0,
+                        &message.area.1,
+                        &(message.time as i64),
+                        &message.msg,
+                    ],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    fn messages(&self, query: &HistoryQuery) -> Result<Vec<MessageRecord>, String> {
+        let connection = &mut *self.connection();
+        connection
+            .client
+            .query(
+                &connection.messages,
+                &[
+                    &query.area.0,
+                    &query.area.1,
+                    &query.before.map(|before| before as i64),
+                    &query.search,
+                    &(query.limit as i64),
+                ],
+            )
+            .and_then(|rows| rows.iter().map(message_record).collect())
+            .map_err(|e| e.to_string())
+    }
+
+    fn last_message_id(&self) -> Result<Option<usize>, String> {
+        self.connection()
+            .client
+            .query_one("SELECT MAX(id) FROM messages", &[])
+            .and_then(|row| row.try_get::<_, Option<i64>>(0))
+            .map(|id| id.map(|id| id as usize))
+            .map_err(|e| e.to_string())
+    }
+
+    fn export_messages(&self) -> Result<Vec<MessageRecord>, String> {
+        self.connection()
+            .client
+            .query(
+                format!("SELECT {} FROM messages ORDER BY id", MESSAGE_COLUMNS).as_str(),
+                &[],
+            )
+            .and_then(|rows| rows.iter().map(message_record).collect())
+            .map_err(|e| e.to_string())
+    }
+
+    fn prune_messages(
+        &self,
+        sent_before: Option<u64>,
+        keep_per_area: Option<usize>,
+    ) -> Result<usize, String> {
+        let client = &mut self.connection().client;
+        let mut deleted = 0;
+
+        if let Some(sent_before) = sent_before {
+            deleted += client
+                .execute(
+                    "DELETE FROM messages WHERE time < $1",
+                    &[&(sent_before as i64)],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        if let Some(keep) = keep_per_area {
+            deleted += client
+                .execute(
+                    "DELETE FROM messages WHERE id IN (
+                        SELECT id FROM (
+                            SELECT id, ROW_NUMBER() OVER (
+                                PARTITION BY area_lat, area_lon ORDER BY id DESC
+                            ) AS n FROM messages
+                        ) ranked WHERE n > $1
+                    )",
+                    &[&(keep as i64)],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(deleted as usize)
+    }
+
+    fn save_location(&self, location: &LocationRecord) -> Result<(), String> {
+        self.connection()
+            .client
+            .execute(
+                "INSERT INTO locations (user_id, lat, lon, time, sealed) VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &(location.user_id as i64),
+                    &location.lat,
+                    &location.lon,
+                    &(location.time as i64),
+                    &location.sealed,
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn locations(
+        &self,
+        user_id: usize,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<LocationRecord>, String> {
+        self.connection()
+            .client
+            .query(
+                "SELECT user_id, lat, lon, time, sealed FROM locations
+                WHERE user_id = $1 AND time >= $2
+                ORDER BY time LIMIT $3",
+                &[&(user_id as i64), &(since as i64), &(limit as i64)],
+            )
+            .and_then(|rows| {
+                rows.iter()
+                    .map(|row| {
+                        Ok(LocationRecord {
+                            user_id: row.try_get::<_, i64>(0)? as usize,
+                            lat: row.try_get(1)?,
+                            lon: row.try_get(2)?,
+                            time: row.try_get::<_, i64>(3)? as u64,
+                            sealed: row.try_get(4)?,
+                        })
+                    })
+                    .collect()
+            })
+            .map_err(|e| e.to_string())
+    }
+
+    fn delete_locations(&self, user_id: usize) -> Result<(), String> {
+        self.connection()
+            .client
+            .execute(
+                "DELETE FROM locations WHERE user_id = $1",
+                &[&(user_id as i64)],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_stats(&self, stats: &StatsRecord) -> Result<(), String> {
+        self.connection()
+            .client
+            .execute(
+                "INSERT INTO stats (time, period_secs, messages, active_users, areas)
+                VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &(stats.time as i64),
+                    &(stats.period_secs as i64),
+                    &(stats.messages as i64),
+                    &(stats.active_users as i64),
+                    &Json(&stats.areas),
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn stats(&self, since: u64, limit: usize) -> Result<Vec<StatsRecord>, String> {
+        self.connection()
+            .client
+            .query(
+                "SELECT time, period_secs, messages, active_users, areas FROM stats
+                WHERE time >= $1 ORDER BY time LIMIT $2",
+                &[&(since as i64), &(limit as i64)],
+            )
+            .and_then(|rows| {
+                rows.iter()
+                    .map(|row| {
+                        Ok(StatsRecord {
+                            time: row.try_get::<_, i64>(0)? as u64,
+                            period_secs: row.try_get::<_, i64>(1)? as u64,
+                            messages: row.try_get::<_, i64>(2)? as usize,
+                            active_users: row.try_get::<_, i64>(3)? as usize,
+                            areas: row.try_get::<_, Json<_>>(4)?.0,
+                        })
+                    })
+                    .collect()
+            })
+            .map_err(|e| e.to_string())
+    }
+}
+
+// All pending migrations run in one transaction that holds a lock on
+// schema_migrations, so instances starting together apply them only once
+fn migrate(client: &mut Client) -> Result<(), postgres::Error> {
+    client.batch_execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at BIGINT NOT NULL
+        )",
+    )?;
+
+    let mut transaction = client.transaction()?;
+    transaction.batch_execute("LOCK TABLE schema_migrations IN EXCLUSIVE MODE")?;
+    let applied: i64 = transaction
+        .query_one(
+            "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+            &[],
+        )?
+        .try_get(0)?;
+
+    for &Migration { version, name, sql } in migrations::pending(migrations::POSTGRES, applied) {
+        println!("Applying PostgreSQL migration {} {}", version, name);
+
+        transaction.batch_execute(sql)?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES ($1, $2, $3)",
+            &[&version, &name, &(unix_time() as i64)],
+        )?;
+    }
+
+    transaction.commit()
+}
+
+fn message_record(row: &Row) -> Result<MessageRecord, postgres::Error> {
+    Ok(MessageRecord {
+        id: row.try_get::<_, i64>(0)? as usize,
+        user_id: row.try_get::<_, i64>(1)? as usize,
+        username: row.try_get(2)?,
+        guest: row.try_get(3)?,
+        bot: row.try_get(4)?,
+        area: (row.try_get(5)?, row.try_get(6)?),
+        time: row.try_get::<_, i64>(7)? as u64,
+        msg: row.try_get(8)?,
+    })
+}
+
+fn record(row: &Row) -> Result<UserRecord, postgres::Error> {
+    Ok(UserRecord {
+        id: row.try_get::<_, i64>(0)? as usize,
+        name: row.try_get(1)?,
+        password: row.try_get(2)?,
+        role: row.try_get::<_, Json<_>>(3)?.0,
+        status: row.try_get::<_, Json<_>>(4)?.0,
+        email: row.try_get(5)?,
+        verified: row.try_get(6)?,
+        bot: row.try_get(7)?,
+        public_key: row.try_get(8)?,
+        banned: row.try_get(9)?,
+        suspension: row.try_get::<_, Json<_>>(10)?.0,
+        last_seen_visible: row.try_get(11)?,
+        settings: row.try_get::<_, Json<_>>(12)?.0,
+        location_history: row.try_get(13)?,
+        radius_km: row.try_get(14)?,
+        fuzz_location: row.try_get(15)?,
+    })
+}
@@ -0,0 +1,93 @@
+use chashmap::CHashMap;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const POLL_DURATION: Duration = Duration::from_secs(60 * 60);
+const MAX_QUESTION_LENGTH: usize = 300;
+const MAX_OPTION_LENGTH: usize = 100;
+const MAX_OPTIONS: usize = 10;
+
+pub struct Poll {
+    pub id: usize,
+    pub user_id: usize,
+    pub question: String,
+    pub options: Vec<String>,
+    votes: HashMap<usize, usize>,
+    expires: Instant,
+}
+
+impl Poll {
+    pub fn tally(&self) -> Vec<usize> {
+        let mut tally = vec![0; self.options.len()];
+        for &option in self.votes.values() {
+            tally[option] += 1;
+        }
+
+        tally
+    }
+
+    fn expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+}
+
+#[derive(Clone)]
+pub struct Polls {
+    current_id: Arc<AtomicUsize>,
+    polls: Arc<CHashMap<usize, Poll>>,
+}
+
+impl Polls {
+    pub fn new() -> Self {
+        Polls {
+            current_id: Arc::new(AtomicUsize::new(0)),
+            polls: Arc::new(CHashMap::new()),
+        }
+    }
+
+    pub fn valid(question: &str, options: &[String]) -> bool {
+        question.len() <= MAX_QUESTION_LENGTH
+            && options.len() >= 2
+            && options.len() <= MAX_OPTIONS
+            && options
+                .iter()
+                .all(|option| option.len() <= MAX_OPTION_LENGTH)
+    }
+
+    pub fn create(&self, user_id: usize, question: String, options: Vec<String>) -> usize {
+        self.polls.retain(|_, poll| !poll.expired());
+
+        let id = self.current_id.fetch_add(1, Ordering::Relaxed);
+        self.polls.insert(
+            id,
+            Poll {
+                id,
+                user_id,
+                question,
+                options,
+                votes: HashMap::new(),
+                expires: Instant::now() + POLL_DURATION,
+            },
+        );
+
+        id
+    }
+
+    pub fn vote(&self, id: usize, user_id: usize, option: usize) -> bool {
+        match self.polls.get_mut(&id) {
+            Some(ref mut poll) if !poll.expired() && option < poll.options.len() => {
+                poll.votes.insert(user_id, option);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<chashmap::ReadGuard<'_, usize, Poll>> {
+        self.polls.get(&id)
+    }
+}
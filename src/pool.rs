@@ -0,0 +1,141 @@
+use crossbeam::channel::{bounded, Sender};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Past this many jobs on a thread, queueing another waits for room
+const MAX_QUEUED_JOBS: usize = 10_000;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// Threads for work that waits on storage or password hashing, so the workers
+// stay free to fan out messages
+#[derive(Clone)]
+pub struct Pool {
+    queues: Arc<Vec<Sender<Job>>>,
+    next: Arc<AtomicUsize>,
+}
+
+// Holds every thread of a pool until dropped
+pub struct Paused {
+    _release: Sender<()>,
+}
+
+impl Pool {
+    pub fn new(threads: usize) -> Pool {
+        let queues = (0..threads.max(1))
+            .map(|_| {
+                let (tx, rx) = bounded::<Job>(MAX_QUEUED_JOBS);
+                thread::spawn(move || {
+                    while let Ok(job) = rx.recv() {
+                        job();
+                    }
+                });
+                tx
+            })
+            .collect();
+
+        Pool {
+            queues: Arc::new(queues),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn execute<F: FnOnce() + Send + 'static>(&self, job: F) {
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        self.execute_for(next, job);
+    }
+
+    // Jobs with the same key run in the order they were queued, e.g. writes
+    // of the same user
+    pub fn execute_for<F: FnOnce() + Send + 'static>(&self, key: usize, job: F) {
+        let _ = self.queues[key % self.queues.len()].send(Box::new(job));
+    }
+
+    // Gives up instead of waiting for room, for threads that mustn't wait on
+    // a pool that may be waiting on them. False if the job wasn't queued.
+    pub fn try_execute_for<F: FnOnce() + Send + 'static>(&self, key: usize, job: F) -> bool {
+        self.queues[key % self.queues.len()]
+            .try_send(Box::new(job))
+            .is_ok()
+    }
+
+    // Lets every thread finish the jobs queued so far and then holds it, so
+    // nothing runs until the pool is released. None if that takes longer
+    // than the timeout, or a queue is full.
+    pub fn pause(&self, timeout: Duration) -> Option<Paused> {
+        let deadline = Instant::now() + timeout;
+        let (started_tx, started_rx) = bounded(self.queues.len());
+        let (release_tx, release_rx) = bounded::<()>(0);
+
+        for key in 0..self.queues.len() {
+            let started = started_tx.clone();
+            let release = release_rx.clone();
+            let queued = self.try_execute_for(key, move || {
+                let _ = started.send(());
+                // Nothing is ever sent, it returns once the sender is dropped
+                let _ = release.recv();
+            });
+            if !queued {
+                return None;
+            }
+        }
+
+        for _ in 0..self.queues.len() {
+            started_rx
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .ok()?;
+        }
+
+        Some(Paused {
+            _release: release_tx,
+        })
+    }
+}
+
+// Work on a user is keyed by name, which is all a login knows, so it queues
+// behind earlier writes of that user
+pub fn user_key(name: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish() as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn pause_runs_queued_jobs_and_holds_later_ones() {
+        let pool = Pool::new(2);
+        let (first, later) = (
+            Arc::new(AtomicBool::new(false)),
+            Arc::new(AtomicBool::new(false)),
+        );
+
+        let ran = first.clone();
+        pool.execute_for(0, move || {
+            thread::sleep(Duration::from_millis(20));
+            ran.store(true, Ordering::SeqCst);
+        });
+        let paused = pool.pause(Duration::from_secs(1)).unwrap();
+        assert!(first.load(Ordering::SeqCst));
+
+        let ran = later.clone();
+        pool.execute_for(0, move || ran.store(true, Ordering::SeqCst));
+        thread::sleep(Duration::from_millis(20));
+        assert!(!later.load(Ordering::SeqCst));
+
+        drop(paused);
+        thread::sleep(Duration::from_millis(20));
+        assert!(later.load(Ordering::SeqCst));
+    }
+}
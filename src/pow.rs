@@ -0,0 +1,209 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::server::random_token;
+
+const CHALLENGE_DURATION: Duration = Duration::from_secs(10 * 60);
+// Unsolved challenges a connection can hold at a time
+const MAX_CHALLENGES_PER_CONNECTION: usize = 4;
+// Challenges handed out within CHALLENGE_DURATION, solved or not, so what
+// they take up is bounded
+const MAX_CHALLENGES: usize = 100_000;
+
+// The client searches for a nonce so that sha256(challenge + nonce) starts
+// with at least `difficulty` zero bits
+#[derive(Serialize, Deserialize)]
+pub struct Proof {
+    pub challenge: String,
+    pub nonce: String,
+}
+
+#[derive(Default)]
+struct Outstanding {
+    // The connection each challenge was handed to
+    owners: HashMap<String, usize>,
+    per_connection: HashMap<usize, usize>,
+    // Oldest first, as every challenge lasts as long. Solved challenges stay
+    // in here until their time is up.
+    expiry: VecDeque<(Instant, String)>,
+}
+
+impl Outstanding {
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(expires, _)) = self.expiry.front() {
+            if expires > now {
+                break;
+            }
+            if let Some((_, challenge)) = self.expiry.pop_front() {
+                self.take(&challenge);
+            }
+        }
+    }
+
+    fn take(&mut self, challenge: &str) -> bool {
+        let owner = match self.owners.remove(challenge) {
+            Some(owner) => owner,
+            None => return false,
+        };
+
+        if let Some(count) = self.per_connection.get_mut(&owner) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_connection.remove(&owner);
+            }
+        }
+
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct Challenges {
+    difficulty: u32,
+    outstanding: Arc<Mutex<Outstanding>>,
+}
+
+impl Challenges {
+    pub fn new(difficulty: u32) -> Self {
+        Challenges {
+            difficulty,
+            outstanding: Arc::new(Mutex::new(Outstanding::default())),
+        }
+    }
+
+    pub fn difficulty(&self) -> u32 {
+        self.difficulty
+    }
+
+    // None when the connection holds too many unsolved challenges, or too
+    // many were handed out lately
+    pub fn create(&self, id: usize) -> Option<String> {
+        let now = Instant::now();
+        let mut outstanding = self.outstanding.lock();
+        outstanding.expire(now);
+
+        let count = outstanding.per_connection.get(&id).copied().unwrap_or(0);
+        if count >= MAX_CHALLENGES_PER_CONNECTION || outstanding.expiry.len() >= MAX_CHALLENGES {
+            return None;
+        }
+
+        let challenge = random_token();
+        outstanding.owners.insert(challenge.clone(), id);
+        outstanding.per_connection.insert(id, count + 1);
+        outstanding
+            .expiry
+            .push_back((now + CHALLENGE_DURATION, challenge.clone()));
+
+        Some(challenge)
+    }
+
+    // A challenge is only used up by a correct solution
+    pub fn check(&self, proof: &Proof) -> bool {
+        let hash = Sha256::digest(format!("{}{}", proof.challenge, proof.nonce).as_bytes());
+        if leading_zero_bits(&hash) < self.difficulty {
+            return false;
+        }
+
+        let mut outstanding = self.outstanding.lock();
+        outstanding.expire(Instant::now());
+        outstanding.take(&proof.challenge)
+    }
+}
+
+// What a client does with a challenge, for the load generator
+pub fn solve(challenge: String, difficulty: u32) -> Proof {
+    let nonce = (0u64..)
+        .map(|nonce| nonce.to_string())
+        .find(|nonce| {
+            let hash = Sha256::digest(format!("{}{}", challenge, nonce).as_bytes());
+            leading_zero_bits(&hash) >= difficulty
+        })
+        .unwrap_or_default();
+
+    Proof { challenge, nonce }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFFICULTY: u32 = 8;
+
+    #[test]
+    fn accepts_a_solution_once() {
+        let challenges = Challenges::new(DIFFICULTY);
+        let challenge = challenges.create(1).unwrap();
+
+        let proof = solve(challenge, DIFFICULTY);
+        assert!(challenges.check(&proof));
+        assert!(!challenges.check(&proof));
+    }
+
+    #[test]
+    fn rejects_a_wrong_nonce() {
+        let challenges = Challenges::new(DIFFICULTY);
+        let challenge = challenges.create(1).unwrap();
+
+        let nonce = (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| {
+                let hash = Sha256::digest(format!("{}{}", challenge, nonce).as_bytes());
+                leading_zero_bits(&hash) < DIFFICULTY
+            })
+            .unwrap();
+        assert!(!challenges.check(&Proof {
+            challenge: challenge.clone(),
+            nonce,
+        }));
+
+        // The challenge isn't used up by a wrong guess
+        assert!(challenges.check(&solve(challenge, DIFFICULTY)));
+    }
+
+    #[test]
+    fn rejects_a_challenge_it_did_not_hand_out() {
+        let challenges = Challenges::new(DIFFICULTY);
+
+        assert!(!challenges.check(&solve(random_token(), DIFFICULTY)));
+    }
+
+    #[test]
+    fn caps_challenges_per_connection() {
+        let challenges = Challenges::new(DIFFICULTY);
+        let handed_out = (0..MAX_CHALLENGES_PER_CONNECTION)
+            .map(|_| challenges.create(1).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(challenges.create(1), None);
+        assert!(challenges.create(2).is_some());
+
+        // Solving one makes room for another
+        assert!(challenges.check(&solve(handed_out[0].clone(), DIFFICULTY)));
+        assert!(challenges.create(1).is_some());
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0x80, 0x00]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x0f, 0xff]), 12);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+}
@@ -0,0 +1,238 @@
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use crate::server::Message;
+
+// A bounded queue of messages on their way to the workers. Past half full,
+// location updates are dropped as the next one makes up for them, everything
+// else waits for room so the sender slows down instead of memory growing.
+#[derive(Clone)]
+pub struct Queue {
+    tx: Sender<Message>,
+    capacity: usize,
+    // Shared by every queue
+    dropped: Arc<AtomicUsize>,
+}
+
+impl Queue {
+    pub fn bounded(capacity: usize, dropped: &Arc<AtomicUsize>) -> (Queue, Receiver<Message>) {
+        let (tx, rx) = bounded(capacity);
+
+        (
+            Queue {
+                tx,
+                capacity,
+                dropped: dropped.clone(),
+            },
+            rx,
+        )
+    }
+
+    // Messages waiting
+    pub fn len(&self) -> usize {
+        self.tx.len()
+    }
+
+    // Whether a send can go through without waiting. Other senders could
+    // fill the rest first, which the margin of half the queue makes unlikely.
+    pub fn has_room(&self) -> bool {
+        self.tx.len() < self.capacity / 2
+    }
+
+    // False once the receiving end is gone. A dropped message counts as
+    // sent.
+    pub fn send(&self, msg: Message) -> bool {
+        if msg.sheddable() && self.tx.len() >= self.capacity / 2 {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+
+        self.tx.send(msg).is_ok()
+    }
+
+    // Gives up instead of waiting for room, for threads the receiving end
+    // may be waiting on. False if the message wasn't queued.
+    pub fn try_send(&self, msg: Message) -> bool {
+        self.tx.try_send(msg).is_ok()
+    }
+}
+
+// Holds messages back until a deadline and then sends them on the queue, so
+// nothing has to sleep to answer late
+#[derive(Clone)]
+pub struct Delayed {
+    tx: Sender<(Instant, Message)>,
+}
+
+// Waits out the deadlines on a thread of its own
+pub struct DelayTimer {
+    rx: Receiver<(Instant, Message)>,
+    queue: Queue,
+}
+
+impl Delayed {
+    pub fn new(queue: Queue) -> (Delayed, DelayTimer) {
+        let (tx, rx) = unbounded();
+
+        (Delayed { tx }, DelayTimer { rx, queue })
+    }
+
+    pub fn send_at(&self, deadline: Instant, msg: Message) {
+        let _ = self.tx.send((deadline, msg));
+    }
+
+    // Sends right away, for threads that mustn't wait for room or drop the
+    // message when there is none
+    pub fn send(&self, msg: Message) {
+        self.send_at(Instant::now(), msg);
+    }
+}
+
+impl DelayTimer {
+    pub fn run(self) {
+        // Soonest first, in the order they came for the same deadline
+        let mut deadlines: BinaryHeap<Reverse<(Instant, u64)>> = BinaryHeap::new();
+        let mut waiting = HashMap::new();
+        let mut next_seq = 0u64;
+
+        loop {
+            let received = match deadlines.peek() {
+                Some(&Reverse((deadline, _))) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match self.rx.recv_timeout(timeout) {
+                        Ok(received) => Some(received),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                None => match self.rx.recv() {
+                    Ok(received) => Some(received),
+                    Err(_) => return,
+                },
+            };
+
+            if let Some((deadline, msg)) = received {
+                deadlines.push(Reverse((deadline, next_seq)));
+                waiting.insert(next_seq, msg);
+                next_seq += 1;
+            }
+
+            let now = Instant::now();
+            while let Some(&Reverse((deadline, seq))) = deadlines.peek() {
+                if deadline > now {
+                    break;
+                }
+                deadlines.pop();
+                if let Some(msg) = waiting.remove(&seq) {
+                    self.queue.send(msg);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socket::CloseCode;
+    use std::{thread, time::Duration};
+
+    fn close(id: usize) -> Message {
+        Message::Close {
+            id,
+            code: CloseCode::Normal,
+        }
+    }
+
+    fn location(id: usize) -> Message {
+        Message::Location {
+            id,
+            user_id: id,
+            lat: 59.33,
+            lon: 18.07,
+            alt: None,
+            accuracy_m: None,
+        }
+    }
+
+    #[test]
+    fn sheds_locations_past_half_full() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (queue, rx) = Queue::bounded(4, &dropped);
+
+        assert!(queue.send(location(1)));
+        assert!(queue.has_room());
+        assert!(queue.send(close(2)));
+        assert_eq!(queue.len(), 2);
+        assert!(!queue.has_room());
+
+        // Counts as sent, but isn't queued
+        assert!(queue.send(location(3)));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(dropped.load(Ordering::Relaxed), 1);
+
+        // Everything else still gets in
+        assert!(queue.send(close(4)));
+        assert_eq!(queue.len(), 3);
+
+        assert_eq!(
+            rx.try_iter().map(|msg| msg.id()).collect::<Vec<_>>(),
+            [1, 2, 4]
+        );
+    }
+
+    #[test]
+    fn send_waits_for_room() {
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let (queue, rx) = Queue::bounded(1, &dropped);
+        assert!(queue.send(close(1)));
+
+        let sender = {
+            let queue = queue.clone();
+            thread::spawn(move || queue.send(close(2)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!sender.is_finished());
+
+        assert_eq!(rx.recv().map(|msg| msg.id()), Ok(1));
+        assert!(sender.join().unwrap());
+        assert_eq!(rx.recv().map(|msg| msg.id()), Ok(2));
+        assert_eq!(dropped.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn try_send_gives_up_when_full() {
+        let (queue, rx) = Queue::bounded(1, &Arc::new(AtomicUsize::new(0)));
+
+        assert!(queue.try_send(close(1)));
+        assert!(!queue.try_send(close(2)));
+        assert_eq!(queue.len(), 1);
+
+        drop(rx);
+        assert!(!queue.send(close(3)));
+    }
+
+    #[test]
+    fn delayed_sends_in_deadline_order() {
+        let (queue, rx) = Queue::bounded(4, &Arc::new(AtomicUsize::new(0)));
+        let (delayed, timer) = Delayed::new(queue);
+        thread::spawn(move || timer.run());
+
+        let start = Instant::now();
+        delayed.send_at(start + Duration::from_millis(100), close(1));
+        delayed.send_at(start + Duration::from_millis(50), close(2));
+
+        assert_eq!(rx.recv().map(|msg| msg.id()), Ok(2));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(rx.recv().map(|msg| msg.id()), Ok(1));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
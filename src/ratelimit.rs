@@ -0,0 +1,414 @@
+use chashmap::CHashMap;
+use serde::Deserialize;
+use std::{
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LoginLimit {
+    pub max_failures: u32,
+    pub window_secs: u64,
+    pub lockout_secs: u64,
+}
+
+impl Default for LoginLimit {
+    fn default() -> LoginLimit {
+        LoginLimit {
+            max_failures: 5,
+            window_secs: 5 * 60,
+            lockout_secs: 15 * 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct MessageLimit {
+    pub max_messages: u32,
+    pub window_secs: u64,
+}
+
+impl Default for MessageLimit {
+    fn default() -> MessageLimit {
+        MessageLimit {
+            max_messages: 30,
+            window_secs: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LocationLimit {
+    pub per_sec: f32,
+    pub burst: f32,
+}
+
+impl Default for LocationLimit {
+    fn default() -> LocationLimit {
+        LocationLimit {
+            per_sec: 1.0,
+            burst: 5.0,
+        }
+    }
+}
+
+struct Failures {
+    count: u32,
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+struct Tracker<K: PartialEq + Hash> {
+    failures: CHashMap<K, Failures>,
+}
+
+impl<K: PartialEq + Hash> Tracker<K> {
+    fn new() -> Self {
+        Tracker {
+            failures: CHashMap::new(),
+        }
+    }
+
+    fn locked(&self, key: &K) -> Option<Duration> {
+        let now = Instant::now();
+
+        match self.failures.get(key) {
+            Some(ref failures) => match failures.locked_until {
+                Some(until) if until > now => Some(until - now),
+                _ => None,
+            },
+            None => None,
+        }
+    }
+
+    fn fail(&self, key: K, limit: &LoginLimit) {
+        let now = Instant::now();
+        let window = Duration::from_secs(limit.window_secs);
+
+        self.failures.upsert(
+            key,
+            || Failures {
+                count: 1,
+                since: now,
+                locked_until: None,
+            },
+            |failures| {
+                if now.duration_since(failures.since) > window {
+                    failures.count = 0;
+                    failures.since = now;
+                }
+
+                failures.count += 1;
+                if failures.count >= limit.max_failures {
+                    failures.count = 0;
+                    failures.since = now;
+                    failures.locked_until = Some(now + Duration::from_secs(limit.lockout_secs));
+                }
+            },
+        );
+    }
+
+    fn clear(&self, key: &K) {
+        self.failures.remove(key);
+    }
+
+    // Drops what neither a lockout nor the current window still needs
+    fn sweep(&self, limit: &LoginLimit) {
+        let now = Instant::now();
+        let window = Duration::from_secs(limit.window_secs);
+
+        self.failures.retain(|_, failures| {
+            failures.locked_until.is_some_and(|until| until > now)
+                || now.duration_since(failures.since) <= window
+        });
+    }
+}
+
+// Logins counted both per username and per remote address. An attempt counts
+// as failed from the moment it is made, so attempts still being checked count
+// towards the limit, and a success clears the count again.
+#[derive(Clone)]
+pub struct LoginAttempts {
+    limit: LoginLimit,
+    usernames: Arc<Tracker<String>>,
+    addrs: Arc<Tracker<String>>,
+}
+
+impl LoginAttempts {
+    pub fn new(limit: LoginLimit) -> Self {
+        LoginAttempts {
+            limit,
+            usernames: Arc::new(Tracker::new()),
+            addrs: Arc::new(Tracker::new()),
+        }
+    }
+
+    pub fn locked(&self, username: &str, addr: Option<&str>) -> Option<Duration> {
+        self.usernames
+            .locked(&username.to_string())
+            .or_else(|| addr.and_then(|addr| self.addrs.locked(&addr.to_string())))
+    }
+
+    pub fn attempted(&self, username: &str, addr: Option<&str>) {
+        self.usernames.fail(username.to_string(), &self.limit);
+        if let Some(addr) = addr {
+            self.addrs.fail(addr.to_string(), &self.limit);
+        }
+    }
+
+    pub fn succeeded(&self, username: &str, addr: Option<&str>) {
+        self.usernames.clear(&username.to_string());
+        if let Some(addr) = addr {
+            self.addrs.clear(&addr.to_string());
+        }
+    }
+
+    // Called now and then, so usernames and addresses tried once don't pile up
+    pub fn sweep(&self) {
+        self.usernames.sweep(&self.limit);
+        self.addrs.sweep(&self.limit);
+    }
+}
+
+// Messages counted per user in fixed windows
+#[derive(Clone)]
+pub struct MessageRate {
+    limit: MessageLimit,
+    windows: Arc<CHashMap<usize, (u32, Instant)>>,
+}
+
+impl MessageRate {
+    pub fn new(limit: MessageLimit) -> Self {
+        MessageRate {
+            limit,
+            windows: Arc::new(CHashMap::new()),
+        }
+    }
+
+    // Counts a message, returning how long to wait if it is over the limit
+    pub fn check(&self, user_id: usize) -> Option<Duration> {
+        let now = Instant::now();
+        let window = Duration::from_secs(self.limit.window_secs);
+        let mut retry_after = None;
+
+        self.windows.upsert(
+            user_id,
+            || (1, now),
+            |&mut (ref mut count, ref mut since)| {
+                if now.duration_since(*since) > window {
+                    *count = 0;
+                    *since = now;
+                }
+
+                if *count >= self.limit.max_messages {
+                    retry_after = Some(window - now.duration_since(*since));
+                } else {
+                    *count += 1;
+                }
+            },
+        );
+
+        retry_after
+    }
+}
+
+// Refills at a steady rate up to the burst, every update takes a token
+pub struct TokenBucket {
+    limit: LocationLimit,
+    tokens: f32,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: LocationLimit) -> Self {
+        TokenBucket {
+            tokens: limit.burst,
+            limit,
+            updated: Instant::now(),
+        }
+    }
+
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f32();
+        self.tokens = (self.tokens + elapsed * self.limit.per_sec).min(self.limit.burst);
+        self.updated = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    max_per_addr: usize,
+    connections: Arc<CHashMap<String, usize>>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max_per_addr: usize) -> Self {
+        ConnectionLimit {
+            max_per_addr,
+            connections: Arc::new(CHashMap::new()),
+        }
+    }
+
+    // Counts a new connection from `addr`, unless it already has the maximum
+    pub fn open(&self, addr: &str) -> bool {
+        let mut allowed = true;
+        self.connections.upsert(
+            addr.to_string(),
+            || 1,
+            |count| {
+                if *count >= self.max_per_addr {
+                    allowed = false;
+                } else {
+                    *count += 1;
+                }
+            },
+        );
+
+        allowed
+    }
+
+    pub fn close(&self, addr: &str) {
+        self.connections
+            .alter(addr.to_string(), |count| match count {
+                Some(count) if count > 1 => Some(count - 1),
+                _ => None,
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_out_after_failed_logins() {
+        let attempts = LoginAttempts::new(LoginLimit {
+            max_failures: 3,
+            window_secs: 60,
+            lockout_secs: 60,
+        });
+
+        for _ in 0..2 {
+            attempts.attempted("alice", Some("192.0.2.1"));
+        }
+        assert_eq!(attempts.locked("alice", Some("192.0.2.1")), None);
+
+        // Counted before the password is checked
+        attempts.attempted("alice", Some("192.0.2.1"));
+        let locked = attempts.locked("alice", Some("192.0.2.1"));
+        assert!(locked.is_some_and(|locked| locked <= Duration::from_secs(60)));
+
+        // Locked for the username from any address, and for the address
+        // with any username, on a new connection too
+        assert!(attempts.locked("alice", Some("192.0.2.2")).is_some());
+        assert!(attempts.locked("bob", Some("192.0.2.1")).is_some());
+        assert_eq!(attempts.locked("bob", Some("192.0.2.2")), None);
+        assert_eq!(attempts.locked("bob", None), None);
+    }
+
+    #[test]
+    fn successful_login_clears_failures() {
+        let attempts = LoginAttempts::new(LoginLimit {
+            max_failures: 2,
+            window_secs: 60,
+            lockout_secs: 60,
+        });
+
+        attempts.attempted("alice", Some("192.0.2.1"));
+        attempts.succeeded("alice", Some("192.0.2.1"));
+        attempts.attempted("alice", Some("192.0.2.1"));
+        assert_eq!(attempts.locked("alice", Some("192.0.2.1")), None);
+    }
+
+    #[test]
+    fn sweep_keeps_only_live_windows_and_lockouts() {
+        let attempts = LoginAttempts::new(LoginLimit {
+            max_failures: 2,
+            window_secs: 60,
+            lockout_secs: 60,
+        });
+
+        attempts.attempted("alice", None);
+        attempts.attempted("alice", None);
+        attempts.attempted("bob", Some("192.0.2.1"));
+        let past = Instant::now() - Duration::from_secs(120);
+        if let Some(mut failures) = attempts.usernames.failures.get_mut("bob") {
+            failures.since = past;
+        }
+        if let Some(mut failures) = attempts.addrs.failures.get_mut("192.0.2.1") {
+            failures.since = past;
+        }
+        attempts.sweep();
+
+        // alice is still locked out, bob's window has passed
+        assert!(attempts.locked("alice", None).is_some());
+        assert_eq!(attempts.usernames.failures.len(), 1);
+        assert_eq!(attempts.addrs.failures.len(), 0);
+    }
+
+    #[test]
+    fn limits_messages_per_window() {
+        let rate = MessageRate::new(MessageLimit {
+            max_messages: 3,
+            window_secs: 60,
+        });
+
+        for _ in 0..3 {
+            assert_eq!(rate.check(1), None);
+        }
+        let retry_after = rate.check(1);
+        assert!(retry_after.is_some_and(|retry_after| retry_after <= Duration::from_secs(60)));
+
+        // Each user has a window of their own
+        assert_eq!(rate.check(2), None);
+    }
+
+    #[test]
+    fn token_bucket_allows_a_burst() {
+        let mut bucket = TokenBucket::new(LocationLimit {
+            per_sec: 0.0,
+            burst: 2.0,
+        });
+
+        assert!(bucket.take());
+        assert!(bucket.take());
+        assert!(!bucket.take());
+    }
+
+    #[test]
+    fn token_bucket_refills() {
+        let mut bucket = TokenBucket::new(LocationLimit {
+            per_sec: 1000.0,
+            burst: 1.0,
+        });
+
+        assert!(bucket.take());
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(bucket.take());
+    }
+
+    #[test]
+    fn limits_connections_per_address() {
+        let limit = ConnectionLimit::new(2);
+
+        assert!(limit.open("10.0.0.1"));
+        assert!(limit.open("10.0.0.1"));
+        assert!(!limit.open("10.0.0.1"));
+        assert!(limit.open("10.0.0.2"));
+
+        limit.close("10.0.0.1");
+        assert!(limit.open("10.0.0.1"));
+    }
+}
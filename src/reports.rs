@@ -0,0 +1,54 @@
+use crate::server::ChatMessage;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{sync::atomic::AtomicUsize, sync::atomic::Ordering, sync::Arc};
+
+pub const MAX_REASON_LENGTH: usize = 300;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Report {
+    pub id: usize,
+    pub reporter: String,
+    pub target: String,
+    pub message: ChatMessage,
+    pub reason: String,
+}
+
+#[derive(Clone)]
+pub struct Reports {
+    current_id: Arc<AtomicUsize>,
+    reports: Arc<Mutex<Vec<Report>>>,
+}
+
+impl Reports {
+    pub fn new() -> Self {
+        Reports {
+            current_id: Arc::new(AtomicUsize::new(0)),
+            reports: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn add(&self, reporter: String, target: String, message: ChatMessage, reason: String) {
+        let id = self.current_id.fetch_add(1, Ordering::Relaxed);
+
+        self.reports.lock().push(Report {
+            id,
+            reporter,
+            target,
+            message,
+            reason,
+        });
+    }
+
+    pub fn list(&self) -> Vec<Report> {
+        self.reports.lock().clone()
+    }
+
+    pub fn resolve(&self, id: usize) -> bool {
+        let mut reports = self.reports.lock();
+        let len = reports.len();
+        reports.retain(|report| report.id != id);
+
+        reports.len() != len
+    }
+}
@@ -1,205 +1,2679 @@
 use chashmap::CHashMap;
-use crossbeam::channel::unbounded;
+use crossbeam::channel::{bounded, unbounded, Receiver, RecvTimeoutError, Sender};
 use parking_lot::{Mutex, RwLock};
+use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::{Deserialize, Serialize};
-use std::{sync::atomic::AtomicUsize, sync::atomic::Ordering, sync::Arc};
-use ws::{CloseCode, Handler, Handshake, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::{self, Hash},
+    mem,
+    net::SocketAddr,
+    str::FromStr,
+    sync::atomic::AtomicUsize,
+    sync::atomic::Ordering,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-const PBKDF2_ITERATIONS: u32 = 1;
+use crate::addrban::{AddrBans, TrustedProxies};
+use crate::audit::Entry;
+use crate::fences::{Fence, Shape};
+use crate::geo::{Geometry, MAX_NEARBY_LIMIT};
+use crate::oauth::Identity;
+use crate::origin::OriginPolicy;
+use crate::password::{self, Hasher, PasswordError, Policy};
+use crate::polls::Polls;
+use crate::pool::{user_key, Pool};
+use crate::pow::Proof;
+use crate::queue::Queue;
+use crate::ratelimit::TokenBucket;
+use crate::reports::{Report, MAX_REASON_LENGTH};
+use crate::shared::{Shared, SharedSession};
+use crate::socket::{self, CloseCode, Frame, Payload, Request, Result, Socket};
+use crate::stats::{HeatmapCell, HEATMAP_PRECISION, MAX_HEATMAP_WINDOW_SECS};
+use crate::storage::{StatsRecord, Storage, UserRecord};
+
+// Size of an area in degrees
 const RANGE_LATLON: f32 = 0.1;
+// Radius of users who haven't set their own
 const RANGE_KM: f32 = 10.0;
+const KM_PER_DEGREE_LAT: f32 = 111.2;
+// A metre up or down counts as this many along the ground, so floors of a
+// tower aren't in range of the street below
+const ALTITUDE_WEIGHT: f32 = 50.0;
+const MIN_ALTITUDE_M: f32 = -500.0;
+const MAX_ALTITUDE_M: f32 = 10_000.0;
+// Mean radius
+const EARTH_RADIUS_KM: f64 = 6371.0088;
+const MAX_STATUS_LENGTH: usize = 64;
+const UNREAD_BACKLOG: usize = 1000;
+const MESSAGE_BACKLOG: usize = 10_000;
+const MAX_PINS: usize = 10;
+const MAX_CLIENT_ID_LENGTH: usize = 64;
+const MAX_KEY_LENGTH: usize = 1024;
+const MAX_CIPHERTEXT_LENGTH: usize = 8192;
+const CLIENT_ID_WINDOW: Duration = Duration::from_secs(5 * 60);
+const SESSION_TOKEN_LENGTH: usize = 32;
+const RESET_DURATION: Duration = Duration::from_secs(60 * 60);
+const VERIFICATION_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+const NONCE_DURATION: Duration = Duration::from_secs(5 * 60);
+const SHARED_SESSION_INTERVAL: u64 = 60;
+const MAX_USER_AGENT_LENGTH: usize = 256;
+// Larger frames close the connection before they are read in full
+pub const MAX_FRAME_SIZE: usize = 16 * 1024;
+const MAX_FIELD_LENGTH: usize = MAX_CIPHERTEXT_LENGTH;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+const MAX_PASSWORD_LENGTH: usize = 256;
+// Users without a name of their own are called "provider:subject", so no
+// one else may pick a name like that
+const RESERVED_USERNAME_CHARS: &[char] = &[':'];
+const MAX_MESSAGE_LENGTH: usize = 300;
+const MAX_TRAVEL_SPEED_KMH: f32 = 1000.0;
+// How far back the path of a user in route mode reaches
+const ROUTE_WINDOW: Duration = Duration::from_secs(15 * 60);
+// Points of a route closer than this to the one before aren't kept
+const ROUTE_SPACING_KM: f32 = 0.5;
+const MAX_ROUTE_POINTS: usize = 100;
+const LOCATION_JITTER_KM: f32 = 1.0;
+// Fixes less accurate than this, such as ones from a cell tower far away,
+// aren't used at all
+const MAX_LOCATION_ACCURACY_M: f32 = 5000.0;
+const MAX_SETTINGS: usize = 32;
+const MAX_SETTING_SIZE: usize = 256;
+// Setting holding "km" or "mi", km when missing
+const UNITS_SETTING: &str = "units";
+const KM_PER_MILE: f32 = 1.609_344;
+// Changes to the connections are published to readers once this many have
+// been made, or this long after the first of them
+const REFRESH_BATCH: usize = 64;
+const REFRESH_INTERVAL: Duration = Duration::from_millis(5);
+// Areas around the world at any latitude
+const LON_AREAS: i32 = (360.0 / RANGE_LATLON) as i32;
+
+pub type Area = (i32, i32);
+// Free-form preferences kept for the client, e.g. notifications or privacy flags
+pub type Settings = HashMap<String, serde_json::Value>;
+
+pub fn random_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+// Finds the first string in a frame that is longer than its field allows
+fn oversized_field(val: &serde_json::Value, field: &str) -> Option<(String, usize)> {
+    match *val {
+        serde_json::Value::String(ref s) => {
+            let max = match field {
+                "username" | "nickname" => MAX_USERNAME_LENGTH,
+                "password" | "old" | "new" | "new_password" => MAX_PASSWORD_LENGTH,
+                _ => MAX_FIELD_LENGTH,
+            };
+
+            if s.len() > max {
+                Some((field.to_string(), max))
+            } else {
+                None
+            }
+        }
+        serde_json::Value::Array(ref vals) => vals
+            .iter()
+            .filter_map(|val| oversized_field(val, field))
+            .next(),
+        serde_json::Value::Object(ref map) => map
+            .iter()
+            .filter_map(|(field, val)| oversized_field(val, field))
+            .next(),
+        _ => None,
+    }
+}
+
+pub fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= MAX_USERNAME_LENGTH
+        && !username
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || RESERVED_USERNAME_CHARS.contains(&c))
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+// Messages of the global room are kept under an area no location falls in
+pub const GLOBAL_AREA: Area = (i32::MIN, i32::MIN);
+
+pub fn area(lat: f32, lon: f32) -> Area {
+    (
+        (lat / RANGE_LATLON).floor() as i32,
+        (lon / RANGE_LATLON).floor() as i32,
+    )
+}
+
+// Great circle distance in km, by the haversine formula. Computed in f64 since
+// f32 loses the short distances that matter here.
+pub fn distance(lat1: f32, lon1: f32, lat2: f32, lon2: f32) -> f32 {
+    let (lat1, lat2) = (f64::from(lat1).to_radians(), f64::from(lat2).to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (f64::from(lon2) - f64::from(lon1)).to_radians();
+
+    let h = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    (2.0 * EARTH_RADIUS_KM * h.sqrt().min(1.0).asin()) as f32
+}
+
+// Ordered by privilege
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, PartialOrd, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Suspension {
+    pub reason: String,
+    // Unix time the suspension ends at, or never
+    pub until: Option<u64>,
+}
+
+impl Suspension {
+    fn active(&self) -> bool {
+        match self.until {
+            Some(until) => until > unix_time(),
+            None => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum Status {
+    Online,
+    Away,
+    Busy,
+    Custom(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum RegisterError {
+    InvalidUsername,
+    UsernameTaken,
+    PasswordTooShort,
+    PasswordTooWeak,
+    EmailRequired,
+    InvalidEmail,
+}
+
+impl From<PasswordError> for RegisterError {
+    fn from(error: PasswordError) -> Self {
+        match error {
+            PasswordError::TooShort => RegisterError::PasswordTooShort,
+            PasswordError::TooWeak => RegisterError::PasswordTooWeak,
+        }
+    }
+}
+
+// A logged in connection
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SessionInfo {
+    pub id: usize,
+    pub connected_at: u64,
+    pub user_agent: Option<String>,
+    pub addr: Option<String>,
+    pub current: bool,
+}
+
+// A point of a user's location history
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LocationPoint {
+    pub lat: f32,
+    pub lon: f32,
+    pub geometry: Geometry,
+    pub time: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Unit {
+    Km,
+    Mi,
+}
+
+// A distance in the unit the user it is sent to prefers
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Distance {
+    pub value: f32,
+    pub unit: Unit,
+}
+
+impl Distance {
+    // Rounded to a tenth of the unit
+    pub fn approximate(km: f32, unit: Unit) -> Distance {
+        Distance {
+            value: ((Distance::convert(km, unit) * 10.0).round() / 10.0).max(0.1),
+            unit,
+        }
+    }
+
+    // Rounded up to whole units, so it can't be used to pinpoint anyone
+    pub fn coarse(km: f32, unit: Unit) -> Distance {
+        Distance {
+            value: Distance::convert(km, unit).ceil().max(1.0),
+            unit,
+        }
+    }
+
+    fn convert(km: f32, unit: Unit) -> f32 {
+        match unit {
+            Unit::Km => km,
+            Unit::Mi => km / KM_PER_MILE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NearbyUser {
+    pub username: String,
+    pub guest: bool,
+    pub bot: bool,
+    pub distance: Distance,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub place: Option<String>,
+}
+
+// Everything stored about a user, for them to take with them
+#[derive(Serialize, Deserialize)]
+pub struct DataExport {
+    pub username: String,
+    pub email: Option<String>,
+    pub verified: bool,
+    pub role: Role,
+    pub status: Status,
+    pub guest: bool,
+    pub bot: bool,
+    pub lat: f32,
+    pub lon: f32,
+    pub last_seen_visible: bool,
+    pub location_history: bool,
+    pub fuzz_location: bool,
+    pub radius_km: f32,
+    pub public_key: Option<String>,
+    pub suspension: Option<Suspension>,
+    pub settings: Settings,
+    pub messages: Vec<ChatMessage>,
+    pub sessions: Vec<SessionInfo>,
+    pub audit: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChatMessage {
+    pub id: usize,
+    pub username: String,
+    #[serde(default)]
+    pub guest: bool,
+    #[serde(default)]
+    pub bot: bool,
+    pub msg: String,
+}
 
 #[derive(Serialize, Deserialize)]
 pub enum JsonMessage {
-    Location { lat: f32, lon: f32 },
-    Login { username: String, password: String },
-    LoginResponse { status: bool },
-    Register { username: String, password: String },
-    RegisterResponse { status: bool },
-    SendMessage { msg: String },
-    Message { username: String, msg: String },
-    Error { reason: String },
+    // Either lat and lon or a GeoJSON point, which wins when both are sent
+    Location {
+        #[serde(default)]
+        lat: Option<f32>,
+        #[serde(default)]
+        lon: Option<f32>,
+        // Metres above sea level, when the client knows it
+        #[serde(default)]
+        alt: Option<f32>,
+        #[serde(default)]
+        geometry: Option<Geometry>,
+        // Radius in metres the real location is likely within, as reported
+        // by the device
+        #[serde(default)]
+        accuracy_m: Option<f32>,
+    },
+    Login {
+        username: String,
+        password: String,
+    },
+    LoginResponse {
+        status: bool,
+        token: Option<String>,
+    },
+    RateLimited {
+        retry_after: u64,
+    },
+    Register {
+        username: String,
+        password: String,
+        #[serde(default)]
+        email: Option<String>,
+        #[serde(default)]
+        proof: Option<Proof>,
+    },
+    RegisterChallenge {
+        challenge: String,
+        difficulty: u32,
+    },
+    RegisterResponse {
+        status: bool,
+        token: Option<String>,
+        reason: Option<RegisterError>,
+    },
+    LoginToken {
+        jwt: String,
+    },
+    GuestLogin {
+        nickname: String,
+    },
+    LoginOAuth {
+        provider: String,
+        token: String,
+    },
+    BotLogin {
+        key: String,
+    },
+    VerifyEmail {
+        code: String,
+    },
+    VerifyEmailResponse {
+        status: bool,
+    },
+    Resume {
+        token: String,
+    },
+    ResumeResponse {
+        status: bool,
+    },
+    Logout,
+    LogoutResponse {
+        status: bool,
+    },
+    ListSessions,
+    SessionList {
+        sessions: Vec<SessionInfo>,
+    },
+    RevokeSession {
+        session_id: usize,
+    },
+    // Every session except the one sending it
+    RevokeAllSessions,
+    RevokeResponse {
+        status: bool,
+    },
+    SessionExpiring {
+        expires_in: u64,
+    },
+    SessionExpired,
+    GetNonce,
+    Nonce {
+        nonce: String,
+    },
+    ChangePassword {
+        old: String,
+        new: String,
+        #[serde(default)]
+        nonce: Option<String>,
+    },
+    ChangePasswordResponse {
+        status: bool,
+    },
+    ResetPassword {
+        token: String,
+        new_password: String,
+    },
+    ResetPasswordResponse {
+        status: bool,
+    },
+    SendMessage {
+        msg: String,
+        #[serde(default)]
+        client_id: Option<String>,
+        // Only reaches the users right around the sender
+        #[serde(default)]
+        whisper: bool,
+    },
+    MessageAck {
+        id: usize,
+        client_id: String,
+    },
+    SetStatus {
+        status: Status,
+    },
+    SetLastSeenVisible {
+        visible: bool,
+    },
+    // Clamped to the server's bounds, the radius used is sent back
+    SetRadius {
+        km: f32,
+    },
+    Radius {
+        km: f32,
+    },
+    // Online users and messages per minute within the radius
+    GetAreaInfo,
+    AreaInfo {
+        online_users: usize,
+        messages_per_minute: f32,
+    },
+    // Named areas whose messages everyone inside them gets
+    GetFences,
+    AddFence {
+        name: String,
+        shape: Shape,
+        #[serde(default)]
+        poi: bool,
+    },
+    RemoveFence {
+        id: usize,
+    },
+    Fences {
+        fences: Vec<Fence>,
+    },
+    // Points of interest the user is in, also sent whenever that changes
+    GetChannels,
+    Channels {
+        channels: Vec<Fence>,
+    },
+    SendToChannel {
+        channel: usize,
+        msg: String,
+    },
+    ChannelMessage {
+        channel: usize,
+        username: String,
+        guest: bool,
+        bot: bool,
+        msg: String,
+    },
+    FenceJoined {
+        fence: String,
+        username: String,
+    },
+    FenceLeft {
+        fence: String,
+        username: String,
+    },
+    // Sent to both users when a move brings them into or out of range
+    InRange {
+        username: String,
+        guest: bool,
+        bot: bool,
+    },
+    OutOfRange {
+        username: String,
+    },
+    // The closest online users in range
+    Nearby {
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    NearbyUsers {
+        users: Vec<NearbyUser>,
+    },
+    // Opting out deletes the history kept so far
+    SetLocationHistory {
+        enabled: bool,
+    },
+    // For users who'd rather not share their location, messages go to and
+    // come from everyone else in the room
+    SetGlobal {
+        enabled: bool,
+    },
+    // Messages go to the users along the last minutes of the user's path as
+    // well as the ones around them
+    SetRouteMode {
+        enabled: bool,
+    },
+    // Snaps the user's location to the server's grid before it is used
+    SetFuzzLocation {
+        enabled: bool,
+    },
+    MyLocationHistory {
+        #[serde(default)]
+        since: Option<u64>,
+    },
+    LocationHistory {
+        locations: Vec<LocationPoint>,
+    },
+    GetProfile {
+        username: String,
+    },
+    Profile {
+        username: String,
+        status: Status,
+        online: bool,
+        last_seen_minutes: Option<u64>,
+    },
+    // Keys and ciphertexts are opaque to the server
+    PublishKey {
+        key: String,
+    },
+    GetKey {
+        username: String,
+    },
+    PublicKey {
+        username: String,
+        key: Option<String>,
+    },
+    EncryptedMessage {
+        to: String,
+        ciphertext: String,
+    },
+    Encrypted {
+        from: String,
+        ciphertext: String,
+    },
+    MarkRead {
+        id: usize,
+    },
+    GetUnreadCounts,
+    UnreadCounts {
+        count: usize,
+        last_read: Option<usize>,
+    },
+    RequestDataExport,
+    GetSettings,
+    // Null values remove a setting, others are left as they are
+    UpdateSettings {
+        settings: Settings,
+    },
+    Settings {
+        settings: Settings,
+    },
+    DataExport {
+        data: DataExport,
+    },
+    // Messages sent in the user's area, newest first
+    GetHistory {
+        #[serde(default)]
+        before: Option<usize>,
+        #[serde(default)]
+        search: Option<String>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    History {
+        messages: Vec<ChatMessage>,
+    },
+    // Messages sent in a quick burst share a frame, as an array of them
+    Message {
+        id: usize,
+        username: String,
+        guest: bool,
+        bot: bool,
+        msg: String,
+        #[serde(default, skip_serializing_if = "is_false")]
+        whisper: bool,
+        // How far the sender is from the recipient, left out for the sender
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        distance: Option<Distance>,
+        // Where the sender is, when a geocoder is configured
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        place: Option<String>,
+    },
+    ShareLocation,
+    SharedLocation {
+        username: String,
+        guest: bool,
+        bot: bool,
+        lat: f32,
+        lon: f32,
+        geometry: Geometry,
+    },
+    Pin {
+        id: usize,
+    },
+    Unpin {
+        id: usize,
+    },
+    PinResponse {
+        status: bool,
+    },
+    Pinned {
+        messages: Vec<ChatMessage>,
+    },
+    CreatePoll {
+        question: String,
+        options: Vec<String>,
+    },
+    Vote {
+        poll_id: usize,
+        option: usize,
+    },
+    Poll {
+        id: usize,
+        question: String,
+        options: Vec<String>,
+        tally: Vec<usize>,
+    },
+    ReportMessage {
+        id: usize,
+        reason: String,
+    },
+    Announce {
+        text: String,
+    },
+    Announcement {
+        text: String,
+    },
+    Ban {
+        username: String,
+    },
+    BanResponse {
+        status: bool,
+    },
+    Suspend {
+        username: String,
+        reason: String,
+        #[serde(default)]
+        until: Option<u64>,
+    },
+    Unsuspend {
+        username: String,
+    },
+    SuspendResponse {
+        status: bool,
+    },
+    BanAddress {
+        range: String,
+    },
+    UnbanAddress {
+        range: String,
+    },
+    BanAddressResponse {
+        status: bool,
+    },
+    // Paths are on the server
+    Backup {
+        path: String,
+    },
+    Restore {
+        path: String,
+    },
+    BackupResponse {
+        users: usize,
+        messages: usize,
+    },
+    Suspended {
+        reason: String,
+        until: Option<u64>,
+    },
+    GetStats,
+    Stats {
+        users: usize,
+        connections: usize,
+        messages: usize,
+        uptime_secs: u64,
+        // Location updates dropped since start while the server was behind
+        dropped: usize,
+        // Messages passed from the router to the workers
+        routed_per_sec: f32,
+        // Messages waiting on the router, then on each worker
+        queues: Vec<usize>,
+        // Failed logins since start, by any method
+        auth_failures: usize,
+    },
+    // Stored activity per period, oldest first
+    GetStatsHistory {
+        #[serde(default)]
+        since: Option<u64>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    StatsHistory {
+        stats: Vec<StatsRecord>,
+    },
+    // Messages per geohash cell, for admins and bots
+    GetHeatmap {
+        #[serde(default)]
+        window_secs: Option<u64>,
+        #[serde(default)]
+        precision: Option<usize>,
+    },
+    Heatmap {
+        cells: Vec<HeatmapCell>,
+    },
+    // Unusually many users in one place, sent to admins and optionally the
+    // users around it
+    Hotspot {
+        geohash: String,
+        lat: f32,
+        lon: f32,
+        geometry: Geometry,
+        users: usize,
+    },
+    // Online users inside a box, for moderators and bots. Only moderators
+    // get the usernames.
+    QueryBox {
+        min_lat: f32,
+        min_lon: f32,
+        max_lat: f32,
+        max_lon: f32,
+    },
+    BoxCounts {
+        users: usize,
+        guests: usize,
+        bots: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        usernames: Option<Vec<String>>,
+    },
+    PermissionDenied {
+        required: Role,
+    },
+    GetAuditLog {
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        limit: Option<usize>,
+    },
+    AuditLog {
+        entries: Vec<Entry>,
+    },
+    GetReports,
+    Reports {
+        reports: Vec<Report>,
+    },
+    ResolveReport {
+        id: usize,
+    },
+    TimeSync {
+        #[serde(default)]
+        client_time: Option<u64>,
+    },
+    TimeSyncResponse {
+        client_time: Option<u64>,
+        wall_time: u64,
+        monotonic_time: u64,
+    },
+    Error {
+        reason: String,
+    },
+    LimitExceeded {
+        field: String,
+        max: usize,
+    },
+}
+
+pub enum Message {
+    Open {
+        server: Server,
+        tx: crossbeam::Sender<Option<usize>>,
+    },
+    Close {
+        id: usize,
+        code: CloseCode,
+    },
+    // Answered straight to the connection, so it isn't held up waiting
+    Login {
+        id: usize,
+        username: String,
+        password: String,
+    },
+    // The outcome of checking the password of a login, None when it failed
+    AuthResult {
+        id: usize,
+        username: String,
+        user_id: Option<usize>,
+    },
+    Register {
+        id: usize,
+        username: String,
+        password: String,
+        email: Option<String>,
+        proof: Option<Proof>,
+    },
+    // The outcome of adding the user of a registration
+    RegisterResult {
+        id: usize,
+        username: String,
+        result: std::result::Result<usize, RegisterError>,
+    },
+    VerifyEmail {
+        id: usize,
+        user_id: usize,
+        code: String,
+    },
+    LoginToken {
+        id: usize,
+        jwt: String,
+    },
+    GuestLogin {
+        id: usize,
+        nickname: String,
+    },
+    LoginOAuth {
+        id: usize,
+        provider: String,
+        token: String,
+    },
+    // The identity the provider gave for the token of a login, None when it
+    // didn't accept it or couldn't be reached
+    OAuthResult {
+        id: usize,
+        provider: String,
+        identity: Option<Identity>,
+    },
+    BotLogin {
+        id: usize,
+        key: String,
+    },
+    Resume {
+        id: usize,
+        token: String,
+    },
+    Logout {
+        id: usize,
+    },
+    Nonce {
+        id: usize,
+    },
+    ListSessions {
+        id: usize,
+        user_id: usize,
+    },
+    // None revokes all but the connection `id`
+    RevokeSession {
+        id: usize,
+        user_id: usize,
+        session_id: Option<usize>,
+    },
+    ChangePassword {
+        id: usize,
+        user_id: usize,
+        old: String,
+        new: String,
+        nonce: Option<String>,
+    },
+    ResetPassword {
+        id: usize,
+        token: String,
+        new_password: String,
+    },
+    // Signs `user_id` out of the connection on the worker it belongs to, for
+    // changes decided elsewhere
+    SignOut {
+        id: usize,
+        user_id: usize,
+        expired: bool,
+    },
+    Message {
+        id: usize,
+        user_id: usize,
+        msg: String,
+        client_id: Option<String>,
+        whisper: bool,
+    },
+    Location {
+        id: usize,
+        user_id: usize,
+        lat: f32,
+        lon: f32,
+        alt: Option<f32>,
+        accuracy_m: Option<f32>,
+    },
+    ShareLocation {
+        id: usize,
+        user_id: usize,
+    },
+    Status {
+        id: usize,
+        user_id: usize,
+        status: Status,
+    },
+    Seen {
+        id: usize,
+        user_id: usize,
+    },
+    LastSeenVisible {
+        id: usize,
+        user_id: usize,
+        visible: bool,
+    },
+    Radius {
+        id: usize,
+        user_id: usize,
+        km: f32,
+    },
+    Nearby {
+        id: usize,
+        user_id: usize,
+        limit: usize,
+    },
+    AreaInfo {
+        id: usize,
+        user_id: usize,
+    },
+    Fences {
+        id: usize,
+    },
+    AddFence {
+        id: usize,
+        user_id: usize,
+        name: String,
+        shape: Shape,
+        poi: bool,
+    },
+    RemoveFence {
+        id: usize,
+        user_id: usize,
+        fence_id: usize,
+    },
+    Channels {
+        id: usize,
+        user_id: usize,
+    },
+    ToChannel {
+        id: usize,
+        user_id: usize,
+        channel: usize,
+        msg: String,
+    },
+    FuzzLocation {
+        id: usize,
+        user_id: usize,
+        enabled: bool,
+    },
+    RouteMode {
+        id: usize,
+        user_id: usize,
+        enabled: bool,
+    },
+    Global {
+        id: usize,
+        user_id: usize,
+        enabled: bool,
+    },
+    LocationHistory {
+        id: usize,
+        user_id: usize,
+        enabled: bool,
+    },
+    MyLocationHistory {
+        id: usize,
+        user_id: usize,
+        since: u64,
+    },
+    Profile {
+        id: usize,
+        username: String,
+    },
+    PublishKey {
+        id: usize,
+        user_id: usize,
+        key: String,
+    },
+    Key {
+        id: usize,
+        username: String,
+    },
+    Encrypted {
+        id: usize,
+        user_id: usize,
+        to: String,
+        ciphertext: String,
+    },
+    MarkRead {
+        id: usize,
+        user_id: usize,
+        message_id: usize,
+    },
+    UnreadCounts {
+        id: usize,
+        user_id: usize,
+    },
+    DataExport {
+        id: usize,
+        user_id: usize,
+    },
+    Settings {
+        id: usize,
+        user_id: usize,
+        update: Option<Settings>,
+    },
+    History {
+        id: usize,
+        user_id: usize,
+        before: Option<usize>,
+        search: Option<String>,
+        limit: Option<usize>,
+    },
+    Pin {
+        id: usize,
+        user_id: usize,
+        message_id: usize,
+        pinned: bool,
+    },
+    CreatePoll {
+        id: usize,
+        user_id: usize,
+        question: String,
+        options: Vec<String>,
+    },
+    Vote {
+        id: usize,
+        user_id: usize,
+        poll_id: usize,
+        option: usize,
+    },
+    Report {
+        id: usize,
+        user_id: usize,
+        message_id: usize,
+        reason: String,
+    },
+    Reports {
+        id: usize,
+        user_id: usize,
+        resolve: Option<usize>,
+    },
+    Announce {
+        id: usize,
+        user_id: usize,
+        text: String,
+    },
+    Ban {
+        id: usize,
+        user_id: usize,
+        username: String,
+    },
+    Stats {
+        id: usize,
+        user_id: usize,
+    },
+    StatsHistory {
+        id: usize,
+        user_id: usize,
+        since: u64,
+        limit: Option<usize>,
+    },
+    Heatmap {
+        id: usize,
+        user_id: usize,
+        window_secs: u64,
+        precision: usize,
+    },
+    QueryBox {
+        id: usize,
+        user_id: usize,
+        min_lat: f32,
+        min_lon: f32,
+        max_lat: f32,
+        max_lon: f32,
+    },
+    Suspend {
+        id: usize,
+        user_id: usize,
+        username: String,
+        suspension: Option<Suspension>,
+    },
+    BanAddress {
+        id: usize,
+        user_id: usize,
+        range: String,
+        banned: bool,
+    },
+    AuditLog {
+        id: usize,
+        user_id: usize,
+        username: Option<String>,
+        limit: Option<usize>,
+    },
+    Backup {
+        id: usize,
+        user_id: usize,
+        path: String,
+        restore: bool,
+    },
+}
+
+impl Message {
+    // The connection the message came from, which decides the worker that
+    // handles it
+    pub fn id(&self) -> usize {
+        match self {
+            Message::Open { server, .. } => server.id,
+            Message::Close { id, .. }
+            | Message::Login { id, .. }
+            | Message::AuthResult { id, .. }
+            | Message::Register { id, .. }
+            | Message::RegisterResult { id, .. }
+            | Message::VerifyEmail { id, .. }
+            | Message::LoginToken { id, .. }
+            | Message::GuestLogin { id, .. }
+            | Message::LoginOAuth { id, .. }
+            | Message::OAuthResult { id, .. }
+            | Message::BotLogin { id, .. }
+            | Message::Resume { id, .. }
+            | Message::Logout { id, .. }
+            | Message::Nonce { id, .. }
+            | Message::ListSessions { id, .. }
+            | Message::RevokeSession { id, .. }
+            | Message::ChangePassword { id, .. }
+            | Message::ResetPassword { id, .. }
+            | Message::SignOut { id, .. }
+            | Message::Message { id, .. }
+            | Message::Location { id, .. }
+            | Message::ShareLocation { id, .. }
+            | Message::Status { id, .. }
+            | Message::Seen { id, .. }
+            | Message::LastSeenVisible { id, .. }
+            | Message::Radius { id, .. }
+            | Message::Nearby { id, .. }
+            | Message::AreaInfo { id, .. }
+            | Message::Fences { id, .. }
+            | Message::AddFence { id, .. }
+            | Message::RemoveFence { id, .. }
+            | Message::Channels { id, .. }
+            | Message::ToChannel { id, .. }
+            | Message::FuzzLocation { id, .. }
+            | Message::RouteMode { id, .. }
+            | Message::Global { id, .. }
+            | Message::LocationHistory { id, .. }
+            | Message::MyLocationHistory { id, .. }
+            | Message::Profile { id, .. }
+            | Message::PublishKey { id, .. }
+            | Message::Key { id, .. }
+            | Message::Encrypted { id, .. }
+            | Message::MarkRead { id, .. }
+            | Message::UnreadCounts { id, .. }
+            | Message::DataExport { id, .. }
+            | Message::Settings { id, .. }
+            | Message::History { id, .. }
+            | Message::Pin { id, .. }
+            | Message::CreatePoll { id, .. }
+            | Message::Vote { id, .. }
+            | Message::Report { id, .. }
+            | Message::Reports { id, .. }
+            | Message::Announce { id, .. }
+            | Message::Ban { id, .. }
+            | Message::Stats { id, .. }
+            | Message::StatsHistory { id, .. }
+            | Message::Heatmap { id, .. }
+            | Message::QueryBox { id, .. }
+            | Message::Suspend { id, .. }
+            | Message::BanAddress { id, .. }
+            | Message::AuditLog { id, .. }
+            | Message::Backup { id, .. } => *id,
+        }
+    }
+
+    // The user, role and connection of messages restricted to a role
+    pub fn required_role(&self) -> Option<(usize, Role, usize)> {
+        match self {
+            Message::Reports { user_id, id, .. }
+            | Message::Announce { user_id, id, .. }
+            | Message::Ban { user_id, id, .. }
+            | Message::Stats { user_id, id, .. }
+            | Message::StatsHistory { user_id, id, .. }
+            | Message::Suspend { user_id, id, .. }
+            | Message::BanAddress { user_id, id, .. }
+            | Message::AuditLog { user_id, id, .. }
+            | Message::AddFence { user_id, id, .. }
+            | Message::RemoveFence { user_id, id, .. }
+            | Message::Backup { user_id, id, .. } => Some((*user_id, Role::Admin, *id)),
+            Message::Pin { user_id, id, .. } => Some((*user_id, Role::Moderator, *id)),
+            _ => None,
+        }
+    }
+
+    // Messages that run with every other worker paused, so they see and
+    // leave consistent state
+    pub fn exclusive(&self) -> bool {
+        matches!(self, Message::Backup { .. })
+    }
+
+    // Messages that can be dropped when the workers fall behind
+    pub fn sheddable(&self) -> bool {
+        matches!(self, Message::Location { .. })
+    }
+}
+
+pub struct User {
+    pub id: usize,
+    pub name: String,
+    pub lat: f32,
+    pub lon: f32,
+    pub password: String,
+    pub status: Status,
+    pub role: Role,
+    pub guest: bool,
+    pub bot: bool,
+    pub email: Option<String>,
+    pub verified: bool,
+    pub public_key: Option<String>,
+    pub banned: bool,
+    pub suspension: Option<Suspension>,
+    pub last_seen: Instant,
+    pub last_seen_visible: bool,
+    pub last_read: Option<usize>,
+    pub unread: VecDeque<usize>,
+    pub settings: Settings,
+    pub location_history: bool,
+    pub fuzz_location: bool,
+    // In the world-wide room instead of talking to the users around them
+    pub global: bool,
+    // Messages also go to the users along the recent path
+    pub route_mode: bool,
+    // None until the user sets one
+    pub radius_km: Option<f32>,
+    // Unknown unless the client sends it with the location
+    pub alt: Option<f32>,
+    // Also unknown unless sent, which is taken as an exact location
+    pub accuracy_m: Option<f32>,
+    // Name of the place the user is at, looked up after they move
+    pub place: Option<String>,
+    located_at: Option<Instant>,
+    // Where the user was lately, oldest first, while in route mode
+    route: VecDeque<(Instant, f32, f32)>,
+    sent: HashMap<String, (usize, Instant)>,
+}
+
+impl User {
+    fn new(id: usize, name: String, password: String) -> User {
+        User {
+            id,
+            name,
+            lat: 0.0,
+            lon: 0.0,
+            alt: None,
+            accuracy_m: None,
+            place: None,
+            password,
+            status: Status::Online,
+            role: Role::User,
+            guest: false,
+            bot: false,
+            email: None,
+            verified: true,
+            public_key: None,
+            banned: false,
+            suspension: None,
+            last_seen: Instant::now(),
+            last_seen_visible: true,
+            last_read: None,
+            unread: VecDeque::new(),
+            located_at: None,
+            sent: HashMap::new(),
+            settings: HashMap::new(),
+            location_history: false,
+            fuzz_location: false,
+            global: false,
+            route_mode: false,
+            route: VecDeque::new(),
+            radius_km: None,
+        }
+    }
+
+    // Takes on the stored state of the user
+    fn load(&mut self, record: UserRecord) {
+        self.password = record.password;
+        self.role = record.role;
+        self.status = record.status;
+        self.email = record.email;
+        self.verified = record.verified;
+        self.bot = record.bot;
+        self.public_key = record.public_key;
+        self.banned = record.banned;
+        self.suspension = record.suspension;
+        self.last_seen_visible = record.last_seen_visible;
+        self.settings = record.settings;
+        self.location_history = record.location_history;
+        self.radius_km = record.radius_km;
+        self.fuzz_location = record.fuzz_location;
+    }
+
+    fn record(&self) -> UserRecord {
+        UserRecord {
+            id: self.id,
+            name: self.name.clone(),
+            password: self.password.clone(),
+            role: self.role,
+            status: self.status.clone(),
+            email: self.email.clone(),
+            verified: self.verified,
+            bot: self.bot,
+            public_key: self.public_key.clone(),
+            banned: self.banned,
+            suspension: self.suspension.clone(),
+            last_seen_visible: self.last_seen_visible,
+            settings: self.settings.clone(),
+            location_history: self.location_history,
+            radius_km: self.radius_km,
+            fuzz_location: self.fuzz_location,
+        }
+    }
+
+    // How far off the location may be in km
+    fn uncertainty(&self) -> f32 {
+        self.accuracy_m.unwrap_or(0.0) / 1000.0
+    }
+
+    // Altitude only counts when both users have one
+    fn distance_to(&self, other: &User) -> f32 {
+        let ground = distance(self.lat, self.lon, other.lat, other.lon);
+
+        match (self.alt, other.alt) {
+            (Some(alt), Some(other_alt)) => {
+                let vertical = (alt - other_alt).abs() / 1000.0 * ALTITUDE_WEIGHT;
+                (ground * ground + vertical * vertical).sqrt()
+            }
+            _ => ground,
+        }
+    }
+
+    pub fn area(&self) -> Area {
+        if self.global {
+            GLOBAL_AREA
+        } else {
+            area(self.lat, self.lon)
+        }
+    }
+
+    // Moves the user unless getting there from the last location would have
+    // needed an impossible travel speed. The first location is always accepted.
+    // A jump within the accuracy of either fix isn't taken as travel
+    pub fn relocate(
+        &mut self,
+        lat: f32,
+        lon: f32,
+        alt: Option<f32>,
+        accuracy_m: Option<f32>,
+    ) -> bool {
+        if accuracy_m.is_some_and(|accuracy_m| accuracy_m > MAX_LOCATION_ACCURACY_M) {
+            return false;
+        }
+
+        if let Some(located_at) = self.located_at {
+            let km = distance(self.lat, self.lon, lat, lon);
+            let hours = located_at.elapsed().as_secs_f32() / 3600.0;
+            let jitter = LOCATION_JITTER_KM
+                .max(self.uncertainty())
+                .max(accuracy_m.unwrap_or(0.0) / 1000.0);
+
+            if km > jitter && km / hours > MAX_TRAVEL_SPEED_KMH {
+                return false;
+            }
+        }
+
+        self.lat = lat;
+        self.lon = lon;
+        self.alt = alt;
+        self.accuracy_m = accuracy_m;
+        self.located_at = Some(Instant::now());
+
+        if self.route_mode {
+            let spaced = self.route.back().is_none_or(|&(_, last_lat, last_lon)| {
+                distance(last_lat, last_lon, lat, lon) >= ROUTE_SPACING_KM
+            });
+            if spaced {
+                if self.route.len() >= MAX_ROUTE_POINTS {
+                    self.route.pop_front();
+                }
+                self.route.push_back((Instant::now(), lat, lon));
+            }
+        }
+
+        true
+    }
+
+    pub fn set_route_mode(&mut self, enabled: bool) {
+        self.route_mode = enabled;
+        self.route.clear();
+    }
+
+    // The points of the path within the window, the latest included
+    pub fn route(&self) -> Vec<(f32, f32)> {
+        self.route
+            .iter()
+            .filter(|(at, _, _)| at.elapsed() < ROUTE_WINDOW)
+            .map(|&(_, lat, lon)| (lat, lon))
+            .collect()
+    }
+
+    pub fn unit(&self) -> Unit {
+        match self
+            .settings
+            .get(UNITS_SETTING)
+            .and_then(|unit| unit.as_str())
+        {
+            Some("mi") => Unit::Mi,
+            _ => Unit::Km,
+        }
+    }
+
+    pub fn located(&self) -> bool {
+        self.located_at.is_some()
+    }
+
+    fn located_within(&self, ttl: Duration) -> bool {
+        self.located_at
+            .is_some_and(|located_at| located_at.elapsed() < ttl)
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius_km.unwrap_or(RANGE_KM)
+    }
+
+    // The user's radius, cut down to the reach of a kind of event
+    pub fn reach(&self, radius: Option<f32>) -> f32 {
+        radius.map_or(self.radius(), |radius| radius.min(self.radius()))
+    }
+
+    // Applied all at once or not at all
+    pub fn update_settings(&mut self, update: Settings) -> std::result::Result<(), &'static str> {
+        let mut settings = self.settings.clone();
+        for (key, value) in update {
+            if value.is_null() {
+                settings.remove(&key);
+            } else if key.len() + value.to_string().len() > MAX_SETTING_SIZE {
+                return Err("Setting too large");
+            } else if key == UNITS_SETTING && value != "km" && value != "mi" {
+                return Err("Units must be km or mi");
+            } else {
+                settings.insert(key, value);
+            }
+        }
+
+        if settings.len() > MAX_SETTINGS {
+            return Err("Too many settings");
+        }
+
+        self.settings = settings;
+        Ok(())
+    }
+
+    pub fn add_unread(&mut self, id: usize) {
+        if self.unread.back() == Some(&id) {
+            return;
+        }
+
+        if self.unread.len() >= UNREAD_BACKLOG {
+            self.unread.pop_front();
+        }
+        self.unread.push_back(id);
+    }
+
+    pub fn sent_message(&mut self, client_id: &str) -> Option<usize> {
+        self.sent
+            .retain(|_, &mut (_, sent_at)| sent_at.elapsed() < CLIENT_ID_WINDOW);
+
+        self.sent.get(client_id).map(|&(id, _)| id)
+    }
+
+    pub fn remember_sent(&mut self, client_id: String, id: usize) {
+        self.sent.insert(client_id, (id, Instant::now()));
+    }
+
+    pub fn mark_read(&mut self, id: usize) {
+        if Some(id) > self.last_read {
+            self.last_read = Some(id);
+        }
+        self.unread.retain(|&unread| unread > id);
+    }
+}
+
+#[derive(Clone)]
+pub struct Users {
+    hasher: Hasher,
+    policy: Policy,
+    dummy_hash: Arc<String>,
+    current_id: Arc<AtomicUsize>,
+    users: Arc<CHashMap<usize, User>>,
+    users_by_name: Arc<CHashMap<String, usize>>,
+    // Nicknames of the guests online, which no other guest may take
+    guests_by_name: Arc<CHashMap<String, usize>>,
+    storage: Arc<dyn Storage>,
+    // Writes to storage happen here
+    pool: Pool,
+    // Locations older than this are out of range of everyone
+    location_ttl: Duration,
+}
+
+impl Users {
+    pub fn new(
+        hasher: Hasher,
+        policy: Policy,
+        storage: Arc<dyn Storage>,
+        pool: Pool,
+        location_ttl: Duration,
+    ) -> Self {
+        Users {
+            dummy_hash: Arc::new(hasher.hash(&random_token())),
+            hasher,
+            policy,
+            current_id: Arc::new(AtomicUsize::new(0)),
+            users: Arc::new(CHashMap::new()),
+            users_by_name: Arc::new(CHashMap::new()),
+            guests_by_name: Arc::new(CHashMap::new()),
+            storage,
+            pool,
+            location_ttl,
+        }
+    }
+
+    // Adds users loaded from storage, new users get ids after theirs
+    pub fn restore(&self, records: Vec<UserRecord>) {
+        for record in records {
+            self.current_id.fetch_max(record.id + 1, Ordering::Relaxed);
+
+            match self.users.get_mut(&record.id) {
+                Some(mut user) => user.load(record),
+                None => {
+                    let mut user = User::new(record.id, record.name.clone(), String::new());
+                    user.load(record);
+
+                    self.users_by_name.insert(user.name.clone(), user.id);
+                    self.users.insert(user.id, user);
+                }
+            }
+        }
+    }
+
+    // Picks up a user added or changed by another instance sharing storage,
+    // must not be called while holding a user
+    fn refresh(&self, username: &str) {
+        match self.storage.find_user(username) {
+            Ok(Some(record)) => self.restore(vec![record]),
+            Ok(None) => (),
+            Err(e) => println!("Failed to look up user {}: {}", username, e),
+        }
+    }
+
+    fn next_id(&self) -> usize {
+        match self.storage.next_id() {
+            Ok(Some(id)) => {
+                self.current_id.fetch_max(id + 1, Ordering::Relaxed);
+                return id;
+            }
+            Ok(None) => (),
+            Err(e) => println!("Failed to allocate a user id: {}", e),
+        }
+
+        self.current_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Writes the user through to storage, must not be called while holding the user
+    pub fn persist(&self, id: usize) {
+        let record = match self.users.get(&id) {
+            Some(ref user) if !user.guest => user.record(),
+            _ => return,
+        };
+
+        let storage = self.storage.clone();
+        self.pool.execute_for(user_key(&record.name), move || {
+            if let Err(e) = storage.save_user(&record) {
+                println!("Failed to save user {}: {}", record.name, e);
+            }
+        });
+    }
+
+    // Every registered user, for snapshots
+    pub fn records(&self) -> Vec<UserRecord> {
+        (0..self.current_id.load(Ordering::Relaxed))
+            .filter_map(|id| self.users.get(&id))
+            .filter(|user| !user.guest)
+            .map(|user| user.record())
+            .collect()
+    }
+
+    pub fn contains_username(&self, username: &str) -> bool {
+        self.refresh(username);
+        self.users_by_name.contains_key(username)
+    }
+
+    pub fn add(&self, username: &str, password: &str) -> usize {
+        self.add_with_hash(username, self.hash_password(password))
+    }
+
+    pub fn hash_password(&self, password: &str) -> String {
+        self.hasher.hash(password)
+    }
+
+    pub fn check_password(&self, password: &str) -> std::result::Result<(), PasswordError> {
+        self.policy.check(password)
+    }
+
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        self.hasher.needs_rehash(hash)
+    }
+
+    // The id of the user and whether their hash is outdated. Unknown users and
+    // users without a password are checked against a dummy hash so that they
+    // take as long to reject as a wrong password.
+    pub fn authenticate(&self, username: &str, password: &str) -> Option<(usize, bool)> {
+        self.refresh(username);
+        // The hash is copied out, so the user isn't locked while it is checked
+        let user = self
+            .get_by_name(username)
+            .filter(|user| !user.password.is_empty())
+            .map(|user| (user.id, user.password.clone()));
+        let hash = match user {
+            Some((_, ref hash)) => hash.as_str(),
+            None => self.dummy_hash.as_str(),
+        };
+
+        if !password::verify(password, hash) {
+            return None;
+        }
+
+        user.map(|(id, hash)| (id, self.needs_rehash(&hash)))
+    }
+
+    // An empty hash never verifies, so such users can only log in externally
+    pub fn add_with_hash(&self, username: &str, hash: String) -> usize {
+        let c_id = self.next_id();
+
+        let user = User::new(c_id, username.to_string(), hash);
+
+        self.users.insert(c_id, user);
+        self.users_by_name.insert(username.to_string(), c_id);
+        self.persist(c_id);
+
+        c_id
+    }
+
+    // Guests live only as long as their connection and never claim a username.
+    // None while another guest goes by the nickname.
+    pub fn add_guest(&self, nickname: &str) -> Option<usize> {
+        let c_id = self.next_id();
+
+        let mut reserved = false;
+        self.guests_by_name
+            .alter(nickname.to_string(), |owner| match owner {
+                Some(owner) => Some(owner),
+                None => {
+                    reserved = true;
+                    Some(c_id)
+                }
+            });
+        if !reserved {
+            return None;
+        }
+
+        let mut user = User::new(c_id, nickname.to_string(), String::new());
+        user.guest = true;
+
+        self.users.insert(c_id, user);
+
+        Some(c_id)
+    }
+
+    pub fn remove_guest(&self, id: usize) {
+        let mut nickname = None;
+        self.users.alter(id, |user| match user {
+            Some(user) if user.guest => {
+                nickname = Some(user.name);
+                None
+            }
+            user => user,
+        });
+
+        if let Some(nickname) = nickname {
+            self.guests_by_name
+                .alter(nickname, |owner| owner.filter(|owner| *owner != id));
+        }
+    }
+
+    pub fn get_by_id(&self, id: usize) -> Option<chashmap::ReadGuard<'_, usize, User>> {
+        self.users.get(&id)
+    }
+
+    pub fn get_mut_by_id(&self, id: usize) -> Option<chashmap::WriteGuard<'_, usize, User>> {
+        self.users.get_mut(&id)
+    }
+
+    pub fn get_by_name(&self, username: &str) -> Option<chashmap::ReadGuard<'_, usize, User>> {
+        let user_id = self.users_by_name.get(username);
+        match user_id {
+            Some(user_id) => self.users.get(&user_id),
+            None => None,
+        }
+    }
+
+    pub fn get_mut_by_name(&self, username: &str) -> Option<chashmap::WriteGuard<'_, usize, User>> {
+        let user_id = self.users_by_name.get(username);
+        match user_id {
+            Some(user_id) => self.users.get_mut(&user_id),
+            None => None,
+        }
+    }
+
+    pub fn has_role(&self, id: usize, role: Role) -> bool {
+        match self.users.get(&id) {
+            Some(user) => user.role >= role,
+            None => false,
+        }
+    }
+
+    // The response to give a user that may not log in
+    pub fn restriction(&self, id: usize) -> Option<JsonMessage> {
+        let user = self.users.get(&id)?;
+
+        if user.banned {
+            return Some(JsonMessage::Error {
+                reason: "Account banned".to_string(),
+            });
+        }
+
+        match user.suspension {
+            Some(ref suspension) if suspension.active() => Some(JsonMessage::Suspended {
+                reason: suspension.reason.clone(),
+                until: suspension.until,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    // How far the second user is, in the unit the first one prefers
+    pub fn distance(&self, id_1: usize, id_2: usize) -> Option<Distance> {
+        let user_1 = self.users.get(&id_1)?;
+        let user_2 = self.users.get(&id_2)?;
+
+        Some(Distance::approximate(
+            user_1.distance_to(&user_2),
+            user_1.unit(),
+        ))
+    }
+
+    pub fn located_recently(&self, id: usize) -> bool {
+        self.users
+            .get(&id)
+            .is_some_and(|user| user.located_within(self.location_ttl))
+    }
+
+    // Both users have to be within the other's radius and the given one, and
+    // have sent their location recently. Latitude is compared first as
+    // degrees of it are the same length everywhere. Inaccurate locations are
+    // taken to be as far apart as they could be, so a rough fix doesn't put
+    // anyone in range who may not be.
+    pub fn in_range(&self, id_1: usize, id_2: usize, radius: Option<f32>) -> bool {
+        if let Some(user_1) = self.users.get(&id_1) {
+            if let Some(user_2) = self.users.get(&id_2) {
+                let radius = user_1.reach(radius).min(user_2.radius());
+
+                return user_1.located_within(self.location_ttl)
+                    && user_2.located_within(self.location_ttl)
+                    && (user_1.lat - user_2.lat).abs() * KM_PER_DEGREE_LAT < radius
+                    && user_1.distance_to(&user_2) + user_1.uncertainty() + user_2.uncertainty()
+                        < radius;
+            }
+        }
+
+        false
+    }
+}
+
+pub struct StoredMessage {
+    pub user_id: usize,
+    pub area: Area,
+    pub message: ChatMessage,
+}
+
+#[derive(Clone)]
+pub struct Messages {
+    current_id: Arc<AtomicUsize>,
+    messages: Arc<CHashMap<usize, StoredMessage>>,
+    history: Arc<Mutex<VecDeque<usize>>>,
+    pins: Arc<CHashMap<Area, Vec<ChatMessage>>>,
 }
 
-pub enum Message {
-    Open {
-        server: Server,
-        tx: crossbeam::Sender<usize>,
-    },
-    Close {
-        id: usize,
-        code: ws::CloseCode,
-    },
-    Login {
-        id: usize,
-        username: String,
-        password: String,
-        tx: crossbeam::Sender<JsonMessage>,
-    },
-    Register {
-        id: usize,
-        username: String,
-        password: String,
-        tx: crossbeam::Sender<JsonMessage>,
-    },
-    Message {
+impl Messages {
+    // Ids continue from first_id so they don't clash with stored history
+    pub fn new(first_id: usize) -> Self {
+        Messages {
+            current_id: Arc::new(AtomicUsize::new(first_id)),
+            messages: Arc::new(CHashMap::new()),
+            history: Arc::new(Mutex::new(VecDeque::new())),
+            pins: Arc::new(CHashMap::new()),
+        }
+    }
+
+    // Keeps new ids clear of restored ones
+    pub fn skip_past(&self, id: usize) {
+        self.current_id.fetch_max(id + 1, Ordering::Relaxed);
+    }
+
+    pub fn add(
+        &self,
         user_id: usize,
+        area: Area,
+        username: String,
+        guest: bool,
+        bot: bool,
         msg: String,
-    },
-    Location {
-        user_id: usize,
-        lat: f32,
-        lon: f32,
-    },
+    ) -> ChatMessage {
+        let id = self.current_id.fetch_add(1, Ordering::Relaxed);
+        let message = ChatMessage {
+            id,
+            username,
+            guest,
+            bot,
+            msg,
+        };
+
+        self.messages.insert(
+            id,
+            StoredMessage {
+                user_id,
+                area,
+                message: message.clone(),
+            },
+        );
+
+        let mut history = self.history.lock();
+        history.push_back(id);
+        if history.len() > MESSAGE_BACKLOG {
+            if let Some(oldest) = history.pop_front() {
+                self.messages.remove(&oldest);
+            }
+        }
+
+        message
+    }
+
+    pub fn get(&self, id: usize) -> Option<chashmap::ReadGuard<'_, usize, StoredMessage>> {
+        self.messages.get(&id)
+    }
+
+    pub fn pin(&self, id: usize) -> bool {
+        let (area, message) = match self.messages.get(&id) {
+            Some(stored) => (stored.area, stored.message.clone()),
+            None => return false,
+        };
+
+        let mut pinned = true;
+        self.pins.upsert(
+            area,
+            || vec![message.clone()],
+            |pins| {
+                if pins.iter().any(|pin| pin.id == id) {
+                    return;
+                }
+
+                if pins.len() >= MAX_PINS {
+                    pinned = false;
+                } else {
+                    pins.push(message.clone());
+                }
+            },
+        );
+
+        pinned
+    }
+
+    pub fn unpin(&self, id: usize) -> bool {
+        let area = match self.messages.get(&id) {
+            Some(stored) => stored.area,
+            None => return false,
+        };
+
+        match self.pins.get_mut(&area) {
+            Some(ref mut pins) => {
+                let len = pins.len();
+                pins.retain(|pin| pin.id != id);
+                pins.len() != len
+            }
+            None => false,
+        }
+    }
+
+    pub fn pinned(&self, area: Area) -> Vec<ChatMessage> {
+        match self.pins.get(&area) {
+            Some(pins) => pins.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.history.lock().len()
+    }
+
+    // Oldest first
+    pub fn by_user(&self, user_id: usize) -> Vec<ChatMessage> {
+        self.history
+            .lock()
+            .iter()
+            .filter_map(|id| self.messages.get(id))
+            .filter(|stored| stored.user_id == user_id)
+            .map(|stored| stored.message.clone())
+            .collect()
+    }
 }
 
-pub struct User {
-    pub id: usize,
-    pub name: String,
-    pub lat: f32,
-    pub lon: f32,
-    pub password: String,
+// How far each kind of event goes at most, on top of the radii of the users.
+// Announcements go to everyone when they have no limit.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Reach {
+    pub message_km: Option<f32>,
+    pub whisper_km: Option<f32>,
+    pub announcement_km: Option<f32>,
+    // Coming into and going out of range
+    pub presence_km: Option<f32>,
 }
 
-impl User {
-    fn new(id: usize, name: String, password: String) -> User {
-        User {
-            id,
-            name,
-            lat: 0.0,
-            lon: 0.0,
-            password,
+impl Default for Reach {
+    fn default() -> Reach {
+        Reach {
+            message_km: None,
+            whisper_km: Some(0.1),
+            announcement_km: None,
+            presence_km: None,
         }
     }
+}
 
-    fn distance_to(&self, other: &User) -> f32 {
-        let mut ph1: f32 = self.lat - other.lat;
-        let mut th1: f32 = self.lon;
-        let mut th2: f32 = other.lon;
+// Bounds of the radius users can set for themselves
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct RadiusLimits {
+    pub min_km: f32,
+    pub max_km: f32,
+}
+
+impl Default for RadiusLimits {
+    fn default() -> RadiusLimits {
+        RadiusLimits {
+            min_km: 0.5,
+            max_km: 50.0,
+        }
+    }
+}
+
+impl RadiusLimits {
+    pub fn clamp(&self, km: f32) -> f32 {
+        km.max(self.min_km).min(self.max_km)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct SessionLimits {
+    pub max_lifetime_secs: u64,
+    // Sessions lapse after this long without messages from any of their connections
+    pub idle_timeout_secs: u64,
+    // How long before lapsing connections are sent SessionExpiring
+    pub warning_secs: u64,
+}
+
+impl Default for SessionLimits {
+    fn default() -> SessionLimits {
+        SessionLimits {
+            max_lifetime_secs: 30 * 24 * 60 * 60,
+            idle_timeout_secs: 7 * 24 * 60 * 60,
+            warning_secs: 5 * 60,
+        }
+    }
+}
+
+pub struct Session {
+    pub user_id: usize,
+    // Seconds since the unix epoch
+    pub created: u64,
+    pub last_active: u64,
+    warned: bool,
+    // When the session was last written to shared state
+    shared_at: u64,
+}
+
+impl Session {
+    fn new(user_id: usize, created: u64, last_active: u64) -> Session {
+        Session {
+            user_id,
+            created,
+            last_active,
+            warned: false,
+            shared_at: last_active,
+        }
+    }
 
-        ph1 = ph1.to_radians();
-        th1 = th1.to_radians();
-        th2 = th2.to_radians();
-        let dz: f32 = th1.sin() - th2.sin();
-        let dx: f32 = ph1.cos() * th1.cos() - th2.cos();
-        let dy: f32 = ph1.sin() * th1.cos();
-        ((dx * dx + dy * dy + dz * dz).sqrt() / 2.0).asin() * 2.0 * 6372.8
+    fn expires(&self, limits: &SessionLimits) -> u64 {
+        std::cmp::min(
+            self.created + limits.max_lifetime_secs,
+            self.last_active + limits.idle_timeout_secs,
+        )
     }
 
-    fn within_bounds(&self, other: &User, diff: f32) -> bool {
-        (self.lat - other.lat).abs() < diff && (self.lon - other.lon).abs() < diff
+    fn shared(&self) -> SharedSession {
+        SharedSession {
+            user_id: self.user_id,
+            created: self.created,
+            last_active: self.last_active,
+        }
     }
 }
 
+pub enum Expiry {
+    Valid,
+    // Reported once per session, until activity pushes the expiry back out
+    Expiring(Duration),
+    Expired,
+}
+
 #[derive(Clone)]
-pub struct Users {
-    current_id: Arc<AtomicUsize>,
-    users: Arc<CHashMap<usize, User>>,
-    users_by_name: Arc<CHashMap<String, usize>>,
+pub struct Sessions {
+    limits: SessionLimits,
+    sessions: Arc<CHashMap<String, Session>>,
+    connections: Arc<CHashMap<usize, String>>,
+    shared: Option<Shared>,
 }
 
-impl Users {
+impl Sessions {
+    pub fn new(limits: SessionLimits, shared: Option<Shared>) -> Self {
+        Sessions {
+            limits,
+            sessions: Arc::new(CHashMap::new()),
+            connections: Arc::new(CHashMap::new()),
+            shared,
+        }
+    }
+
+    fn share(&self, token: &str, session: &Session) {
+        if let Some(ref shared) = self.shared {
+            let ttl = session.expires(&self.limits).saturating_sub(unix_time());
+            shared.save_session(token, &session.shared(), ttl);
+        }
+    }
+
+    pub fn create(&self, id: usize, user_id: usize) -> String {
+        let now = unix_time();
+        let token = random_token();
+        let session = Session::new(user_id, now, now);
+        self.share(&token, &session);
+        self.sessions.insert(token.clone(), session);
+        self.connections.insert(id, token.clone());
+
+        token
+    }
+
+    // Shared state decides when configured, so sessions created or ended by
+    // other instances are respected
+    pub fn resume(&self, id: usize, token: &str) -> Option<usize> {
+        if let Some(ref shared) = self.shared {
+            match shared.load_session(token) {
+                Some(shared) => {
+                    if !self.sessions.contains_key(token) {
+                        self.sessions.insert(
+                            token.to_string(),
+                            Session::new(shared.user_id, shared.created, shared.last_active),
+                        );
+                    }
+                }
+                None => {
+                    self.sessions.remove(token);
+                    return None;
+                }
+            }
+        }
+
+        let user_id = match self.sessions.get(token) {
+            Some(ref session) if session.expires(&self.limits) > unix_time() => session.user_id,
+            _ => return None,
+        };
+        self.connections.insert(id, token.to_string());
+        self.touch(id);
+
+        Some(user_id)
+    }
+
+    // Slides the idle timeout of the session bound to a connection
+    pub fn touch(&self, id: usize) {
+        let token = match self.connections.get(&id) {
+            Some(token) => token.clone(),
+            None => return,
+        };
+
+        if let Some(ref mut session) = self.sessions.get_mut(&token) {
+            let now = unix_time();
+            session.last_active = now;
+
+            if session.expires(&self.limits) > now + self.limits.warning_secs {
+                session.warned = false;
+            }
+
+            // Shared state only needs to be roughly as fresh as the warning period
+            if now >= session.shared_at + SHARED_SESSION_INTERVAL {
+                session.shared_at = now;
+                self.share(&token, session);
+            }
+        }
+    }
+
+    // Connections without a session, like guests and bots, are always valid
+    pub fn expiry(&self, id: usize) -> Expiry {
+        let token = match self.connections.get(&id) {
+            Some(token) => token.clone(),
+            None => return Expiry::Valid,
+        };
+
+        if let Some(ref shared) = self.shared {
+            if shared.session_ended(&token) {
+                self.sessions.remove(&token);
+            }
+        }
+
+        let mut session = match self.sessions.get_mut(&token) {
+            Some(session) => session,
+            None => return Expiry::Expired,
+        };
+
+        let now = unix_time();
+        let expires = session.expires(&self.limits);
+
+        if expires <= now {
+            Expiry::Expired
+        } else if !session.warned && expires <= now + self.limits.warning_secs {
+            session.warned = true;
+            Expiry::Expiring(Duration::from_secs(expires - now))
+        } else {
+            Expiry::Valid
+        }
+    }
+
+    // Invalidates the session bound to a connection
+    pub fn end(&self, id: usize) -> bool {
+        let token = match self.connections.remove(&id) {
+            Some(token) => token,
+            None => return false,
+        };
+
+        if let Some(ref shared) = self.shared {
+            shared.end_session(&token);
+        }
+
+        self.sessions.remove(&token).is_some()
+    }
+
+    // Invalidates every session of a user except the one bound to `id`
+    pub fn end_others(&self, id: usize, user_id: usize) {
+        let keep = self.connections.get(&id).map(|token| token.clone());
+
+        if let Some(ref shared) = self.shared {
+            shared.end_user_sessions(user_id, keep.as_deref());
+        }
+
+        self.sessions
+            .retain(|token, session| session.user_id != user_id || keep.as_ref() == Some(token));
+        self.connections
+            .retain(|_, token| self.sessions.contains_key(token));
+    }
+
+    pub fn end_all(&self, user_id: usize) {
+        if let Some(ref shared) = self.shared {
+            shared.end_user_sessions(user_id, None);
+        }
+
+        self.sessions
+            .retain(|_, session| session.user_id != user_id);
+        self.connections
+            .retain(|_, token| self.sessions.contains_key(token));
+    }
+
+    // Forgets sessions past their expiry, which no one can resume anymore
+    pub fn sweep(&self) {
+        let now = unix_time();
+        self.sessions
+            .retain(|_, session| session.expires(&self.limits) > now);
+    }
+
+    // Unbinds a closed connection, leaving its session valid for Resume
+    pub fn disconnect(&self, id: usize) {
+        self.connections.remove(&id);
+    }
+}
+
+#[derive(Clone)]
+pub struct PasswordResets {
+    resets: Arc<CHashMap<String, (usize, Instant)>>,
+}
+
+impl PasswordResets {
     pub fn new() -> Self {
-        Users {
-            current_id: Arc::new(AtomicUsize::new(0)),
-            users: Arc::new(CHashMap::new()),
-            users_by_name: Arc::new(CHashMap::new()),
+        PasswordResets {
+            resets: Arc::new(CHashMap::new()),
         }
     }
 
-    pub fn contains_username(&self, username: &str) -> bool {
-        self.users_by_name.contains_key(username)
+    pub fn create(&self, user_id: usize) -> String {
+        let now = Instant::now();
+        self.resets.retain(|_, &(_, expires)| expires > now);
+
+        let token = random_token();
+        self.resets
+            .insert(token.clone(), (user_id, now + RESET_DURATION));
+
+        token
     }
 
-    pub fn add(&self, username: &str, password: &str) -> usize {
-        let c_id = self.current_id.fetch_add(1, Ordering::Relaxed);
+    // Tokens are single use, so they are removed whether or not they have expired
+    pub fn consume(&self, token: &str) -> Option<usize> {
+        match self.resets.remove(token) {
+            Some((user_id, expires)) if expires > Instant::now() => Some(user_id),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct EmailVerifications {
+    codes: Arc<CHashMap<usize, (String, Instant)>>,
+}
+
+impl EmailVerifications {
+    pub fn new() -> Self {
+        EmailVerifications {
+            codes: Arc::new(CHashMap::new()),
+        }
+    }
 
-        let user = User::new(
-            c_id,
-            username.to_string(),
-            pbkdf2::pbkdf2_simple(&password, PBKDF2_ITERATIONS).unwrap(),
+    pub fn create(&self, user_id: usize) -> String {
+        let code = random_token();
+        self.codes.insert(
+            user_id,
+            (code.clone(), Instant::now() + VERIFICATION_DURATION),
         );
 
-        self.users.insert(c_id, user);
-        self.users_by_name.insert(username.to_string(), c_id);
+        code
+    }
 
-        c_id
+    pub fn consume(&self, user_id: usize, code: &str) -> bool {
+        let valid = match self.codes.get(&user_id) {
+            Some(entry) => entry.0 == code && entry.1 > Instant::now(),
+            None => false,
+        };
+
+        if valid {
+            self.codes.remove(&user_id);
+        }
+
+        valid
     }
+}
 
-    pub fn get_by_id(&self, id: usize) -> Option<chashmap::ReadGuard<'_, usize, User>> {
-        self.users.get(&id)
+#[derive(Clone)]
+pub struct Nonces {
+    nonces: Arc<CHashMap<String, (usize, Instant)>>,
+}
+
+impl Nonces {
+    pub fn new() -> Self {
+        Nonces {
+            nonces: Arc::new(CHashMap::new()),
+        }
     }
 
-    pub fn get_mut_by_id(&self, id: usize) -> Option<chashmap::WriteGuard<'_, usize, User>> {
-        self.users.get_mut(&id)
+    pub fn create(&self, id: usize) -> String {
+        let now = Instant::now();
+        self.nonces.retain(|_, &(_, expires)| expires > now);
+
+        let nonce = random_token();
+        self.nonces
+            .insert(nonce.clone(), (id, now + NONCE_DURATION));
+
+        nonce
     }
 
-    pub fn get_by_name(&self, username: &str) -> Option<chashmap::ReadGuard<'_, usize, User>> {
-        let user_id = self.users_by_name.get(username);
-        match user_id {
-            Some(user_id) => self.users.get(&user_id),
-            None => None,
+    // A nonce is single use and only valid on the connection it was issued to
+    pub fn consume(&self, id: usize, nonce: &str) -> bool {
+        match self.nonces.remove(nonce) {
+            Some((owner, expires)) => owner == id && expires > Instant::now(),
+            None => false,
         }
     }
+}
 
-    pub fn in_range(&self, id_1: usize, id_2: usize) -> bool {
-        if let Some(user_1) = self.users.get(&id_1) {
-            if let Some(user_2) = self.users.get(&id_2) {
-                return user_1.within_bounds(&user_2, RANGE_LATLON)
-                    && user_1.distance_to(&user_2) < RANGE_KM;
+enum Change {
+    Update(usize, Server),
+    Empty(usize),
+}
+
+// Makes the changes to the connections on a thread of its own, so workers
+// never wait on each other to make one
+pub struct ServersWriter {
+    handle: evmap::handles::WriteHandle<usize, Server>,
+    changes: Receiver<Change>,
+    // Changes readers don't see yet, None for connections that are gone
+    pending: Arc<CHashMap<usize, Option<Server>>>,
+}
+
+impl ServersWriter {
+    pub fn run(mut self) {
+        let mut applied = Vec::new();
+        let mut deadline: Option<Instant> = None;
+
+        loop {
+            let change = match deadline {
+                Some(deadline) => {
+                    match self
+                        .changes
+                        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    {
+                        Ok(change) => Some(change),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                None => match self.changes.recv() {
+                    Ok(change) => Some(change),
+                    Err(_) => return,
+                },
+            };
+
+            let timed_out = change.is_none();
+            if let Some(change) = change {
+                applied.push(match change {
+                    Change::Update(id, server) => {
+                        self.handle.update(id, server.clone());
+                        (id, Some(server))
+                    }
+                    Change::Empty(id) => {
+                        self.handle.remove_entry(id);
+                        (id, None)
+                    }
+                });
+                deadline = deadline.or_else(|| Some(Instant::now() + REFRESH_INTERVAL));
+            }
+
+            if timed_out || applied.len() >= REFRESH_BATCH {
+                self.refresh(&mut applied);
+                deadline = None;
             }
         }
+    }
 
-        false
+    // Connections changed again since stay pending
+    fn refresh(&mut self, applied: &mut Vec<(usize, Option<Server>)>) {
+        self.handle.publish();
+        for (id, server) in applied.drain(..) {
+            self.pending
+                .alter(id, |pending| pending.filter(|pending| *pending != server));
+        }
+    }
+}
+
+// Connections of logged in users listed by the area the user is in, and in
+// the global room for those in it, so a message only goes through the lists
+// it can reach
+#[derive(Default)]
+struct Subscriptions {
+    areas: HashMap<Area, HashMap<usize, (usize, Socket)>>,
+    global: HashMap<usize, (usize, Socket)>,
+    // Where each connection is listed
+    listed: HashMap<usize, (Area, bool)>,
+}
+
+impl Subscriptions {
+    fn list(&mut self, id: usize, user_id: usize, socket: Socket, area: Area, global: bool) {
+        if self.listed.get(&id) == Some(&(area, global)) {
+            return;
+        }
+
+        self.unlist(id);
+        if global {
+            self.global.insert(id, (user_id, socket.clone()));
+        }
+        self.areas
+            .entry(area)
+            .or_default()
+            .insert(id, (user_id, socket));
+        self.listed.insert(id, (area, global));
+    }
+
+    fn unlist(&mut self, id: usize) {
+        if let Some((area, _)) = self.listed.remove(&id) {
+            if let Some(list) = self.areas.get_mut(&area) {
+                list.remove(&id);
+                if list.is_empty() {
+                    self.areas.remove(&area);
+                }
+            }
+            self.global.remove(&id);
+        }
+    }
+
+    // The lists of the areas a circle reaches into. When it covers more areas
+    // than have anyone in them, those are gone through instead.
+    fn around(&self, lat: f32, lon: f32, radius_km: f32) -> Vec<&HashMap<usize, (usize, Socket)>> {
+        let lat_span = radius_km / KM_PER_DEGREE_LAT;
+        // Degrees of longitude are shortest at the edge nearest a pole
+        let edge = (lat.abs() + lat_span).min(90.0).to_radians().cos();
+        let lon_span = lat_span / edge.max(0.01);
+
+        let (min_lat, min_lon) = area(lat - lat_span, lon - lon_span);
+        let (max_lat, max_lon) = area(lat + lat_span, lon + lon_span);
+        let all_lons = max_lon - min_lon + 1 >= LON_AREAS;
+        let lon_areas = if all_lons {
+            LON_AREAS
+        } else {
+            max_lon - min_lon + 1
+        };
+
+        let count = (max_lat - min_lat + 1) as usize * lon_areas as usize;
+        if count > self.areas.len() {
+            return self
+                .areas
+                .iter()
+                .filter(|&(&(a_lat, a_lon), _)| {
+                    a_lat >= min_lat
+                        && a_lat <= max_lat
+                        && (all_lons || (a_lon - min_lon).rem_euclid(LON_AREAS) < lon_areas)
+                })
+                .map(|(_, list)| list)
+                .collect();
+        }
+
+        let mut lists = Vec::new();
+        for a_lat in min_lat..=max_lat {
+            for a_lon in min_lon..min_lon + lon_areas {
+                let a_lon = (a_lon + LON_AREAS / 2).rem_euclid(LON_AREAS) - LON_AREAS / 2;
+                if let Some(list) = self.areas.get(&(a_lat, a_lon)) {
+                    lists.push(list);
+                }
+            }
+        }
+
+        lists
     }
 }
 
 #[derive(Clone)]
 pub struct Servers {
     current_id: Arc<AtomicUsize>,
-    reader: evmap::ReadHandle<usize, Server>,
-    writer: Arc<Mutex<evmap::WriteHandle<usize, Server>>>,
+    reader: evmap::handles::ReadHandle<usize, Server>,
+    changes: Sender<Change>,
+    pending: Arc<CHashMap<usize, Option<Server>>>,
+    // Connections of every logged in user
+    by_user: Arc<CHashMap<usize, Vec<usize>>>,
+    subscriptions: Arc<RwLock<Subscriptions>>,
 }
 
-impl Servers {
-    pub fn new() -> Self {
-        let (reader, writer) = evmap::new();
-        Servers {
-            current_id: Arc::new(AtomicUsize::new(0)),
-            reader,
-            writer: Arc::new(Mutex::new(writer)),
+impl Servers {
+    pub fn new() -> (Servers, ServersWriter) {
+        // Server's Hash and Eq only look at the socket token, which never changes
+        let (handle, reader) = unsafe { evmap::new_assert_stable() };
+        let (tx, rx) = unbounded();
+        let pending = Arc::new(CHashMap::new());
+
+        (
+            Servers {
+                // 0 is left for connections that were never opened
+                current_id: Arc::new(AtomicUsize::new(1)),
+                reader,
+                changes: tx,
+                pending: pending.clone(),
+                by_user: Arc::new(CHashMap::new()),
+                subscriptions: Arc::new(RwLock::new(Subscriptions::default())),
+            },
+            ServersWriter {
+                handle,
+                changes: rx,
+                pending,
+            },
+        )
+    }
+
+    // Only the users within the sender's radius, or the given one when it is
+    // smaller, are checked
+    pub fn for_each_in_range<F>(&self, users: &Users, user_id: usize, radius: Option<f32>, mut f: F)
+    where
+        F: FnMut(&Socket, usize),
+    {
+        let (lat, lon, reach) = match users.get_by_id(user_id) {
+            Some(user) => (user.lat, user.lon, user.reach(radius)),
+            None => return,
+        };
+
+        let subscriptions = self.subscriptions.read();
+        for list in subscriptions.around(lat, lon, reach) {
+            for &(user_id_other, ref socket) in list.values() {
+                // The sender gets their own message even with a stale location
+                if user_id_other == user_id || users.in_range(user_id, user_id_other, radius) {
+                    f(socket, user_id_other);
+                }
+            }
+        }
+    }
+
+    pub fn for_each_global<F>(&self, mut f: F)
+    where
+        F: FnMut(&Socket, usize),
+    {
+        for &(user_id, ref socket) in self.subscriptions.read().global.values() {
+            f(socket, user_id);
         }
     }
 
-    /*
-    fn write(&self) -> MutexGuard<'_, evmap::WriteHandle<usize, Server>, > {
-        self.writer.lock()
+    // Lists the connections of the user under the area they are in, and in
+    // the global room when they are in it
+    pub fn subscribe(&self, user_id: usize, area: Area, global: bool) {
+        let ids = match self.by_user.get(&user_id) {
+            Some(ids) => ids.clone(),
+            None => return,
+        };
+
+        let servers = ids
+            .into_iter()
+            .filter_map(|id| self.get(id))
+            .collect::<Vec<_>>();
+        let mut subscriptions = self.subscriptions.write();
+        for server in servers {
+            subscriptions.list(server.id, user_id, server.socket, area, global);
+        }
     }
-    */
 
-    pub fn read(&self) -> &evmap::ReadHandle<usize, Server> {
-        &self.reader
+    // Queues the response on the connection, if it's still open
+    pub fn send_to(&self, id: usize, response: &JsonMessage) {
+        if let Some(server) = self.get(id) {
+            server.send(response);
+        }
     }
 
     pub fn update(&self, id: usize, server: Server) {
-        self.writer.lock().update(id, server).refresh();
+        self.pending.insert(id, Some(server.clone()));
+        let _ = self.changes.send(Change::Update(id, server));
     }
 
     pub fn empty(&self, id: usize) {
-        self.writer.lock().empty(id).refresh();
+        self.subscriptions.write().unlist(id);
+        if let Some(server) = self.get(id) {
+            if let Some(user_id) = *server.user_id.read() {
+                self.unlink(id, user_id);
+            }
+        }
+
+        self.pending.insert(id, None);
+        let _ = self.changes.send(Change::Empty(id));
+    }
+
+    fn unlink(&self, id: usize, user_id: usize) {
+        self.by_user.alter(user_id, |ids| {
+            ids.map(|ids| {
+                ids.into_iter()
+                    .filter(|&other| other != id)
+                    .collect::<Vec<_>>()
+            })
+            .filter(|ids| !ids.is_empty())
+        });
     }
 
     pub fn get_next_id(&self) -> usize {
         self.current_id.fetch_add(1, Ordering::Relaxed)
     }
 
+    pub fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(&Server),
+    {
+        if let Some(servers) = self.reader.enter() {
+            for (_, servers) in &servers {
+                if let Some(server) = servers.get_one() {
+                    f(server);
+                }
+            }
+        }
+    }
+
+    pub fn find_by_user(&self, user_id: usize) -> Vec<Server> {
+        let ids = match self.by_user.get(&user_id) {
+            Some(ids) => ids.clone(),
+            None => return Vec::new(),
+        };
+
+        ids.into_iter().filter_map(|id| self.get(id)).collect()
+    }
+
+    pub fn set_user(&self, id: usize, user_id: Option<usize>) {
+        if let Some(server) = self.get(id) {
+            let previous = mem::replace(&mut *server.user_id.write(), user_id);
+            if let Some(previous) = previous {
+                self.unlink(id, previous);
+                self.subscriptions.write().unlist(id);
+            }
+            if let Some(user_id) = user_id {
+                self.by_user
+                    .upsert(user_id, || vec![id], |ids| ids.push(id));
+            }
+
+            self.update(id, server);
+        }
+    }
+
+    // Connections opened since the last refresh are only found in the
+    // pending changes, while ones closed since are still returned until then
     pub fn get(&self, id: usize) -> Option<Server> {
         self.reader
-            .get_and(&id, |rs| match rs.first() {
-                Some(rs) => Some(rs.clone()),
-                _ => None,
-            })
-            .unwrap_or_else(|| None)
+            .get_one(&id)
+            .map(|server| server.clone())
+            .or_else(|| self.pending.get(&id).and_then(|server| server.clone()))
     }
 
     pub fn len(&self) -> usize {
@@ -212,8 +2686,18 @@ impl Servers {
 pub struct Server {
     pub id: usize,
     pub user_id: Arc<RwLock<Option<usize>>>,
-    pub socket: ws::Sender,
-    pub channel: crossbeam::Sender<Message>,
+    pub socket: Socket,
+    pub channel: Queue,
+    pub started: Instant,
+    pub addr: Option<String>,
+    pub connected_at: u64,
+    pub user_agent: Option<String>,
+    pub origin_policy: Arc<OriginPolicy>,
+    pub addr_bans: AddrBans,
+    // Only these may say which address a connection is for
+    pub trusted_proxies: TrustedProxies,
+    // Location updates past this are dropped
+    pub location_rate: Arc<Mutex<TokenBucket>>,
 }
 
 impl Eq for Server {}
@@ -224,42 +2708,142 @@ impl PartialEq for Server {
     }
 }
 
-impl evmap::ShallowCopy for Server {
-    unsafe fn shallow_copy(&mut self) -> Self {
-        Server {
-            id: self.id,
-            user_id: self.user_id.clone(),
-            socket: self.socket.clone(),
-            channel: self.channel.clone(),
+impl Hash for Server {
+    fn hash<H: hash::Hasher>(&self, state: &mut H) {
+        self.socket.token().hash(state);
+    }
+}
+
+// Serializes into a buffer kept by a worker, so a payload costs a single
+// allocation however many are made
+#[derive(Default)]
+pub struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn encode<T: Serialize>(&mut self, value: &T) -> Option<Payload> {
+        self.buf.clear();
+        serde_json::to_writer(&mut self.buf, value).ok()?;
+
+        std::str::from_utf8(&self.buf).ok().map(Payload::from)
+    }
+
+    // Several values go in a JSON array, so every frame is still a single
+    // JSON value. A single one goes on its own, as in any other frame.
+    pub fn encode_batch<T: Serialize>(&mut self, values: &[T]) -> Option<Payload> {
+        match values {
+            [value] => self.encode(value),
+            values => {
+                self.buf.clear();
+                serde_json::to_writer(&mut self.buf, values).ok()?;
+
+                std::str::from_utf8(&self.buf).ok().map(Payload::from)
+            }
         }
     }
 }
 
-impl Handler for Server {
-    fn on_open(&mut self, _shake: Handshake) -> Result<()> {
-        let (tx, rx) = unbounded();
+impl Server {
+    fn send(&self, response: &JsonMessage) {
+        if let Ok(json) = serde_json::to_string(response) {
+            let _ = self.socket.send(json);
+        }
+    }
+}
+
+// Called from the task of the connection
+impl Server {
+    pub fn on_open(&mut self, request: &Request, peer: SocketAddr) -> Result<()> {
+        // Stops other sites from riding a browser's logged in session
+        if !self.origin_policy.allows(request) {
+            return self.socket.close(CloseCode::Policy);
+        }
+
+        let addr = socket::remote_addr(request, peer, &self.trusted_proxies);
+        self.addr = Some(addr.to_string());
+        self.connected_at = unix_time();
+        self.user_agent = request.headers().get("User-Agent").map(|agent| {
+            String::from_utf8_lossy(agent.as_bytes())
+                .chars()
+                .take(MAX_USER_AGENT_LENGTH)
+                .collect()
+        });
+
+        // The address the connection comes from is checked as well as the
+        // one a trusted proxy forwards it for, so a banned proxy is refused
+        if self.addr_bans.bans(&peer.ip()) || self.addr_bans.bans(&addr) {
+            return self.socket.close(CloseCode::Policy);
+        }
+
+        let (tx, rx) = bounded(1);
         let _ = self.channel.send(Message::Open {
             server: self.clone(),
             tx,
         });
 
-        if let Ok(id) = rx.recv() {
-            self.id = id;
+        match rx.recv() {
+            Ok(Some(id)) => self.id = id,
+            Ok(None) => self.socket.close(CloseCode::Policy)?,
+            Err(_) => (),
         }
 
         Ok(())
     }
 
-    fn on_message(&mut self, msg: ws::Message) -> Result<()> {
-        let (tx, rx) = unbounded();
+    pub fn on_message(&mut self, msg: Frame) -> Result<()> {
+        if let Ok(s) = msg.to_text() {
+            let val = match serde_json::from_str(s) {
+                Ok(val) => val,
+                Err(_) => return Ok(()),
+            };
+            if let Some((field, max)) = oversized_field(&val, "") {
+                self.send(&JsonMessage::LimitExceeded { field, max });
+                return Ok(());
+            }
 
-        if let Ok(s) = msg.as_text() {
-            if let Ok(val) = serde_json::from_str(s) {
+            if let Ok(val) = serde_json::from_value(val) {
                 let val: JsonMessage = val;
+
+                if let Some(user_id) = *self.user_id.read() {
+                    let _ = self.channel.send(Message::Seen {
+                        id: self.id,
+                        user_id,
+                    });
+                }
+
                 match val {
-                    JsonMessage::Location { lat, lon } => {
-                        if let Some(user_id) = *self.user_id.read() {
-                            let _ = self.channel.send(Message::Location { user_id, lat, lon });
+                    JsonMessage::Location {
+                        lat,
+                        lon,
+                        alt,
+                        geometry,
+                        accuracy_m,
+                    } => {
+                        if !self.location_rate.lock().take() {
+                            return Ok(());
+                        }
+
+                        let location = match (geometry, lat, lon) {
+                            (Some(geometry), _, _) => geometry.lat_lon_alt(),
+                            (None, Some(lat), Some(lon)) => Some((lat, lon, alt)),
+                            _ => None,
+                        };
+
+                        if let (Some(user_id), Some((lat, lon, alt))) =
+                            (*self.user_id.read(), location)
+                        {
+                            let _ = self.channel.send(Message::Location {
+                                id: self.id,
+                                user_id,
+                                lat,
+                                lon,
+                                alt: alt
+                                    .filter(|alt| *alt >= MIN_ALTITUDE_M && *alt <= MAX_ALTITUDE_M),
+                                accuracy_m: accuracy_m.filter(|accuracy_m| {
+                                    accuracy_m.is_finite() && *accuracy_m >= 0.0
+                                }),
+                            });
                         }
                     }
                     JsonMessage::Login { username, password } => {
@@ -267,36 +2851,594 @@ impl Handler for Server {
                             id: self.id,
                             username,
                             password,
-                            tx,
                         });
-
-                        if let Ok(response) = rx.recv() {
-                            if let Ok(json) = serde_json::to_string(&response) {
-                                let _ = self.socket.send(json);
-                            }
-                        }
                     }
-                    JsonMessage::Register { username, password } => {
+                    JsonMessage::Register {
+                        username,
+                        password,
+                        email,
+                        proof,
+                    } => {
                         let _ = self.channel.send(Message::Register {
                             id: self.id,
                             username,
                             password,
-                            tx,
+                            email,
+                            proof,
                         });
+                    }
+                    JsonMessage::LoginToken { jwt } => {
+                        let _ = self.channel.send(Message::LoginToken { id: self.id, jwt });
+                    }
+                    JsonMessage::GuestLogin { nickname } => {
+                        let _ = self.channel.send(Message::GuestLogin {
+                            id: self.id,
+                            nickname,
+                        });
+                    }
+                    JsonMessage::LoginOAuth { provider, token } => {
+                        let _ = self.channel.send(Message::LoginOAuth {
+                            id: self.id,
+                            provider,
+                            token,
+                        });
+                    }
+                    JsonMessage::BotLogin { key } => {
+                        let _ = self.channel.send(Message::BotLogin { id: self.id, key });
+                    }
+                    JsonMessage::Resume { token } => {
+                        let _ = self.channel.send(Message::Resume { id: self.id, token });
+                    }
+                    JsonMessage::Logout => {
+                        let _ = self.channel.send(Message::Logout { id: self.id });
+                    }
+                    JsonMessage::ListSessions => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::ListSessions {
+                                id: self.id,
+                                user_id,
+                            });
+                        }
+                    }
+                    JsonMessage::RevokeSession { session_id } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::RevokeSession {
+                                id: self.id,
+                                user_id,
+                                session_id: Some(session_id),
+                            });
+                        }
+                    }
+                    JsonMessage::RevokeAllSessions => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::RevokeSession {
+                                id: self.id,
+                                user_id,
+                                session_id: None,
+                            });
+                        }
+                    }
+                    JsonMessage::GetNonce => {
+                        let _ = self.channel.send(Message::Nonce { id: self.id });
+                    }
+                    JsonMessage::ChangePassword { old, new, nonce } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::ChangePassword {
+                                id: self.id,
+                                user_id,
+                                old,
+                                new,
+                                nonce,
+                            });
+                        }
+                    }
+                    JsonMessage::VerifyEmail { code } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::VerifyEmail {
+                                id: self.id,
+                                user_id,
+                                code,
+                            });
+                        }
+                    }
+                    JsonMessage::ResetPassword {
+                        token,
+                        new_password,
+                    } => {
+                        let _ = self.channel.send(Message::ResetPassword {
+                            id: self.id,
+                            token,
+                            new_password,
+                        });
+                    }
+                    JsonMessage::SendMessage {
+                        msg,
+                        client_id,
+                        whisper,
+                    } => {
+                        let valid = msg.len() <= MAX_MESSAGE_LENGTH
+                            && client_id
+                                .iter()
+                                .all(|client_id| client_id.len() <= MAX_CLIENT_ID_LENGTH);
 
-                        if let Ok(response) = rx.recv() {
-                            if let Ok(json) = serde_json::to_string(&response) {
-                                let _ = self.socket.send(json);
+                        if valid {
+                            if let Some(user_id) = *self.user_id.read() {
+                                let _ = self.channel.send(Message::Message {
+                                    id: self.id,
+                                    user_id,
+                                    msg,
+                                    client_id,
+                                    whisper,
+                                });
                             }
                         }
                     }
-                    JsonMessage::SendMessage { msg } => {
-                        if msg.len() <= 300 {
+                    JsonMessage::SetStatus { status } => {
+                        let valid = match status {
+                            Status::Custom(ref text) => text.len() <= MAX_STATUS_LENGTH,
+                            _ => true,
+                        };
+
+                        if valid {
                             if let Some(user_id) = *self.user_id.read() {
-                                let _ = self.channel.send(Message::Message { user_id, msg });
+                                let _ = self.channel.send(Message::Status {
+                                    id: self.id,
+                                    user_id,
+                                    status,
+                                });
                             }
                         }
                     }
+                    JsonMessage::SetLastSeenVisible { visible } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::LastSeenVisible {
+                                id: self.id,
+                                user_id,
+                                visible,
+                            });
+                        }
+                    }
+                    JsonMessage::SetRadius { km } if km.is_finite() => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Radius {
+                                id: self.id,
+                                user_id,
+                                km,
+                            });
+                        }
+                    }
+                    JsonMessage::GetFences if self.user_id.read().is_some() => {
+                        let _ = self.channel.send(Message::Fences { id: self.id });
+                    }
+                    JsonMessage::AddFence { name, shape, poi } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::AddFence {
+                                id: self.id,
+                                user_id,
+                                name,
+                                shape,
+                                poi,
+                            });
+                        }
+                    }
+                    JsonMessage::RemoveFence { id } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::RemoveFence {
+                                id: self.id,
+                                user_id,
+                                fence_id: id,
+                            });
+                        }
+                    }
+                    JsonMessage::GetChannels => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Channels {
+                                id: self.id,
+                                user_id,
+                            });
+                        }
+                    }
+                    JsonMessage::SendToChannel { channel, msg }
+                        if msg.len() <= MAX_MESSAGE_LENGTH =>
+                    {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::ToChannel {
+                                id: self.id,
+                                user_id,
+                                channel,
+                                msg,
+                            });
+                        }
+                    }
+                    JsonMessage::GetAreaInfo => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::AreaInfo {
+                                id: self.id,
+                                user_id,
+                            });
+                        }
+                    }
+                    JsonMessage::Nearby { limit } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Nearby {
+                                id: self.id,
+                                user_id,
+                                limit: limit.unwrap_or(MAX_NEARBY_LIMIT).min(MAX_NEARBY_LIMIT),
+                            });
+                        }
+                    }
+                    JsonMessage::SetGlobal { enabled } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Global {
+                                id: self.id,
+                                user_id,
+                                enabled,
+                            });
+                        }
+                    }
+                    JsonMessage::SetRouteMode { enabled } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::RouteMode {
+                                id: self.id,
+                                user_id,
+                                enabled,
+                            });
+                        }
+                    }
+                    JsonMessage::SetFuzzLocation { enabled } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::FuzzLocation {
+                                id: self.id,
+                                user_id,
+                                enabled,
+                            });
+                        }
+                    }
+                    JsonMessage::SetLocationHistory { enabled } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::LocationHistory {
+                                id: self.id,
+                                user_id,
+                                enabled,
+                            });
+                        }
+                    }
+                    JsonMessage::MyLocationHistory { since } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::MyLocationHistory {
+                                id: self.id,
+                                user_id,
+                                since: since.unwrap_or(0),
+                            });
+                        }
+                    }
+                    JsonMessage::GetProfile { username } if self.user_id.read().is_some() => {
+                        let _ = self.channel.send(Message::Profile {
+                            id: self.id,
+                            username,
+                        });
+                    }
+                    JsonMessage::PublishKey { key } if key.len() <= MAX_KEY_LENGTH => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::PublishKey {
+                                id: self.id,
+                                user_id,
+                                key,
+                            });
+                        }
+                    }
+                    JsonMessage::GetKey { username } if self.user_id.read().is_some() => {
+                        let _ = self.channel.send(Message::Key {
+                            id: self.id,
+                            username,
+                        });
+                    }
+                    JsonMessage::EncryptedMessage { to, ciphertext }
+                        if ciphertext.len() <= MAX_CIPHERTEXT_LENGTH =>
+                    {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Encrypted {
+                                id: self.id,
+                                user_id,
+                                to,
+                                ciphertext,
+                            });
+                        }
+                    }
+                    JsonMessage::TimeSync { client_time } => {
+                        let wall_time = SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map(|time| time.as_secs() * 1000 + u64::from(time.subsec_millis()))
+                            .unwrap_or(0);
+                        let monotonic = self.started.elapsed();
+
+                        self.send(&JsonMessage::TimeSyncResponse {
+                            client_time,
+                            wall_time,
+                            monotonic_time: monotonic.as_secs() * 1000
+                                + u64::from(monotonic.subsec_millis()),
+                        });
+                    }
+                    JsonMessage::ShareLocation => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::ShareLocation {
+                                id: self.id,
+                                user_id,
+                            });
+                        }
+                    }
+                    JsonMessage::MarkRead { id } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::MarkRead {
+                                id: self.id,
+                                user_id,
+                                message_id: id,
+                            });
+                        }
+                    }
+                    JsonMessage::GetUnreadCounts => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::UnreadCounts {
+                                id: self.id,
+                                user_id,
+                            });
+                        }
+                    }
+                    JsonMessage::GetHistory {
+                        before,
+                        search,
+                        limit,
+                    } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::History {
+                                id: self.id,
+                                user_id,
+                                before,
+                                search,
+                                limit,
+                            });
+                        }
+                    }
+                    JsonMessage::GetSettings => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Settings {
+                                id: self.id,
+                                user_id,
+                                update: None,
+                            });
+                        }
+                    }
+                    JsonMessage::UpdateSettings { settings } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Settings {
+                                id: self.id,
+                                user_id,
+                                update: Some(settings),
+                            });
+                        }
+                    }
+                    JsonMessage::RequestDataExport => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::DataExport {
+                                id: self.id,
+                                user_id,
+                            });
+                        }
+                    }
+                    JsonMessage::Pin { id } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Pin {
+                                id: self.id,
+                                user_id,
+                                message_id: id,
+                                pinned: true,
+                            });
+                        }
+                    }
+                    JsonMessage::Unpin { id } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Pin {
+                                id: self.id,
+                                user_id,
+                                message_id: id,
+                                pinned: false,
+                            });
+                        }
+                    }
+                    JsonMessage::CreatePoll { question, options }
+                        if Polls::valid(&question, &options) =>
+                    {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::CreatePoll {
+                                id: self.id,
+                                user_id,
+                                question,
+                                options,
+                            });
+                        }
+                    }
+                    JsonMessage::Vote { poll_id, option } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Vote {
+                                id: self.id,
+                                user_id,
+                                poll_id,
+                                option,
+                            });
+                        }
+                    }
+                    JsonMessage::ReportMessage { id, reason }
+                        if reason.len() <= MAX_REASON_LENGTH =>
+                    {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Report {
+                                id: self.id,
+                                user_id,
+                                message_id: id,
+                                reason,
+                            });
+                        }
+                    }
+                    JsonMessage::Announce { text } if text.len() <= MAX_MESSAGE_LENGTH => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Announce {
+                                id: self.id,
+                                user_id,
+                                text,
+                            });
+                        }
+                    }
+                    JsonMessage::Ban { username } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Ban {
+                                id: self.id,
+                                user_id,
+                                username,
+                            });
+                        }
+                    }
+                    JsonMessage::Suspend {
+                        username,
+                        reason,
+                        until,
+                    } if reason.len() <= MAX_REASON_LENGTH => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Suspend {
+                                id: self.id,
+                                user_id,
+                                username,
+                                suspension: Some(Suspension { reason, until }),
+                            });
+                        }
+                    }
+                    JsonMessage::Unsuspend { username } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Suspend {
+                                id: self.id,
+                                user_id,
+                                username,
+                                suspension: None,
+                            });
+                        }
+                    }
+                    JsonMessage::BanAddress { range } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::BanAddress {
+                                id: self.id,
+                                user_id,
+                                range,
+                                banned: true,
+                            });
+                        }
+                    }
+                    JsonMessage::UnbanAddress { range } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::BanAddress {
+                                id: self.id,
+                                user_id,
+                                range,
+                                banned: false,
+                            });
+                        }
+                    }
+                    JsonMessage::GetStats => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Stats {
+                                id: self.id,
+                                user_id,
+                            });
+                        }
+                    }
+                    JsonMessage::GetHeatmap {
+                        window_secs,
+                        precision,
+                    } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Heatmap {
+                                id: self.id,
+                                user_id,
+                                window_secs: window_secs
+                                    .unwrap_or(60 * 60)
+                                    .min(MAX_HEATMAP_WINDOW_SECS),
+                                precision: precision.unwrap_or(HEATMAP_PRECISION),
+                            });
+                        }
+                    }
+                    JsonMessage::QueryBox {
+                        min_lat,
+                        min_lon,
+                        max_lat,
+                        max_lon,
+                    } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::QueryBox {
+                                id: self.id,
+                                user_id,
+                                min_lat,
+                                min_lon,
+                                max_lat,
+                                max_lon,
+                            });
+                        }
+                    }
+                    JsonMessage::GetStatsHistory { since, limit } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::StatsHistory {
+                                id: self.id,
+                                user_id,
+                                since: since.unwrap_or(0),
+                                limit,
+                            });
+                        }
+                    }
+                    JsonMessage::GetAuditLog { username, limit } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::AuditLog {
+                                id: self.id,
+                                user_id,
+                                username,
+                                limit,
+                            });
+                        }
+                    }
+                    JsonMessage::Backup { path } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Backup {
+                                id: self.id,
+                                user_id,
+                                path,
+                                restore: false,
+                            });
+                        }
+                    }
+                    JsonMessage::Restore { path } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Backup {
+                                id: self.id,
+                                user_id,
+                                path,
+                                restore: true,
+                            });
+                        }
+                    }
+                    JsonMessage::GetReports => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Reports {
+                                id: self.id,
+                                user_id,
+                                resolve: None,
+                            });
+                        }
+                    }
+                    JsonMessage::ResolveReport { id } => {
+                        if let Some(user_id) = *self.user_id.read() {
+                            let _ = self.channel.send(Message::Reports {
+                                id: self.id,
+                                user_id,
+                                resolve: Some(id),
+                            });
+                        }
+                    }
                     _ => (),
                 }
             }
@@ -305,8 +3447,84 @@ impl Handler for Server {
         Ok(())
     }
 
-    fn on_close(&mut self, code: CloseCode, _reason: &str) {
-        let _ = self.channel.send(Message::Close { id: self.id, code });
+    pub fn on_close(&mut self, code: CloseCode) {
+        if self.id != 0 {
+            let _ = self.channel.send(Message::Close { id: self.id, code });
+        }
         let _ = self.socket.close(CloseCode::Normal);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::Algorithm;
+    use crate::storage::{self, StorageConfig};
+
+    fn assert_km(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1.0,
+            "{} km, expected {} km",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn distance_between_cities() {
+        // London to Paris
+        assert_km(distance(51.5074, -0.1278, 48.8566, 2.3522), 343.6);
+        // New York to Los Angeles
+        assert_km(distance(40.7128, -74.006, 34.0522, -118.2437), 3935.8);
+    }
+
+    #[test]
+    fn distance_to_the_antipode() {
+        // Half way round, which rounding mustn't push past
+        assert_km(distance(45.0, 10.0, -45.0, -170.0), 20015.1);
+        assert_km(distance(0.0, 0.0, 0.0, 180.0), 20015.1);
+    }
+
+    #[test]
+    fn distance_is_symmetric_and_zero_in_place() {
+        assert_eq!(distance(59.33, 18.07, 59.33, 18.07), 0.0);
+        assert_eq!(
+            distance(51.5074, -0.1278, 48.8566, 2.3522),
+            distance(48.8566, 2.3522, 51.5074, -0.1278)
+        );
+    }
+
+    #[test]
+    fn distance_keeps_short_distances() {
+        // A thousandth of a degree of latitude is about 111 m
+        let d = distance(59.33, 18.07, 59.331, 18.07);
+        assert!((d - 0.1112).abs() < 0.001, "{} km", d);
+    }
+
+    #[test]
+    fn guests_cannot_share_a_nickname() {
+        let users = Users::new(
+            Hasher::new(Algorithm::Pbkdf2, 1),
+            Policy::default(),
+            storage::from_config(&StorageConfig::Memory).unwrap(),
+            Pool::new(1),
+            Duration::from_secs(3600),
+        );
+
+        let guest = users.add_guest("visitor").unwrap();
+        assert_eq!(users.add_guest("visitor"), None);
+
+        // Free again once the guest is gone
+        users.remove_guest(guest);
+        assert!(users.add_guest("visitor").is_some());
+    }
+
+    #[test]
+    fn rejects_reserved_usernames() {
+        assert!(valid_username("alice"));
+        assert!(!valid_username(""));
+        assert!(!valid_username("github:1234"));
+        assert!(!valid_username("alice smith"));
+        assert!(!valid_username(&"a".repeat(MAX_USERNAME_LENGTH + 1)));
+    }
+}
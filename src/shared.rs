@@ -0,0 +1,164 @@
+use parking_lot::Mutex;
+use redis::{Client, Connection, RedisResult};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// How long a user counts as online after the last heartbeat of an instance
+// they are connected to
+const PRESENCE_TTL_SECS: u64 = 30;
+
+// A session as seen by every instance
+#[derive(Serialize, Deserialize)]
+pub struct SharedSession {
+    pub user_id: usize,
+    // Seconds since the unix epoch
+    pub created: u64,
+    pub last_active: u64,
+}
+
+// Presence and sessions kept in Redis, so that several instances, or a
+// restarted one, agree on who is logged in and online. Failures are logged and
+// treated as if Redis wasn't there, the local state keeps working.
+#[derive(Clone)]
+pub struct Shared {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Shared {
+    pub fn connect(url: &str) -> RedisResult<Shared> {
+        let connection = Client::open(url)?.get_connection()?;
+
+        Ok(Shared {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    pub fn save_session(&self, token: &str, session: &SharedSession, ttl_secs: u64) {
+        let json = match serde_json::to_string(session) {
+            Ok(json) => json,
+            Err(_) => return,
+        };
+
+        let result = redis::pipe()
+            .cmd("SET")
+            .arg(session_key(token))
+            .arg(json)
+            .arg("EX")
+            .arg(ttl_secs.max(1))
+            .ignore()
+            .cmd("SADD")
+            .arg(user_sessions_key(session.user_id))
+            .arg(token)
+            .ignore()
+            .query::<()>(&mut *self.connection.lock());
+        log("save session", result);
+    }
+
+    pub fn load_session(&self, token: &str) -> Option<SharedSession> {
+        let result = redis::cmd("GET")
+            .arg(session_key(token))
+            .query::<Option<String>>(&mut *self.connection.lock());
+
+        match result {
+            Ok(json) => json.and_then(|json| serde_json::from_str(&json).ok()),
+            Err(e) => {
+                println!("Failed to load session from Redis: {}", e);
+                None
+            }
+        }
+    }
+
+    // Whether another instance ended the session, assumed not when Redis can't be reached
+    pub fn session_ended(&self, token: &str) -> bool {
+        let result = redis::cmd("EXISTS")
+            .arg(session_key(token))
+            .query::<bool>(&mut *self.connection.lock());
+
+        match result {
+            Ok(exists) => !exists,
+            Err(e) => {
+                println!("Failed to check session in Redis: {}", e);
+                false
+            }
+        }
+    }
+
+    pub fn end_session(&self, token: &str) {
+        let result = redis::cmd("DEL")
+            .arg(session_key(token))
+            .query::<()>(&mut *self.connection.lock());
+        log("end session", result);
+    }
+
+    // Ends every session of a user except `keep`
+    pub fn end_user_sessions(&self, user_id: usize, keep: Option<&str>) {
+        let mut connection = self.connection.lock();
+        let key = user_sessions_key(user_id);
+
+        let tokens = match redis::cmd("SMEMBERS")
+            .arg(&key)
+            .query::<Vec<String>>(&mut *connection)
+        {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                println!("Failed to end sessions in Redis: {}", e);
+                return;
+            }
+        };
+
+        let mut pipe = redis::pipe();
+        for token in tokens.iter().filter(|&token| Some(token.as_str()) != keep) {
+            pipe.cmd("DEL")
+                .arg(session_key(token))
+                .ignore()
+                .cmd("SREM")
+                .arg(&key)
+                .arg(token)
+                .ignore();
+        }
+        log("end sessions", pipe.query::<()>(&mut *connection));
+    }
+
+    // Marks users as online for PRESENCE_TTL_SECS
+    pub fn heartbeat(&self, user_ids: &[usize]) {
+        if user_ids.is_empty() {
+            return;
+        }
+
+        let mut pipe = redis::pipe();
+        for &user_id in user_ids {
+            pipe.cmd("SET")
+                .arg(presence_key(user_id))
+                .arg(1)
+                .arg("EX")
+                .arg(PRESENCE_TTL_SECS)
+                .ignore();
+        }
+        log("heartbeat", pipe.query::<()>(&mut *self.connection.lock()));
+    }
+
+    pub fn is_online(&self, user_id: usize) -> bool {
+        redis::cmd("EXISTS")
+            .arg(presence_key(user_id))
+            .query::<bool>(&mut *self.connection.lock())
+            .unwrap_or(false)
+    }
+}
+
+fn session_key(token: &str) -> String {
+    format!("session:{}", token)
+}
+
+fn user_sessions_key(user_id: usize) -> String {
+    format!("user_sessions:{}", user_id)
+}
+
+fn presence_key(user_id: usize) -> String {
+    format!("presence:{}", user_id)
+}
+
+fn log(action: &str, result: RedisResult<()>) {
+    if let Err(e) = result {
+        println!("Failed to {} in Redis: {}", action, e);
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    thread,
+    time::Duration,
+};
+
+use crate::bots::ApiKeys;
+use crate::crypt::{self, Key};
+use crate::oauth::OAuth;
+use crate::server::Users;
+use crate::storage::UserRecord;
+
+const INTERVAL_SECS: u64 = 60;
+
+#[derive(Deserialize)]
+pub struct SnapshotConfig {
+    pub path: String,
+    #[serde(default = "default_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_interval() -> u64 {
+    INTERVAL_SECS
+}
+
+// Durable state that isn't kept by the storage backend, or all of it when
+// running without one
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    users: Vec<UserRecord>,
+    oauth_links: Vec<((String, String), usize)>,
+    api_keys: Vec<(String, usize)>,
+}
+
+impl Snapshot {
+    pub fn take(users: &Users, oauth: &OAuth, api_keys: &ApiKeys) -> Snapshot {
+        Snapshot {
+            users: users.records(),
+            oauth_links: oauth.links(),
+            api_keys: api_keys.export(),
+        }
+    }
+
+    // None when no snapshot has been written yet
+    pub fn load(path: &str, key: Option<&Key>) -> Result<Option<Snapshot>, String> {
+        match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&crypt::open_file(contents, key)?)
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    pub fn restore(self, users: &Users, oauth: &OAuth, api_keys: &ApiKeys) {
+        users.restore(self.users);
+        oauth.restore(self.oauth_links);
+        api_keys.restore(self.api_keys);
+    }
+
+    pub fn save(&self, path: &str, key: Option<&Key>) -> Result<(), String> {
+        let contents = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        write_atomic(path, &crypt::seal_file(contents, key)?)
+    }
+}
+
+// Written next to the old file and renamed over it, so a crash while writing
+// never leaves a truncated file behind
+pub fn write_atomic(path: &str, contents: &[u8]) -> Result<(), String> {
+    let tmp = format!("{}.tmp", path);
+
+    File::create(&tmp)
+        .and_then(|mut file| {
+            file.write_all(contents)?;
+            file.sync_all()
+        })
+        .map_err(|e| e.to_string())?;
+    fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+pub fn run(
+    config: SnapshotConfig,
+    users: Users,
+    oauth: OAuth,
+    api_keys: ApiKeys,
+    key: Option<Key>,
+) {
+    loop {
+        thread::sleep(Duration::from_secs(config.interval_secs.max(1)));
+
+        let snapshot = Snapshot::take(&users, &oauth, &api_keys);
+        if let Err(e) = snapshot.save(&config.path, key.as_ref()) {
+            println!("Failed to write snapshot {}: {}", config.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::password::{Algorithm, Hasher, Policy};
+    use crate::pool::Pool;
+    use crate::storage::{self, StorageConfig};
+    use std::{collections::HashMap, env};
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("chat_server-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn users() -> Users {
+        Users::new(
+            Hasher::new(Algorithm::Pbkdf2, 1),
+            Policy::default(),
+            storage::from_config(&StorageConfig::Memory).unwrap(),
+            Pool::new(1),
+            Duration::from_secs(3600),
+        )
+    }
+
+    fn key() -> Key {
+        Key::from_base64(&base64::encode([7u8; 32])).unwrap()
+    }
+
+    // Saves a snapshot of a user with an OAuth link and an API key, and
+    // restores it into empty state
+    fn round_trip(path: &str, key: Option<&Key>) -> (Users, OAuth, ApiKeys) {
+        let (users_before, oauth_before, api_keys_before) =
+            (users(), OAuth::new(HashMap::new()), ApiKeys::new());
+        let user_id = users_before.add("alice", "correct horse battery staple");
+        oauth_before.link("github", "1234".to_string(), user_id);
+        api_keys_before.create(user_id);
+
+        Snapshot::take(&users_before, &oauth_before, &api_keys_before)
+            .save(path, key)
+            .unwrap();
+
+        let (users, oauth, api_keys) = (users(), OAuth::new(HashMap::new()), ApiKeys::new());
+        Snapshot::load(path, key)
+            .unwrap()
+            .unwrap()
+            .restore(&users, &oauth, &api_keys);
+
+        assert_eq!(oauth.links(), oauth_before.links());
+        assert_eq!(api_keys.export(), api_keys_before.export());
+
+        (users, oauth, api_keys)
+    }
+
+    #[test]
+    fn round_trips() {
+        let path = path("snapshot-plain");
+        let (users, _, _) = round_trip(&path, None);
+
+        let user_id = users.get_by_name("alice").map(|user| user.id);
+        assert!(user_id.is_some());
+        assert_eq!(
+            users
+                .authenticate("alice", "correct horse battery staple")
+                .map(|(id, _)| id),
+            user_id
+        );
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn round_trips_sealed() {
+        let path = path("snapshot-sealed");
+        let (users, _, _) = round_trip(&path, Some(&key()));
+        assert!(users.get_by_name("alice").is_some());
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("alice"));
+        assert!(Snapshot::load(&path, None).is_err());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn missing_snapshot_is_none() {
+        assert!(Snapshot::load(&path("snapshot-missing"), None)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,498 @@
+use futures_util::{
+    future::{self, Either},
+    pin_mut, SinkExt, StreamExt,
+};
+#[cfg(feature = "tls")]
+use openssl::ssl::{Ssl, SslAcceptor};
+#[cfg(feature = "tls")]
+use std::pin::Pin;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+#[cfg(feature = "tls")]
+use tokio::net::TcpStream;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    runtime,
+    sync::{mpsc, Notify, Semaphore},
+    task,
+};
+#[cfg(feature = "tls")]
+use tokio_openssl::SslStream;
+use tokio_tungstenite::tungstenite::{
+    handshake::server::Response,
+    protocol::{CloseFrame, WebSocketConfig},
+    Error,
+};
+
+pub use tokio_tungstenite::tungstenite::handshake::server::Request;
+pub use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+pub use tokio_tungstenite::tungstenite::Message as Frame;
+
+use crate::addrban::TrustedProxies;
+use crate::server::{Server, MAX_FRAME_SIZE};
+
+static NEXT_TOKEN: AtomicUsize = AtomicUsize::new(1);
+// Bytes waiting to be written to a client past which events are dropped, and
+// past which the client is taken to have stopped reading and is disconnected
+const SHED_BUFFERED: usize = 256 * 1024;
+const MAX_BUFFERED: usize = 1024 * 1024;
+
+// The connection is gone, so nothing more can be sent on it
+#[derive(Debug)]
+pub struct Closed;
+
+pub type Result<T> = std::result::Result<T, Closed>;
+
+// Text sent to many connections at once, serialized a single time
+pub type Payload = Arc<str>;
+
+enum Outgoing {
+    Frame(Frame),
+    // Copied into a frame by the connection's task, off the sending thread
+    Shared(Payload),
+}
+
+impl Outgoing {
+    fn len(&self) -> usize {
+        match self {
+            Outgoing::Frame(frame) => frame.len(),
+            Outgoing::Shared(payload) => payload.len(),
+        }
+    }
+}
+
+// What is waiting to be written to the client
+#[derive(Default)]
+struct Buffer {
+    queued: AtomicUsize,
+    stalled: AtomicBool,
+    stall: Notify,
+}
+
+impl Buffer {
+    fn written(&self, len: usize) {
+        self.queued.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    fn stall(&self) {
+        self.stalled.store(true, Ordering::Relaxed);
+        self.stall.notify_waiters();
+    }
+
+    // Resolves once the client is found to have stopped reading
+    async fn stalled(&self) {
+        loop {
+            let notified = self.stall.notified();
+            if self.stalled.load(Ordering::Relaxed) {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+// The sending half of a connection, usable from any thread. Frames are
+// written out in order by the connection's task.
+#[derive(Clone)]
+pub struct Socket {
+    token: usize,
+    tx: mpsc::UnboundedSender<Outgoing>,
+    buffer: Arc<Buffer>,
+}
+
+impl Socket {
+    fn new() -> (Socket, mpsc::UnboundedReceiver<Outgoing>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+        let buffer = Arc::new(Buffer::default());
+
+        (Socket { token, tx, buffer }, rx)
+    }
+
+    // A socket without a connection, for measuring what sending costs
+    pub fn unconnected() -> (Socket, Unsent) {
+        let (socket, rx) = Socket::new();
+        let buffer = socket.buffer.clone();
+
+        (socket, Unsent { rx, buffer })
+    }
+
+    // Unique to the connection
+    pub fn token(&self) -> usize {
+        self.token
+    }
+
+    pub fn send<M: Into<String>>(&self, msg: M) -> Result<()> {
+        self.queue(Outgoing::Frame(Frame::Text(msg.into())), true)
+    }
+
+    pub fn send_shared(&self, payload: &Payload) -> Result<()> {
+        self.queue(Outgoing::Shared(payload.clone()), true)
+    }
+
+    // For what the client can do without, like presence and shared
+    // locations, which is dropped first when it falls behind on reading
+    pub fn send_event(&self, payload: &Payload) -> Result<()> {
+        self.queue(Outgoing::Shared(payload.clone()), false)
+    }
+
+    fn queue(&self, outgoing: Outgoing, needed: bool) -> Result<()> {
+        let len = outgoing.len();
+        let queued = self.buffer.queued.load(Ordering::Relaxed);
+
+        if queued + len > MAX_BUFFERED {
+            self.buffer.stall();
+            return Err(Closed);
+        }
+        if queued > SHED_BUFFERED && !needed {
+            return Ok(());
+        }
+
+        self.buffer.queued.fetch_add(len, Ordering::Relaxed);
+        self.tx.send(outgoing).map_err(|_| Closed)
+    }
+
+    pub fn close(&self, code: CloseCode) -> Result<()> {
+        let frame = CloseFrame {
+            code,
+            reason: "".into(),
+        };
+        self.tx
+            .send(Outgoing::Frame(Frame::Close(Some(frame))))
+            .map_err(|_| Closed)
+    }
+}
+
+// What was sent on an unconnected socket
+pub struct Unsent {
+    rx: mpsc::UnboundedReceiver<Outgoing>,
+    buffer: Arc<Buffer>,
+}
+
+impl Unsent {
+    // Throws the frames away, returns how many there were
+    pub fn clear(&mut self) -> usize {
+        let mut count = 0;
+        while let Ok(outgoing) = self.rx.try_recv() {
+            self.buffer.written(outgoing.len());
+            count += 1;
+        }
+        count
+    }
+}
+
+pub struct Settings {
+    pub max_connections: usize,
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<SslAcceptor>>,
+}
+
+// The client's address. When the connection comes from a trusted proxy it is
+// the last address in its forwarding header that isn't one of the proxies, as
+// anything before that was put there by the client.
+pub fn remote_addr(request: &Request, peer: SocketAddr, proxies: &TrustedProxies) -> IpAddr {
+    let peer = peer.ip();
+    if !proxies.trusts(&peer) {
+        return peer;
+    }
+
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+
+    let hops = match header("X-Forwarded-For") {
+        Some(value) => value.split(',').map(str::trim).collect::<Vec<_>>(),
+        None => match header("Forwarded") {
+            Some(value) => value
+                .split(',')
+                .filter_map(|element| {
+                    element
+                        .split(';')
+                        .map(str::trim)
+                        .find(|pair| pair.to_ascii_lowercase().starts_with("for="))
+                        .map(|pair| forwarded_for(&pair[4..]))
+                })
+                .collect(),
+            None => return peer,
+        },
+    };
+
+    let mut addr = peer;
+    for hop in hops.into_iter().rev() {
+        match hop.parse() {
+            Ok(hop) => addr = hop,
+            Err(_) => break,
+        }
+        if !proxies.trusts(&addr) {
+            break;
+        }
+    }
+
+    addr
+}
+
+// The address in a Forwarded for= value, which may be quoted and carry a port
+fn forwarded_for(value: &str) -> &str {
+    let value = value.trim_matches('"');
+    if let Some(value) = value.strip_prefix('[') {
+        return value.split(']').next().unwrap_or(value);
+    }
+
+    match value.rfind(':') {
+        Some(i) if value.matches(':').count() == 1 => &value[..i],
+        _ => value,
+    }
+}
+
+// Accepts connections until the listener fails. Each connection is served by
+// a task of its own, which makes a Server for it with the factory.
+pub fn listen<F>(endpoint: &str, settings: Settings, factory: F) -> io::Result<()>
+where
+    F: Fn(Socket) -> Server + Send + Sync + 'static,
+{
+    let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+
+    runtime.block_on(async move {
+        let listener = TcpListener::bind(endpoint).await?;
+        let slots = Arc::new(Semaphore::new(settings.max_connections));
+        let factory = Arc::new(factory);
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            // Connections past the limit are dropped right away
+            let permit = match slots.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => continue,
+            };
+            let factory = factory.clone();
+            #[cfg(feature = "tls")]
+            let tls = settings.tls.clone();
+
+            tokio::spawn(async move {
+                let _permit = permit;
+                let (socket, rx) = Socket::new();
+                let server = factory(socket);
+
+                #[cfg(feature = "tls")]
+                {
+                    if let Some(tls) = tls {
+                        if let Some(stream) = accept_tls(&tls, stream).await {
+                            serve(stream, peer, server, rx).await;
+                        }
+                        return;
+                    }
+                }
+
+                serve(stream, peer, server, rx).await;
+            });
+        }
+    })
+}
+
+#[cfg(feature = "tls")]
+async fn accept_tls(tls: &SslAcceptor, stream: TcpStream) -> Option<SslStream<TcpStream>> {
+    let ssl = Ssl::new(tls.context()).ok()?;
+    let mut stream = SslStream::new(ssl, stream).ok()?;
+    Pin::new(&mut stream).accept().await.ok()?;
+
+    Some(stream)
+}
+
+// Tungstenite decides the error type of the handshake callback
+#[allow(clippy::result_large_err)]
+async fn serve<S>(
+    stream: S,
+    peer: SocketAddr,
+    mut server: Server,
+    mut rx: mpsc::UnboundedReceiver<Outgoing>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // Frames are refused as they come in once they grow past the limit,
+    // instead of being buffered whole first
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_FRAME_SIZE),
+        max_frame_size: Some(MAX_FRAME_SIZE),
+        ..WebSocketConfig::default()
+    };
+
+    // The handshake request is kept for the origin and address checks
+    let mut request = None;
+    let accepted = tokio_tungstenite::accept_hdr_async_with_config(
+        stream,
+        |handshake: &Request, response: Response| {
+            let mut copy = Request::new(());
+            *copy.method_mut() = handshake.method().clone();
+            *copy.uri_mut() = handshake.uri().clone();
+            *copy.headers_mut() = handshake.headers().clone();
+            request = Some(copy);
+
+            Ok(response)
+        },
+        Some(config),
+    )
+    .await;
+
+    let (ws, request) = match (accepted, request) {
+        (Ok(ws), Some(request)) => (ws, request),
+        _ => return,
+    };
+    let (mut sink, mut stream) = ws.split();
+    let buffer = server.socket.buffer.clone();
+
+    // A close frame is the last one written. A client that stopped reading
+    // is dropped without one.
+    let writer_buffer = buffer.clone();
+    let writer = tokio::spawn(async move {
+        let stalled = writer_buffer.stalled();
+        pin_mut!(stalled);
+
+        while let Some(outgoing) = rx.recv().await {
+            let len = outgoing.len();
+            let frame = match outgoing {
+                Outgoing::Frame(frame) => frame,
+                Outgoing::Shared(payload) => Frame::Text(payload.to_string()),
+            };
+            let close = frame.is_close();
+
+            match future::select(sink.send(frame), stalled.as_mut()).await {
+                Either::Left((Ok(()), _)) if !close => writer_buffer.written(len),
+                Either::Left(_) => break,
+                Either::Right(_) => return,
+            }
+        }
+        let _ = sink.close().await;
+    });
+
+    // Opening waits on the workers, so it is let block the thread it runs on
+    // while other tasks move to the rest
+    let mut code = CloseCode::Abnormal;
+    if task::block_in_place(|| server.on_open(&request, peer)).is_ok() {
+        let stalled = buffer.stalled();
+        pin_mut!(stalled);
+
+        loop {
+            let frame = match future::select(stream.next(), stalled.as_mut()).await {
+                Either::Left((Some(frame), _)) => frame,
+                Either::Left((None, _)) => break,
+                Either::Right(_) => {
+                    println!("{} stopped reading, disconnecting", peer);
+                    break;
+                }
+            };
+
+            match frame {
+                Ok(Frame::Close(frame)) => {
+                    code = frame.map_or(CloseCode::Status, |frame| frame.code);
+                    break;
+                }
+                Ok(frame @ Frame::Text(_)) | Ok(frame @ Frame::Binary(_)) => {
+                    if queue_for(&mut server, |server| server.on_message(frame)).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => (),
+                Err(Error::Capacity(_)) => {
+                    code = CloseCode::Size;
+                    let _ = server.socket.close(code);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    queue_for(&mut server, |server| server.on_close(code));
+    let _ = writer.await;
+}
+
+// Handing a frame to the workers only waits once their queue fills up, so
+// until then it is done right on the task
+fn queue_for<T>(server: &mut Server, handle: impl FnOnce(&mut Server) -> T) -> T {
+    if server.channel.has_room() {
+        handle(server)
+    } else {
+        task::block_in_place(|| handle(server))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(headers: &[(&'static str, &str)]) -> Request {
+        let mut request = Request::new(());
+        for &(name, value) in headers {
+            request.headers_mut().append(name, value.parse().unwrap());
+        }
+        request
+    }
+
+    fn peer(addr: &str) -> SocketAddr {
+        SocketAddr::new(addr.parse().unwrap(), 40000)
+    }
+
+    fn proxies() -> TrustedProxies {
+        TrustedProxies::new(&["10.0.0.0/8".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let request = request(&[("X-Forwarded-For", "198.51.100.1")]);
+
+        assert_eq!(
+            remote_addr(&request, peer("203.0.113.9"), &proxies()),
+            "203.0.113.9".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn takes_the_last_untrusted_forwarded_address() {
+        // The client made up the first address, the proxies added the rest
+        let request = request(&[("X-Forwarded-For", "192.0.2.66, 198.51.100.1, 10.0.0.2")]);
+
+        assert_eq!(
+            remote_addr(&request, peer("10.0.0.1"), &proxies()),
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn reads_the_forwarded_header() {
+        let request = request(&[(
+            "Forwarded",
+            "for=192.0.2.66, for=\"[2001:db8::1]:4711\";proto=https",
+        )]);
+
+        assert_eq!(
+            remote_addr(&request, peer("10.0.0.1"), &proxies()),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        let request = self::request(&[("Forwarded", "for=198.51.100.1:8080")]);
+        assert_eq!(
+            remote_addr(&request, peer("10.0.0.1"), &proxies()),
+            "198.51.100.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn stops_at_addresses_that_do_not_parse() {
+        let request = request(&[("X-Forwarded-For", "198.51.100.1, unknown")]);
+
+        assert_eq!(
+            remote_addr(&request, peer("10.0.0.1"), &proxies()),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+    }
+}
@@ -0,0 +1,399 @@
+use parking_lot::Mutex;
+use rusqlite::{params, types::Type, Connection, Row};
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+
+use crate::migrations::{self, Migration};
+use crate::server::unix_time;
+use crate::storage::{
+    HistoryQuery, LocationRecord, MessageRecord, StatsRecord, Storage, UserRecord,
+};
+
+const COLUMNS: &str = "id, name, password, role, status, email, verified, bot, \
+                       public_key, banned, suspension, last_seen_visible, settings, \
+                       location_history, radius_km, fuzz_location";
+const MESSAGE_COLUMNS: &str = "id, user_id, username, guest, bot, area_lat, area_lon, time, msg";
+
+// Enums and structs are stored as JSON text
+pub struct Sqlite {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Sqlite {
+    pub fn open(path: &str) -> rusqlite::Result<Sqlite> {
+        let mut connection = Connection::open(path)?;
+        migrate(&mut connection)?;
+
+        Ok(Sqlite {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+}
+
+impl Storage for Sqlite {
+    fn load_users(&self) -> Result<Vec<UserRecord>, String> {
+        let connection = self.connection.lock();
+        let mut statement = connection
+            .prepare(&format!("SELECT {} FROM users", COLUMNS))
+            .map_err(|e| e.to_string())?;
+        let users = statement
+            .query_map(params![], record)
+            .map_err(|e| e.to_string())?;
+
+        users
+            .collect::<rusqlite::Result<_>>()
+            .map_err(|e| e.to_string())
+    }
+
+    fn find_user(&self, name: &str) -> Result<Option<UserRecord>, String> {
+        let connection = self.connection.lock();
+        let mut statement = connection
+            .prepare_cached(&format!("SELECT {} FROM users WHERE name = ?1", COLUMNS))
+            .map_err(|e| e.to_string())?;
+        let mut users = statement
+            .query_map(params![name], record)
+            .map_err(|e| e.to_string())?;
+
+        users.next().transpose().map_err(|e| e.to_string())
+    }
+
+    fn save_user(&self, user: &UserRecord) -> Result<(), String> {
+        self.connection
+            .lock()
+            .execute(
+                &format!(
+                    "INSERT OR REPLACE INTO users ({})
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+                    COLUMNS
+                ),
+                params![
+                    user.id as i64,
+                    user.name,
+                    user.password,
+                    to_json(&user.role),
+                    to_json(&user.status),
+                    user.email,
+                    user.verified,
+                    user.bot,
+                    user.public_key,
+                    user.banned,
+                    to_json(&user.suspension),
+                    user.last_seen_visible,
+                    to_json(&user.settings),
+                    user.location_history,
+                    user.radius_km.map(f64::from),
+                    user.fuzz_location,
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+
+        Ok(())
+    }
+
+    // One transaction per batch
+    fn save_messages(&self, messages: &[MessageRecord]) -> Result<(), String> {
+        let mut connection = self.connection.lock();
+        let transaction = connection.transaction().map_err(|e| e.to_string())?;
+        {
+            let mut statement = transaction
+                .prepare_cached(&format!(
+                    "INSERT OR IGNORE INTO messages ({})
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    MESSAGE_COLUMNS
+                ))
+                .map_err(|e| e.to_string())?;
+
+            for message in messages {
+                statement
+                    .execute(params![
+                        message.id as i64,
+                        message.user_id as i64,
+                        message.username,
+                        message.guest,
+                        message.bot,
+                        message.area.0,
+                        message.area.1,
+                        message.time as i64,
+                        message.msg,
+                    ])
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+
+        transaction.commit().map_err(|e| e.to_string())
+    }
+
+    fn messages(&self, query: &HistoryQuery) -> Result<Vec<MessageRecord>, String> {
+        let connection = self.connection.lock();
+        let mut statement = connection
+            .prepare_cached(&format!(
+                "SELECT {} FROM messages
+                WHERE area_lat = ?1 AND area_lon = ?2
+                    AND (?3 IS NULL OR id < ?3)
+                    AND (?4 IS NULL OR instr(msg, ?4) > 0)
+                ORDER BY id DESC LIMIT ?5",
+                MESSAGE_COLUMNS
+            ))
+            .map_err(|e| e.to_string())?;
+        let messages = statement
+            .query_map(
+                params![
+                    query.area.0,
+                    query.area.1,
+                    query.before.map(|before| before as i64),
+                    query.search,
+                    query.limit as i64,
+                ],
+                message_record,
+            )
+            .map_err(|e| e.to_string())?;
+
+        messages
+            .collect::<rusqlite::Result<_>>()
+            .map_err(|e| e.to_string())
+    }
+
+    fn last_message_id(&self) -> Result<Option<usize>, String> {
+        self.connection
+            .lock()
+            .query_row("SELECT MAX(id) FROM messages", params![], |row| {
+                row.get::<_, Option<i64>>(0)
+            })
+            .map(|id| id.map(|id| id as usize))
+            .map_err(|e| e.to_string())
+    }
+
+    fn export_messages(&self) -> Result<Vec<MessageRecord>, String> {
+        let connection = self.connection.lock();
+        let mut statement = connection
+            .prepare(&format!(
+                "SELECT {} FROM messages ORDER BY id",
+                MESSAGE_COLUMNS
+            ))
+            .map_err(|e| e.to_string())?;
+        let messages = statement
+            .query_map(params![], message_record)
+            .map_err(|e| e.to_string())?;
+
+        messages
+            .collect::<rusqlite::Result<_>>()
+            .map_err(|e| e.to_string())
+    }
+
+    fn prune_messages(
+        &self,
+        sent_before: Option<u64>,
+        keep_per_area: Option<usize>,
+    ) -> Result<usize, String> {
+        let connection = self.connection.lock();
+        let mut deleted = 0;
+
+        if let Some(sent_before) = sent_before {
+            deleted += connection
+                .execute(
+                    "DELETE FROM messages WHERE time < ?1",
+                    params![sent_before as i64],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        if let Some(keep) = keep_per_area {
+            deleted += connection
+                .execute(
+                    "DELETE FROM messages WHERE id IN (
+                        SELECT id FROM (
+                            SELECT id, ROW_NUMBER() OVER (
+                                PARTITION BY area_lat, area_lon ORDER BY id DESC
+                            ) AS n FROM messages
+                        ) WHERE n > ?1
+                    )",
+                    params![keep as i64],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+
+        Ok(deleted)
+    }
+
+    fn save_location(&self, location: &LocationRecord) -> Result<(), String> {
+        self.connection
+            .lock()
+            .prepare_cached(
+                "INSERT INTO locations (user_id, lat, lon, time, sealed) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )
+            .and_then(|mut statement| {
+                statement.execute(params![
+                    location.user_id as i64,
+                    f64::from(location.lat),
+                    f64::from(location.lon),
+                    location.time as i64,
+                    location.sealed,
+                ])
+            })
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn locations(
+        &self,
+        user_id: usize,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<LocationRecord>, String> {
+        let connection = self.connection.lock();
+        let mut statement = connection
+            .prepare_cached(
+                "SELECT user_id, lat, lon, time, sealed FROM locations
+                WHERE user_id = ?1 AND time >= ?2
+                ORDER BY time LIMIT ?3",
+            )
+            .map_err(|e| e.to_string())?;
+        let locations = statement
+            .query_map(
+                params![user_id as i64, since as i64, limit as i64],
+                location_record,
+            )
+            .map_err(|e| e.to_string())?;
+
+        locations
+            .collect::<rusqlite::Result<_>>()
+            .map_err(|e| e.to_string())
+    }
+
+    fn delete_locations(&self, user_id: usize) -> Result<(), String> {
+        self.connection
+            .lock()
+            .execute(
+                "DELETE FROM locations WHERE user_id = ?1",
+                params![user_id as i64],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn save_stats(&self, stats: &StatsRecord) -> Result<(), String> {
+        self.connection
+            .lock()
+            .execute(
+                "INSERT INTO stats (time, period_secs, messages, active_users, areas)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    stats.time as i64,
+                    stats.period_secs as i64,
+                    stats.messages as i64,
+                    stats.active_users as i64,
+                    to_json(&stats.areas),
+                ],
+            )
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    fn stats(&self, since: u64, limit: usize) -> Result<Vec<StatsRecord>, String> {
+        let connection = self.connection.lock();
+        let mut statement = connection
+            .prepare_cached(
+                "SELECT time, period_secs, messages, active_users, areas FROM stats
+                WHERE time >= ?1 ORDER BY time LIMIT ?2",
+            )
+            .map_err(|e| e.to_string())?;
+        let stats = statement
+            .query_map(params![since as i64, limit as i64], |row| {
+                Ok(StatsRecord {
+                    time: row.get::<_, i64>(0)? as u64,
+                    period_secs: row.get::<_, i64>(1)? as u64,
+                    messages: row.get::<_, i64>(2)? as usize,
+                    active_users: row.get::<_, i64>(3)? as usize,
+                    areas: json(row, 4)?,
+                })
+            })
+            .map_err(|e| e.to_string())?;
+
+        stats
+            .collect::<rusqlite::Result<_>>()
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Each migration runs in its own transaction together with recording it
+fn migrate(connection: &mut Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )",
+    )?;
+    let applied = connection.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_migrations",
+        params![],
+        |row| row.get::<_, i64>(0),
+    )?;
+
+    for &Migration { version, name, sql } in migrations::pending(migrations::SQLITE, applied) {
+        println!("Applying SQLite migration {} {}", version, name);
+
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(sql)?;
+        transaction.execute(
+            "INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+            params![version, name, unix_time() as i64],
+        )?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+fn record(row: &Row) -> rusqlite::Result<UserRecord> {
+    Ok(UserRecord {
+        id: row.get::<_, i64>(0)? as usize,
+        name: row.get(1)?,
+        password: row.get(2)?,
+        role: json(row, 3)?,
+        status: json(row, 4)?,
+        email: row.get(5)?,
+        verified: row.get(6)?,
+        bot: row.get(7)?,
+        public_key: row.get(8)?,
+        banned: row.get(9)?,
+        suspension: json(row, 10)?,
+        last_seen_visible: row.get(11)?,
+        settings: json(row, 12)?,
+        location_history: row.get(13)?,
+        radius_km: row.get::<_, Option<f64>>(14)?.map(|km| km as f32),
+        fuzz_location: row.get(15)?,
+    })
+}
+
+fn message_record(row: &Row) -> rusqlite::Result<MessageRecord> {
+    Ok(MessageRecord {
+        id: row.get::<_, i64>(0)? as usize,
+        user_id: row.get::<_, i64>(1)? as usize,
+        username: row.get(2)?,
+        guest: row.get(3)?,
+        bot: row.get(4)?,
+        area: (row.get(5)?, row.get(6)?),
+        time: row.get::<_, i64>(7)? as u64,
+        msg: row.get(8)?,
+    })
+}
+
+fn location_record(row: &Row) -> rusqlite::Result<LocationRecord> {
+    Ok(LocationRecord {
+        user_id: row.get::<_, i64>(0)? as usize,
+        lat: row.get::<_, f64>(1)? as f32,
+        lon: row.get::<_, f64>(2)? as f32,
+        time: row.get::<_, i64>(3)? as u64,
+        sealed: row.get(4)?,
+    })
+}
+
+fn json<T: DeserializeOwned>(row: &Row, index: usize) -> rusqlite::Result<T> {
+    let text: String = row.get(index)?;
+    serde_json::from_str(&text)
+        .map_err(|e| rusqlite::Error::FromSqlConversionFailure(index, Type::Text, Box::new(e)))
+}
+
+fn to_json<T: serde::Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
@@ -0,0 +1,227 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::geo::geohash;
+use crate::server::{unix_time, Area};
+use crate::storage::{StatsRecord, Storage};
+
+// Busiest areas kept per period
+const MAX_STATS_AREAS: usize = 20;
+pub const MAX_STATS_LIMIT: usize = 1440;
+// Messages are counted per cell of this precision, about 5 km across, and
+// per minute for a day
+pub const HEATMAP_PRECISION: usize = 5;
+const HEATMAP_BUCKET_SECS: u64 = 60;
+pub const MAX_HEATMAP_WINDOW_SECS: u64 = 24 * 60 * 60;
+// Shortest time the routing rate is averaged over
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Window {
+    messages: usize,
+    users: HashSet<usize>,
+    areas: HashMap<Area, usize>,
+}
+
+#[derive(Clone)]
+pub struct Activity {
+    window: Arc<Mutex<Window>>,
+}
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity {
+            window: Arc::new(Mutex::new(Window::default())),
+        }
+    }
+
+    pub fn message(&self, user_id: usize, area: Area) {
+        let mut window = self.window.lock();
+        window.messages += 1;
+        window.users.insert(user_id);
+        *window.areas.entry(area).or_insert(0) += 1;
+    }
+
+    pub fn active(&self, user_id: usize) {
+        self.window.lock().users.insert(user_id);
+    }
+
+    fn take(&self, period_secs: u64) -> StatsRecord {
+        let window = mem::take(&mut *self.window.lock());
+
+        let mut areas = window.areas.into_iter().collect::<Vec<_>>();
+        areas.sort_by_key(|&(_, count)| Reverse(count));
+        areas.truncate(MAX_STATS_AREAS);
+
+        StatsRecord {
+            time: unix_time(),
+            period_secs,
+            messages: window.messages,
+            active_users: window.users.len(),
+            areas,
+        }
+    }
+}
+
+struct Sample {
+    time: Instant,
+    routed: usize,
+    per_sec: f32,
+}
+
+// Kept up to date by the router and the workers as connections come and go
+// and messages pass through, read for the admin stats
+#[derive(Clone)]
+pub struct Counters {
+    connections: Arc<AtomicUsize>,
+    routed: Arc<AtomicUsize>,
+    auth_failures: Arc<AtomicUsize>,
+    sample: Arc<Mutex<Sample>>,
+}
+
+impl Counters {
+    pub fn new() -> Counters {
+        Counters {
+            connections: Arc::new(AtomicUsize::new(0)),
+            routed: Arc::new(AtomicUsize::new(0)),
+            auth_failures: Arc::new(AtomicUsize::new(0)),
+            sample: Arc::new(Mutex::new(Sample {
+                time: Instant::now(),
+                routed: 0,
+                per_sec: 0.0,
+            })),
+        }
+    }
+
+    pub fn opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn routed(&self) {
+        self.routed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn auth_failures(&self) -> usize {
+        self.auth_failures.load(Ordering::Relaxed)
+    }
+
+    // Averaged since it was last taken, or the same as then when that was
+    // less than a second ago
+    pub fn routed_per_sec(&self) -> f32 {
+        let mut sample = self.sample.lock();
+        let elapsed = sample.time.elapsed();
+
+        if elapsed >= RATE_INTERVAL {
+            let routed = self.routed.load(Ordering::Relaxed);
+            sample.per_sec = (routed - sample.routed) as f32 / elapsed.as_secs_f32();
+            sample.routed = routed;
+            sample.time = Instant::now();
+        }
+        sample.per_sec
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HeatmapCell {
+    pub geohash: String,
+    pub messages: usize,
+}
+
+// The minute it started and the messages per cell in it
+type Bucket = (u64, HashMap<String, usize>);
+
+// Messages per geohash cell and minute
+#[derive(Clone)]
+pub struct Heatmap {
+    buckets: Arc<Mutex<VecDeque<Bucket>>>,
+}
+
+impl Heatmap {
+    pub fn new() -> Heatmap {
+        Heatmap {
+            buckets: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn message(&self, lat: f32, lon: f32) {
+        let minute = unix_time() / HEATMAP_BUCKET_SECS;
+        let mut buckets = self.buckets.lock();
+
+        if buckets.back().map(|&(start, _)| start) != Some(minute) {
+            buckets.push_back((minute, HashMap::new()));
+        }
+        let oldest = minute.saturating_sub(MAX_HEATMAP_WINDOW_SECS / HEATMAP_BUCKET_SECS);
+        while buckets.front().is_some_and(|&(start, _)| start < oldest) {
+            buckets.pop_front();
+        }
+
+        if let Some((_, cells)) = buckets.back_mut() {
+            *cells
+                .entry(geohash(lat, lon, HEATMAP_PRECISION))
+                .or_insert(0) += 1;
+        }
+    }
+
+    // Messages per cell over the window, busiest first. Shorter geohashes
+    // merge cells into larger ones.
+    pub fn query(&self, window_secs: u64, precision: usize) -> Vec<HeatmapCell> {
+        let since = unix_time().saturating_sub(window_secs) / HEATMAP_BUCKET_SECS;
+        let precision = precision.clamp(1, HEATMAP_PRECISION);
+
+        let mut counts = HashMap::new();
+        for (_, cells) in self
+            .buckets
+            .lock()
+            .iter()
+            .filter(|&&(minute, _)| minute >= since)
+        {
+            for (cell, count) in cells {
+                *counts.entry(cell[..precision].to_string()).or_insert(0) += count;
+            }
+        }
+
+        let mut cells = counts
+            .into_iter()
+            .map(|(geohash, messages)| HeatmapCell { geohash, messages })
+            .collect::<Vec<_>>();
+        cells.sort_by_key(|cell| Reverse(cell.messages));
+
+        cells
+    }
+}
+
+// Stores the activity of every period
+pub fn run(activity: Activity, storage: Arc<dyn Storage>, period_secs: u64) {
+    let period_secs = period_secs.max(1);
+
+    loop {
+        thread::sleep(Duration::from_secs(period_secs));
+
+        if let Err(e) = storage.save_stats(&activity.take(period_secs)) {
+            println!("Failed to save stats: {}", e);
+        }
+    }
+}
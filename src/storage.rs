@@ -0,0 +1,321 @@
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+use crate::kv::Sled;
+use crate::pg::Postgres;
+use crate::server::{Area, Role, Settings, Status, Suspension};
+use crate::sqlite::Sqlite;
+
+const POOL_SIZE: usize = 4;
+const MEMORY_HISTORY: usize = 10_000;
+const MEMORY_LOCATIONS: usize = 10_000;
+// A week of periods of a minute
+const MEMORY_STATS: usize = 7 * 24 * 60;
+
+// Where durable state is kept. Implementations only persist, the in-memory
+// maps in front of them stay the source of truth for the workers.
+pub trait Storage: Send + Sync {
+    fn load_users(&self) -> Result<Vec<UserRecord>, String>;
+    fn find_user(&self, name: &str) -> Result<Option<UserRecord>, String>;
+    fn save_user(&self, user: &UserRecord) -> Result<(), String>;
+    // Ids have to come from the backend when other instances add users to it
+    // too, otherwise they are handed out locally
+    fn next_id(&self) -> Result<Option<usize>, String> {
+        Ok(None)
+    }
+
+    fn save_messages(&self, messages: &[MessageRecord]) -> Result<(), String>;
+    // Newest first
+    fn messages(&self, query: &HistoryQuery) -> Result<Vec<MessageRecord>, String>;
+    fn last_message_id(&self) -> Result<Option<usize>, String>;
+    // Every stored message, oldest first
+    fn export_messages(&self) -> Result<Vec<MessageRecord>, String>;
+    // Deletes messages sent before `sent_before` and all but the newest
+    // `keep_per_area` of each area, returns how many were deleted
+    fn prune_messages(
+        &self,
+        sent_before: Option<u64>,
+        keep_per_area: Option<usize>,
+    ) -> Result<usize, String>;
+
+    fn save_location(&self, location: &LocationRecord) -> Result<(), String>;
+    // Oldest first
+    fn locations(
+        &self,
+        user_id: usize,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<LocationRecord>, String>;
+    fn delete_locations(&self, user_id: usize) -> Result<(), String>;
+
+    fn save_stats(&self, stats: &StatsRecord) -> Result<(), String>;
+    // Oldest first
+    fn stats(&self, since: u64, limit: usize) -> Result<Vec<StatsRecord>, String>;
+}
+
+#[derive(Deserialize, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StorageConfig {
+    // Nothing outlives the process
+    #[default]
+    Memory,
+    Sqlite {
+        path: String,
+    },
+    // Embedded, for single binary deployments
+    Sled {
+        path: String,
+    },
+    // Can be shared by several instances
+    Postgres {
+        url: String,
+        #[serde(default = "default_pool_size")]
+        pool_size: usize,
+    },
+}
+
+fn default_pool_size() -> usize {
+    POOL_SIZE
+}
+
+pub fn from_config(config: &StorageConfig) -> Result<Arc<dyn Storage>, String> {
+    Ok(match config {
+        StorageConfig::Memory => Arc::new(Memory {
+            messages: Mutex::new(VecDeque::new()),
+            locations: Mutex::new(VecDeque::new()),
+            stats: Mutex::new(VecDeque::new()),
+        }),
+        StorageConfig::Sqlite { path } => Arc::new(Sqlite::open(path).map_err(|e| e.to_string())?),
+        StorageConfig::Sled { path } => Arc::new(Sled::open(path).map_err(|e| e.to_string())?),
+        StorageConfig::Postgres { url, pool_size } => {
+            Arc::new(Postgres::connect(url, *pool_size).map_err(|e| e.to_string())?)
+        }
+    })
+}
+
+// The parts of a user that outlive a restart
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub id: usize,
+    pub name: String,
+    pub password: String,
+    pub role: Role,
+    pub status: Status,
+    pub email: Option<String>,
+    pub verified: bool,
+    pub bot: bool,
+    pub public_key: Option<String>,
+    pub banned: bool,
+    pub suspension: Option<Suspension>,
+    pub last_seen_visible: bool,
+    // Records written before settings existed have none
+    #[serde(default)]
+    pub settings: Settings,
+    // Whether the user opted in to keeping their location history
+    #[serde(default)]
+    pub location_history: bool,
+    #[serde(default)]
+    pub radius_km: Option<f32>,
+    #[serde(default)]
+    pub fuzz_location: bool,
+}
+
+// A broadcast message, filed under the area it was sent in
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MessageRecord {
+    pub id: usize,
+    pub user_id: usize,
+    pub username: String,
+    pub guest: bool,
+    pub bot: bool,
+    pub area: Area,
+    // Seconds since the unix epoch
+    pub time: u64,
+    pub msg: String,
+}
+
+// Where a user who opted in was at a point in time
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LocationRecord {
+    pub user_id: usize,
+    pub lat: f32,
+    pub lon: f32,
+    // Seconds since the unix epoch
+    pub time: u64,
+    // The coordinates when they are stored sealed, lat and lon are zero then
+    #[serde(default)]
+    pub sealed: Option<String>,
+}
+
+// Activity over one period, stored so operators can chart it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StatsRecord {
+    // Seconds since the unix epoch at the end of the period
+    pub time: u64,
+    pub period_secs: u64,
+    pub messages: usize,
+    // Users who sent a message or a location
+    pub active_users: usize,
+    // Messages per area, busiest first
+    pub areas: Vec<(Area, usize)>,
+}
+
+pub struct HistoryQuery {
+    pub area: Area,
+    // Only messages older than this id, for paging backwards
+    pub before: Option<usize>,
+    // Only messages containing this text
+    pub search: Option<String>,
+    pub limit: usize,
+}
+
+impl HistoryQuery {
+    fn matches(&self, message: &MessageRecord) -> bool {
+        message.area == self.area
+            && self.before.iter().all(|&before| message.id < before)
+            && self
+                .search
+                .iter()
+                .all(|search| message.msg.contains(search.as_str()))
+    }
+}
+
+// Keeps recent history but no users, those live in Users already
+struct Memory {
+    messages: Mutex<VecDeque<MessageRecord>>,
+    locations: Mutex<VecDeque<LocationRecord>>,
+    stats: Mutex<VecDeque<StatsRecord>>,
+}
+
+impl Storage for Memory {
+    fn load_users(&self) -> Result<Vec<UserRecord>, String> {
+        Ok(Vec::new())
+    }
+
+    fn find_user(&self, _name: &str) -> Result<Option<UserRecord>, String> {
+        Ok(None)
+    }
+
+    fn save_user(&self, _user: &UserRecord) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn save_messages(&self, messages: &[MessageRecord]) -> Result<(), String> {
+        let mut stored = self.messages.lock();
+        for message in messages {
+            if stored.len() >= MEMORY_HISTORY {
+                stored.pop_front();
+            }
+            stored.push_back(message.clone());
+        }
+
+        Ok(())
+    }
+
+    fn messages(&self, query: &HistoryQuery) -> Result<Vec<MessageRecord>, String> {
+        Ok(self
+            .messages
+            .lock()
+            .iter()
+            .rev()
+            .filter(|message| query.matches(message))
+            .take(query.limit)
+            .cloned()
+            .collect())
+    }
+
+    fn last_message_id(&self) -> Result<Option<usize>, String> {
+        Ok(None)
+    }
+
+    fn export_messages(&self) -> Result<Vec<MessageRecord>, String> {
+        Ok(self.messages.lock().iter().cloned().collect())
+    }
+
+    fn prune_messages(
+        &self,
+        sent_before: Option<u64>,
+        keep_per_area: Option<usize>,
+    ) -> Result<usize, String> {
+        let mut stored = self.messages.lock();
+        let before = stored.len();
+
+        if let Some(sent_before) = sent_before {
+            stored.retain(|message| message.time >= sent_before);
+        }
+        if let Some(keep) = keep_per_area {
+            let mut counts = HashMap::new();
+            let mut kept = stored
+                .drain(..)
+                .rev()
+                .filter(|message| {
+                    let count = counts.entry(message.area).or_insert(0);
+                    *count += 1;
+                    *count <= keep
+                })
+                .collect::<VecDeque<_>>();
+            kept.make_contiguous().reverse();
+            *stored = kept;
+        }
+
+        Ok(before - stored.len())
+    }
+
+    fn save_location(&self, location: &LocationRecord) -> Result<(), String> {
+        let mut stored = self.locations.lock();
+        if stored.len() >= MEMORY_LOCATIONS {
+            stored.pop_front();
+        }
+        stored.push_back(location.clone());
+
+        Ok(())
+    }
+
+    fn locations(
+        &self,
+        user_id: usize,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<LocationRecord>, String> {
+        Ok(self
+            .locations
+            .lock()
+            .iter()
+            .filter(|location| location.user_id == user_id && location.time >= since)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    fn delete_locations(&self, user_id: usize) -> Result<(), String> {
+        self.locations
+            .lock()
+            .retain(|location| location.user_id != user_id);
+        Ok(())
+    }
+
+    fn save_stats(&self, stats: &StatsRecord) -> Result<(), String> {
+        let mut stored = self.stats.lock();
+        if stored.len() >= MEMORY_STATS {
+            stored.pop_front();
+        }
+        stored.push_back(stats.clone());
+
+        Ok(())
+    }
+
+    fn stats(&self, since: u64, limit: usize) -> Result<Vec<StatsRecord>, String> {
+        Ok(self
+            .stats
+            .lock()
+            .iter()
+            .filter(|stats| stats.time >= since)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
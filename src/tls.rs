@@ -0,0 +1,16 @@
+use crate::config::TlsConfig;
+use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod};
+
+pub fn acceptor(config: &TlsConfig) -> Result<SslAcceptor, String> {
+    let mut builder =
+        SslAcceptor::mozilla_intermediate(SslMethod::tls()).map_err(|e| e.to_string())?;
+    builder
+        .set_private_key_file(&config.key, SslFiletype::PEM)
+        .map_err(|e| e.to_string())?;
+    builder
+        .set_certificate_chain_file(&config.cert)
+        .map_err(|e| e.to_string())?;
+    builder.check_private_key().map_err(|e| e.to_string())?;
+
+    Ok(builder.build())
+}
@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use crate::pool::Pool;
+use crate::server::unix_time;
+use crate::storage::{LocationRecord, Storage};
+
+pub const MAX_TRAIL_LIMIT: usize = 1000;
+
+// Location history of users who opted in. Writes go through the pool keyed
+// by user, so forgetting a user can't be overtaken by an earlier write.
+#[derive(Clone)]
+pub struct Trail {
+    storage: Arc<dyn Storage>,
+    pool: Pool,
+}
+
+impl Trail {
+    pub fn new(storage: Arc<dyn Storage>, pool: Pool) -> Trail {
+        Trail { storage, pool }
+    }
+
+    pub fn record(&self, user_id: usize, lat: f32, lon: f32) {
+        let storage = self.storage.clone();
+        self.pool.execute_for(user_id, move || {
+            let location = LocationRecord {
+                user_id,
+                lat,
+                lon,
+                time: unix_time(),
+                sealed: None,
+            };
+
+            if let Err(e) = storage.save_location(&location) {
+                println!("Failed to save location of {}: {}", user_id, e);
+            }
+        });
+    }
+
+    pub fn forget(&self, user_id: usize) {
+        let storage = self.storage.clone();
+        self.pool.execute_for(user_id, move || {
+            if let Err(e) = storage.delete_locations(user_id) {
+                println!("Failed to delete locations of {}: {}", user_id, e);
+            }
+        });
+    }
+
+    // Blocks on storage, run it on the pool
+    pub fn query(&self, user_id: usize, since: u64) -> Vec<LocationRecord> {
+        match self.storage.locations(user_id, since, MAX_TRAIL_LIMIT) {
+            Ok(locations) => locations,
+            Err(e) => {
+                println!("Failed to read locations of {}: {}", user_id, e);
+                Vec::new()
+            }
+        }
+    }
+}
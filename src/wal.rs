@@ -0,0 +1,161 @@
+use parking_lot::Mutex;
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    sync::Arc,
+};
+
+use crate::storage::MessageRecord;
+
+// Messages are appended here before they are broadcast and stay until the
+// history writer has stored them, so a crash in between loses nothing
+#[derive(Clone)]
+pub struct Wal {
+    log: Arc<Mutex<Log>>,
+}
+
+struct Log {
+    file: File,
+    // Appended but not yet stored
+    pending: usize,
+}
+
+impl Wal {
+    // Also returns the entries left over from the last run, a torn final line
+    // from a crash mid-append is dropped
+    pub fn open(path: &str) -> Result<(Wal, Vec<MessageRecord>), String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+
+        let mut unflushed = Vec::new();
+        for line in BufReader::new(&file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            match serde_json::from_str(&line) {
+                Ok(record) => unflushed.push(record),
+                Err(_) => break,
+            }
+        }
+
+        Ok((
+            Wal {
+                log: Arc::new(Mutex::new(Log { file, pending: 0 })),
+            },
+            unflushed,
+        ))
+    }
+
+    pub fn append(&self, message: &MessageRecord) -> Result<(), String> {
+        let mut line = serde_json::to_vec(message).map_err(|e| e.to_string())?;
+        line.push(b'\n');
+
+        let mut log = self.log.lock();
+        log.file
+            .write_all(&line)
+            .and_then(|_| log.file.sync_data())
+            .map_err(|e| e.to_string())?;
+        log.pending += 1;
+
+        Ok(())
+    }
+
+    // Called once messages are in storage, empties the log when nothing
+    // appended is left unstored
+    pub fn stored(&self, count: usize) {
+        let mut log = self.log.lock();
+        log.pending = log.pending.saturating_sub(count);
+
+        if log.pending == 0 {
+            if let Err(e) = log.file.set_len(0) {
+                println!("Failed to truncate message log: {}", e);
+            }
+        }
+    }
+
+    // Replayed entries are in storage now
+    pub fn clear(&self) -> Result<(), String> {
+        self.log.lock().file.set_len(0).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    fn path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("chat_server-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn record(id: usize) -> MessageRecord {
+        MessageRecord {
+            id,
+            user_id: 7,
+            username: "alice".to_string(),
+            guest: false,
+            bot: false,
+            area: (59, 18),
+            time: 1_600_000_000 + id as u64,
+            msg: format!("message {}", id),
+        }
+    }
+
+    #[test]
+    fn replays_what_was_not_stored() {
+        let path = path("wal-replay");
+        let (wal, unflushed) = Wal::open(&path).unwrap();
+        assert!(unflushed.is_empty());
+
+        wal.append(&record(1)).unwrap();
+        wal.append(&record(2)).unwrap();
+        drop(wal);
+
+        let (_, unflushed) = Wal::open(&path).unwrap();
+        assert_eq!(unflushed.len(), 2);
+        assert_eq!(unflushed[0].id, 1);
+        assert_eq!(unflushed[1].msg, "message 2");
+        assert_eq!(unflushed[1].area, (59, 18));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn drops_a_torn_last_line() {
+        let path = path("wal-torn");
+        let (wal, _) = Wal::open(&path).unwrap();
+        wal.append(&record(1)).unwrap();
+        drop(wal);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"id\":2,\"user_id\"").unwrap();
+        drop(file);
+
+        let (_, unflushed) = Wal::open(&path).unwrap();
+        assert_eq!(unflushed.len(), 1);
+        assert_eq!(unflushed[0].id, 1);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn empties_once_everything_is_stored() {
+        let path = path("wal-stored");
+        let (wal, _) = Wal::open(&path).unwrap();
+        wal.append(&record(1)).unwrap();
+        wal.append(&record(2)).unwrap();
+
+        // One is still waiting on storage
+        wal.stored(1);
+        assert_eq!(Wal::open(&path).unwrap().1.len(), 2);
+
+        wal.stored(1);
+        assert!(Wal::open(&path).unwrap().1.is_empty());
+
+        let _ = fs::remove_file(&path);
+    }
+}